@@ -5,19 +5,16 @@ use log::{info, error, warn, Level};
 
 use tokio::sync::{RwLock, mpsc};
 
-use std::{sync::Arc, io::Write};
+use std::{env, sync::Arc, io::Write};
 
 use log::LevelFilter;
 
 use COIN_NET::{
     network::{NetworkCommand, start_network_handling, Node},
-    miner::{start_mine_handling, MiningCommand}
+    miner::{start_mine_handling, MiningCommand},
+    params::Network,
 };
 
-
-//constants
-const NET_ADDR: &str = "0.0.0.0:8333";
-
 fn main() -> Result<()>{
     tokio::runtime::Builder::new_multi_thread()
         .worker_threads(2)  // Just for network + UI coordination
@@ -64,7 +61,10 @@ async fn async_main() -> Result<()>{
         .filter_level(LevelFilter::Info)
         .init();
 
-    info!("Starting Node ...");
+    let network = Network::from_arg(env::args().nth(1).as_deref())?;
+    let params = network.params();
+
+    info!("Starting Node on {:?} ...", network);
 
     let ip = reqwest::get("https://api.ipify.org")
         .await?
@@ -74,7 +74,7 @@ async fn async_main() -> Result<()>{
     info!("{}", ip);
 
 
-    let node = Arc::new(RwLock::new(Node::new()));
+    let node = Arc::new(RwLock::new(Node::new(network)));
 
     let (miner_tx, miner_rx) = mpsc::channel::<MiningCommand>(10);
 
@@ -84,7 +84,7 @@ async fn async_main() -> Result<()>{
     let node_clone = Arc::clone(&node);
     let miner_tx_clone = miner_tx.clone();
     tokio::spawn(async move {
-    if let Err(e) = start_network_handling(&NET_ADDR.to_string(), node_clone, miner_tx_clone, network_rx).await {
+    if let Err(e) = start_network_handling(&params.net_addr(), node_clone, miner_tx_clone, network_rx).await {
         error!("Network handling failed: {}", e);
     }
     });
@@ -106,9 +106,8 @@ async fn async_main() -> Result<()>{
     miner_tx.send(MiningCommand::Stop).await.unwrap();
     miner_handle.await?;
     //outputing mined blocks
-    let blockchain = node.read().await.block_chain.clone();
-    for block in blockchain{
-        println!("H: {:?}, P: {:?}", block.block_header.height, block.block_header.prev_hash);
+    for header in node.read().await.headers(){
+        println!("H: {:?}, P: {:?}", header.height, header.prev_hash);
     }
     Ok(())
 }
\ No newline at end of file
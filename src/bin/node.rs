@@ -8,20 +8,14 @@ use tokio::{sync::{RwLock, mpsc}};
 use std::{env, fs::File, io::{BufReader, Write}, net::SocketAddr, sync::{Arc, atomic::{AtomicBool, Ordering}}, time::Duration};
 
 use COIN_NET::{
-    miner::{MiningCommand, start_mine_handling}, network::{NetworkCommand, Node, start_network_handling}, ui::start_server
+    miner::{MiningCommand, start_mine_handling}, network::{NetworkCommand, Node, start_network_handling}, params::{ChainParams, Network}, ui::start_server
 };
 
+//a crash loses at most this much of the node file, blocks are stored as they connect
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
 
-//CONSTANTS
-
-const NET_ADDR: &str = "0.0.0.0:8333";
-
-const FILE_PATH: &str = "configs/node.json";
-
-
-
-fn get_bootstrap() -> Result<Vec<SocketAddr>>{
-    let file = File::open("configs/Bootstrap.json")?;
+fn get_bootstrap(params: &ChainParams) -> Result<Vec<SocketAddr>>{
+    let file = File::open(params.bootstrap_file())?;
     let reader = BufReader::new(file);
 
     let bootstrap: Vec<SocketAddr> = serde_json::from_reader(reader)?;
@@ -79,14 +73,27 @@ async fn async_main() -> Result<()>{
         .init();
 
 
+    let network = Network::from_arg(env::args().nth(2).as_deref())?;
+    let params = network.params();
+
     let node = Arc::new(RwLock::new(match env::args().nth(1).as_deref(){
-        Some("load") => Node::load(FILE_PATH)?,
-        Some("new") => Node::new(),
+        Some("load") => Node::load(params.node_file())?,
+        Some("new") => Node::create(network)?,
         Some(arg) => return Err(anyhow!("Invalid arguement '{}' expected 'new' or 'load'", arg)),
         None => return Err(anyhow!("Missing argument: expected: 'new' or 'load'")),
     }));
+    if node.read().await.network != network{
+        return Err(anyhow!("{} belongs to another network", params.node_file()))
+    }
+    //pruning is kept once turned on, a later load without the argument leaves it as it was
+    if let Some(arg) = env::args().nth(3){
+        match arg.strip_prefix("prune="){
+            Some(target) => node.write().await.prune = Some(target.parse()?),
+            None => return Err(anyhow!("Invalid argument '{}' expected 'prune=<blocks>' or 'prune=<size>MB'", arg)),
+        }
+    }
 
-    info!("Starting Node ...");
+    info!("Starting Node on {:?} ...", network);
 
     
     let (miner_tx, miner_rx) = mpsc::channel::<MiningCommand>(10);
@@ -99,7 +106,7 @@ async fn async_main() -> Result<()>{
     let node_clone = Arc::clone(&node);
     let miner_tx_clone = miner_tx.clone();
     tokio::spawn(async move {
-    if let Err(e) = start_network_handling(&NET_ADDR.to_string(), node_clone, miner_tx_clone, network_rx).await {
+    if let Err(e) = start_network_handling(&params.net_addr(), node_clone, miner_tx_clone, network_rx).await {
         error!("Network handling failed: {}", e);
     }
     });
@@ -123,8 +130,21 @@ async fn async_main() -> Result<()>{
     }
     }); 
 
+    //checkpointing the node state
+    let node_clone = Arc::clone(&node);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECKPOINT_INTERVAL);
+        interval.tick().await;
+        loop{
+            interval.tick().await;
+            if let Err(e) = node_clone.write().await.checkpoint(){
+                error!("Checkpoint failed: {:#}", e);
+            }
+        }
+    });
+
     //getting bootstrap addr
-    let bootstrap = match get_bootstrap(){
+    let bootstrap = match get_bootstrap(&params){
         Ok(bootstrap) => bootstrap,
         Err(e) => {
             error!("Could not read bootstrap: {}", e);
@@ -144,7 +164,7 @@ async fn async_main() -> Result<()>{
     miner_tx.send(MiningCommand::Stop).await.unwrap();
     miner_handle.await?;
     //storing nodes current state
-    node.read().await.store(FILE_PATH)?;
+    node.write().await.checkpoint()?;
     save_requested.store(true, Ordering::SeqCst);
     tokio::time::sleep(Duration::from_millis(1000)).await;
     Ok(())
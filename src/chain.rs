@@ -0,0 +1,486 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use k256::elliptic_curve::bigint::{Encoding, U256};
+
+use crate::miner::{Block, BlockHeader, HashDigest, get_timestamp};
+
+const MAX_ORPHANS: usize = 100;
+const MAX_ORPHAN_BYTES: usize = 10 * 1024 * 1024;
+//seconds an orphan is kept waiting for its parent
+const ORPHAN_EXPIRY: usize = 20 * 60;
+
+//difficulty is recalculated every RETARGET_INTERVAL blocks
+pub const RETARGET_INTERVAL: usize = 10;
+//seconds we aim to have between blocks
+pub const TARGET_BLOCK_TIME: usize = 60;
+
+//largest serialized block in bytes
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+//a block must be later than the median timestamp of this many previous blocks
+pub const MEDIAN_TIME_SPAN: usize = 11;
+//seconds a block may be ahead of network adjusted time
+pub const MAX_FUTURE_BLOCK_TIME: usize = 2 * 60 * 60;
+//peer clock offsets beyond this are ignored, as is everything after MAX_TIME_SAMPLES peers
+const MAX_TIME_OFFSET: i64 = 70 * 60;
+const MAX_TIME_SAMPLES: usize = 200;
+
+//coins paid to the miner of the first blocks, halving every HALVING_INTERVAL blocks
+pub const INITIAL_SUBSIDY: usize = 10;
+pub const HALVING_INTERVAL: usize = 100_000;
+//every coin the subsidy schedule will ever create
+pub const MAX_MONEY: usize = 1_800_000;
+
+//expands compact bits (exponent byte followed by a 3 byte mantissa) into a 256 bit target,
+//negative or overflowing values give a zero target which nothing can meet
+pub fn compact_to_target(bits: u32) -> U256{
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007fffff;
+    if bits & 0x00800000 != 0 || mantissa == 0{
+        return U256::ZERO
+    }
+    if exponent <= 3{
+        return U256::from_u32(mantissa >> (8 * (3 - exponent)))
+    }
+    let mantissa_bits = 32 - mantissa.leading_zeros() as usize;
+    if mantissa_bits + 8 * (exponent - 3) > 256{
+        return U256::ZERO
+    }
+    U256::from_u32(mantissa).shl_vartime(8 * (exponent - 3))
+}
+
+pub fn target_to_compact(target: &U256) -> u32{
+    let mut size = target.bits_vartime().div_ceil(8);
+    let bytes = target.to_be_bytes();
+    let mut mantissa = bytes[32 - size.max(3)..][..3]
+        .iter()
+        .fold(0u32, |acc, byte| acc << 8 | *byte as u32);
+    if size < 3{
+        mantissa <<= 8 * (3 - size);
+    }
+    //the top mantissa bit is a sign bit so shift it out of the way
+    if mantissa & 0x00800000 != 0{
+        mantissa >>= 8;
+        size += 1;
+    }
+    mantissa | (size as u32) << 24
+}
+
+//a hash meets the target when read as a big endian integer it is no larger
+pub fn hash_meets_target(hash: &HashDigest, bits: u32) -> bool{
+    let target = compact_to_target(bits);
+    target != U256::ZERO && U256::from_be_slice(hash) <= target
+}
+
+pub fn is_valid_bits(bits: u32, pow_limit_bits: u32) -> bool{
+    let target = compact_to_target(bits);
+    target != U256::ZERO && target <= compact_to_target(pow_limit_bits)
+}
+
+//expected number of hashes needed to meet the target, 2^256 / (target + 1)
+pub fn block_work(bits: u32) -> u128{
+    let target = compact_to_target(bits);
+    if target == U256::ZERO{
+        return 0
+    }
+    let work = target.not().wrapping_div(&target.wrapping_add(&U256::ONE)).wrapping_add(&U256::ONE);
+    if work.bits_vartime() > 128{
+        return u128::MAX
+    }
+    u128::from_be_bytes(work.to_be_bytes()[16..].try_into().unwrap())
+}
+
+//how many times harder the target is than the pow limit, for display
+pub fn difficulty(bits: u32, pow_limit_bits: u32) -> f64{
+    to_f64(&compact_to_target(pow_limit_bits)) / to_f64(&compact_to_target(bits)).max(1.0)
+}
+
+fn to_f64(value: &U256) -> f64{
+    value.to_be_bytes().iter().fold(0.0, |acc, byte| acc * 256.0 + *byte as f64)
+}
+
+//scales the target by how long the last window took compared to what we wanted,
+//moving by at most a factor of four each retarget
+pub fn retarget(bits: u32, actual_timespan: usize, pow_limit_bits: u32) -> u32{
+    let expected = TARGET_BLOCK_TIME * (RETARGET_INTERVAL - 1);
+    let actual = U256::from_u64(actual_timespan.clamp(expected / 4, expected * 4) as u64);
+    let expected = U256::from_u64(expected as u64);
+    let target = compact_to_target(bits);
+    //multiply first when there is room so small targets keep their precision
+    let target = if target.bits_vartime() + actual.bits_vartime() <= 256{
+        target.wrapping_mul(&actual).wrapping_div(&expected)
+    }else{
+        target.wrapping_div(&expected).saturating_mul(&actual)
+    };
+    target_to_compact(&target.min(compact_to_target(pow_limit_bits)))
+}
+
+pub fn block_subsidy(height: usize) -> usize{
+    let halvings = (height.saturating_sub(1) / HALVING_INTERVAL) as u32;
+    INITIAL_SUBSIDY.checked_shr(halvings).unwrap_or(0)
+}
+
+pub fn is_money_range(value: usize) -> bool{
+    value <= MAX_MONEY
+}
+
+pub fn is_retarget_height(height: usize) -> bool{
+    height.is_multiple_of(RETARGET_INTERVAL)
+}
+
+//the block itself and its undo data live in the block store
+#[derive(Clone, Debug)]
+pub struct BlockEntry{
+    pub header: BlockHeader,
+    pub chain_work: u128,
+}
+
+impl BlockEntry{
+    pub fn new(header: BlockHeader, chain_work: u128) -> Self{
+        Self {
+            header,
+            chain_work
+        }
+    }
+
+    pub fn height(&self) -> usize{
+        self.header.height
+    }
+}
+
+//every block we have accepted, on the active chain or not, keyed by hash
+#[derive(Clone, Debug, Default)]
+pub struct BlockTree(HashMap<HashDigest, BlockEntry>);
+
+impl BlockTree{
+    pub fn new() -> Self{
+        Self(HashMap::new())
+    }
+
+    //sums the work along every branch the headers form, headers whose parent is missing are left out
+    pub fn from_headers(mut headers: Vec<BlockHeader>) -> Self{
+        headers.sort_by_key(|header| header.height);
+        let mut tree = Self::new();
+        for header in headers{
+            let parent_work = match tree.get(&header.prev_hash){
+                Some(parent) => parent.chain_work,
+                None if header.height == 0 => 0,
+                None => continue
+            };
+            let chain_work = parent_work + block_work(header.bits);
+            tree.insert(header.hash(), BlockEntry::new(header, chain_work));
+        }
+        tree
+    }
+
+    pub fn contains(&self, hash: &HashDigest) -> bool{
+        self.0.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &HashDigest) -> Option<&BlockEntry>{
+        self.0.get(hash)
+    }
+
+    pub fn get_mut(&mut self, hash: &HashDigest) -> Option<&mut BlockEntry>{
+        self.0.get_mut(hash)
+    }
+
+    pub fn insert(&mut self, hash: HashDigest, entry: BlockEntry){
+        self.0.insert(hash, entry);
+    }
+
+    pub fn remove(&mut self, hash: &HashDigest) -> Option<BlockEntry>{
+        self.0.remove(hash)
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.0.is_empty()
+    }
+
+    //the block with the most work behind it
+    pub fn best_tip(&self) -> Option<HashDigest>{
+        self.0.iter()
+            .max_by_key(|(_, entry)| entry.chain_work)
+            .map(|(hash, _)| *hash)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct OrphanEntry{
+    block: Block,
+    size: usize,
+    received: usize,
+}
+
+//blocks whose parent we have not seen yet, bounded in count, bytes and age
+#[derive(Clone, Debug, Default)]
+pub struct OrphanPool{
+    orphans: HashMap<HashDigest, OrphanEntry>,
+    bytes: usize,
+}
+
+impl OrphanPool{
+    pub fn new() -> Self{
+        Self {
+            orphans: HashMap::new(),
+            bytes: 0
+        }
+    }
+
+    pub fn contains(&self, hash: &HashDigest) -> bool{
+        self.orphans.contains_key(hash)
+    }
+
+    pub fn size(&self) -> usize{
+        self.orphans.len()
+    }
+
+    pub fn add(&mut self, hash: HashDigest, block: Block) -> bool{
+        let size = block.size();
+        if self.contains(&hash) || size > MAX_ORPHAN_BYTES{
+            return false
+        }
+        let now = get_timestamp();
+        self.expire(now);
+        while self.orphans.len() >= MAX_ORPHANS || self.bytes + size > MAX_ORPHAN_BYTES{
+            self.evict_oldest();
+        }
+        self.bytes += size;
+        self.orphans.insert(hash, OrphanEntry { block, size, received: now });
+        true
+    }
+
+    //removes and returns every orphan building on parent
+    pub fn take_children(&mut self, parent: &HashDigest) -> Vec<Block>{
+        let children: Vec<HashDigest> = self.orphans.iter()
+            .filter(|(_, entry)| entry.block.block_header.prev_hash == *parent)
+            .map(|(hash, _)| *hash)
+            .collect();
+        children.iter()
+            .filter_map(|hash| self.remove(hash))
+            .collect()
+    }
+
+    fn remove(&mut self, hash: &HashDigest) -> Option<Block>{
+        let entry = self.orphans.remove(hash)?;
+        self.bytes -= entry.size;
+        Some(entry.block)
+    }
+
+    fn expire(&mut self, now: usize){
+        let expired: Vec<HashDigest> = self.orphans.iter()
+            .filter(|(_, entry)| entry.received + ORPHAN_EXPIRY < now)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired{
+            self.remove(&hash);
+        }
+    }
+
+    fn evict_oldest(&mut self){
+        let oldest = self.orphans.iter()
+            .min_by_key(|(_, entry)| entry.received)
+            .map(|(hash, _)| *hash);
+        if let Some(hash) = oldest{
+            self.remove(&hash);
+        }
+    }
+}
+
+//middle value of the timestamps, the upper one for an even count
+pub fn median_time(mut timestamps: Vec<usize>) -> usize{
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied().unwrap_or_default()
+}
+
+//difference between each peer's clock and ours, reported when the peer connects, keyed by address
+//so a host reconnecting from new ports only counts once
+#[derive(Clone, Debug, Default)]
+pub struct TimeOffsets(HashMap<IpAddr, i64>);
+
+impl TimeOffsets{
+    pub fn new() -> Self{
+        Self(HashMap::new())
+    }
+
+    //peers from before the timestamp was sent report 0, that is no clock at all
+    pub fn add(&mut self, peer: IpAddr, peer_time: usize, local_time: usize){
+        if peer_time == 0 || self.0.len() >= MAX_TIME_SAMPLES && !self.0.contains_key(&peer){
+            return
+        }
+        self.0.insert(peer, peer_time as i64 - local_time as i64);
+    }
+
+    //median offset including our own clock, zero if peers disagree with us too much
+    pub fn offset(&self) -> i64{
+        let mut offsets: Vec<i64> = self.0.values().copied().collect();
+        offsets.push(0);
+        offsets.sort_unstable();
+        let median = offsets[offsets.len() / 2];
+        if median.abs() > MAX_TIME_OFFSET{
+            return 0
+        }
+        median
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    //easiest target a block may have, roughly half of all hashes meet it
+    const POW_LIMIT_BITS: u32 = 0x207fffff;
+    //one in 2^24 hashes meets it
+    const INITIAL_BITS: u32 = 0x1e010000;
+    use crate::{miner::sha256, transactions::{Transaction, User}};
+
+    fn orphan(version: usize) -> (HashDigest, Block){
+        let coinbase = Transaction::reward(10, User::new().get_pub_key(), version, 2);
+        let block = Block::new(vec![coinbase], sha256("parent"), 0, 0, 2);
+        (block.calculate_hash(), block)
+    }
+
+    #[test]
+    fn compact_round_trip(){
+        for bits in [POW_LIMIT_BITS, INITIAL_BITS, 0x1d00ffff, 0x1b0404cb, 0x03123456, 0x02008000]{
+            assert_eq!(target_to_compact(&compact_to_target(bits)), bits);
+        }
+        assert_eq!(compact_to_target(0x1d00ffff), U256::from_be_hex("00000000ffff0000000000000000000000000000000000000000000000000000"));
+        assert_eq!(compact_to_target(0x03123456), U256::from_u32(0x123456));
+        assert_eq!(compact_to_target(0x01123456), U256::from_u32(0x12));
+        assert_eq!(compact_to_target(0x04923456), U256::ZERO);
+        assert_eq!(compact_to_target(0x2300ffff), U256::ZERO);
+    }
+
+    #[test]
+    fn compares_hash_as_integer(){
+        let mut hash = [0u8; 32];
+        hash[3] = 0xff;
+        assert!(!hash_meets_target(&hash, 0x1d00ffff));
+        hash[3] = 0;
+        hash[4] = 0xff;
+        hash[5] = 0xff;
+        assert!(hash_meets_target(&hash, 0x1d00ffff));
+        hash[6] = 1;
+        assert!(!hash_meets_target(&hash, 0x1d00ffff));
+    }
+
+    #[test]
+    fn work_grows_with_difficulty(){
+        assert_eq!(block_work(0x1d00ffff), 0x100010001);
+        assert_eq!(block_work(POW_LIMIT_BITS), 2);
+        assert_eq!(block_work(INITIAL_BITS), (1 << 24) - 1);
+        assert!(!is_valid_bits(0x2100ffff, POW_LIMIT_BITS));
+        assert!(!is_valid_bits(POW_LIMIT_BITS, INITIAL_BITS));
+        assert!(is_valid_bits(INITIAL_BITS, POW_LIMIT_BITS));
+    }
+
+    #[test]
+    fn retargets_towards_block_time(){
+        let expected = TARGET_BLOCK_TIME * (RETARGET_INTERVAL - 1);
+        assert_eq!(retarget(INITIAL_BITS, expected, POW_LIMIT_BITS), INITIAL_BITS);
+        assert_eq!(compact_to_target(retarget(INITIAL_BITS, expected * 2, POW_LIMIT_BITS)), compact_to_target(INITIAL_BITS).shl_vartime(1));
+        assert_eq!(compact_to_target(retarget(INITIAL_BITS, expected / 2, POW_LIMIT_BITS)), compact_to_target(INITIAL_BITS).shr_vartime(1));
+        //clamped to a factor of four
+        assert_eq!(retarget(INITIAL_BITS, 0, POW_LIMIT_BITS), retarget(INITIAL_BITS, expected / 4, POW_LIMIT_BITS));
+        assert_eq!(retarget(INITIAL_BITS, expected * 100, POW_LIMIT_BITS), retarget(INITIAL_BITS, expected * 4, POW_LIMIT_BITS));
+        assert_eq!(retarget(POW_LIMIT_BITS, expected * 4, POW_LIMIT_BITS), POW_LIMIT_BITS);
+    }
+
+    #[test]
+    fn subsidy_halves_to_cap(){
+        assert_eq!(block_subsidy(1), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(HALVING_INTERVAL), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(HALVING_INTERVAL + 1), INITIAL_SUBSIDY / 2);
+        assert_eq!(block_subsidy(64 * HALVING_INTERVAL + 1), 0);
+
+        let total: usize = (0..64)
+            .map(|halving| block_subsidy(halving * HALVING_INTERVAL + 1) * HALVING_INTERVAL)
+            .sum();
+        assert_eq!(total, MAX_MONEY);
+    }
+
+    #[test]
+    fn orphan_pool_is_bounded(){
+        let mut pool = OrphanPool::new();
+        for version in 0..MAX_ORPHANS + 10{
+            let (hash, block) = orphan(version);
+            assert!(pool.add(hash, block));
+        }
+        assert_eq!(pool.size(), MAX_ORPHANS);
+
+        let (hash, block) = orphan(0);
+        assert!(pool.add(hash, block.clone()));
+        assert!(!pool.add(hash, block));
+    }
+
+    #[test]
+    fn orphans_expire(){
+        let mut pool = OrphanPool::new();
+        let (hash, block) = orphan(0);
+        pool.add(hash, block);
+        pool.expire(get_timestamp() + ORPHAN_EXPIRY + 1);
+        assert_eq!(pool.size(), 0);
+        assert_eq!(pool.bytes, 0);
+    }
+
+    #[test]
+    fn takes_children(){
+        let mut pool = OrphanPool::new();
+        let (hash, block) = orphan(0);
+        pool.add(hash, block);
+        assert!(pool.take_children(&sha256("other")).is_empty());
+        assert_eq!(pool.take_children(&sha256("parent")).len(), 1);
+        assert_eq!(pool.size(), 0);
+    }
+
+    #[test]
+    fn median_of_timestamps(){
+        assert_eq!(median_time(vec![5, 1, 3]), 3);
+        assert_eq!(median_time(vec![4, 1, 3, 2]), 3);
+        assert_eq!(median_time(Vec::new()), 0);
+    }
+
+    #[test]
+    fn offsets_are_bounded(){
+        let mut offsets = TimeOffsets::new();
+        assert_eq!(offsets.offset(), 0);
+        let peer = |host| IpAddr::from([127, 0, 0, host]);
+        offsets.add(peer(1), 1100, 1000);
+        offsets.add(peer(2), 1200, 1000);
+        assert_eq!(offsets.offset(), 100);
+
+        //a peer only counts once
+        offsets.add(peer(1), 1300, 1000);
+        assert_eq!(offsets.offset(), 200);
+        //one that sent no clock is not counted at all
+        offsets.add(peer(6), 0, 1000);
+        offsets.add(peer(7), 0, 1000);
+        assert_eq!(offsets.offset(), 200);
+
+        offsets.add(peer(3), 1000 + 2 * MAX_TIME_OFFSET as usize, 1000);
+        offsets.add(peer(4), 1000 + 2 * MAX_TIME_OFFSET as usize, 1000);
+        offsets.add(peer(5), 1000 + 2 * MAX_TIME_OFFSET as usize, 1000);
+        assert_eq!(offsets.offset(), 0);
+    }
+
+    #[test]
+    fn block_tree_is_rebuilt_from_headers(){
+        let mut headers = vec![BlockHeader::new([0u8; 32], sha256("genesis"), 0, POW_LIMIT_BITS, 0)];
+        for height in 1..4{
+            headers.push(BlockHeader::new(headers[height - 1].hash(), sha256("main"), 0, POW_LIMIT_BITS, height));
+        }
+        //one harder block on a side branch outweighs two easier ones
+        let fork = BlockHeader::new(headers[1].hash(), sha256("fork"), 0, INITIAL_BITS, 2);
+        let (orphan_hash, orphan) = orphan(0);
+        let mut stored = vec![headers[3].clone(), fork.clone(), orphan.block_header];
+        stored.extend(headers[..3].iter().cloned());
+
+        let tree = BlockTree::from_headers(stored);
+        let work = block_work(POW_LIMIT_BITS);
+        assert_eq!(tree.get(&headers[3].hash()).unwrap().chain_work, 4 * work);
+        assert_eq!(tree.get(&fork.hash()).unwrap().chain_work, 2 * work + block_work(INITIAL_BITS));
+        assert!(!tree.contains(&orphan_hash));
+        assert_eq!(tree.best_tip(), Some(fork.hash()));
+    }
+}
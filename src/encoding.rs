@@ -0,0 +1,212 @@
+use anyhow::{Result, anyhow, bail};
+
+//canonical binary encoding, every consensus hash is taken over these bytes
+//
+//  u8            1 byte
+//  u32           4 bytes little endian
+//  usize         8 bytes little endian, decoding fails if it does not fit
+//  [u8; N]       N raw bytes
+//  Vec<T>        compact size count followed by each item
+//  compact size  below 0xfd a single byte, otherwise 0xfd, 0xfe or 0xff followed by
+//                a little endian u16, u32 or u64, always the shortest form
+//
+//transactions and block headers start with their version so the layout can change later,
+//see the Encode impls next to each type for its field order
+pub trait Encode{
+    fn encode_to(&self, buf: &mut Vec<u8>);
+
+    fn encode(&self) -> Vec<u8>{
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf);
+        buf
+    }
+}
+
+pub trait Decode: Sized{
+    fn decode_from(reader: &mut Reader) -> Result<Self>;
+
+    //fails unless data holds exactly one value
+    fn decode(data: &[u8]) -> Result<Self>{
+        let mut reader = Reader::new(data);
+        let value = Self::decode_from(&mut reader)?;
+        if reader.remaining() != 0{
+            bail!("{} trailing bytes", reader.remaining())
+        }
+        Ok(value)
+    }
+}
+
+pub struct Reader<'a>{
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a>{
+    pub fn new(data: &'a [u8]) -> Self{
+        Self { data, position: 0 }
+    }
+
+    pub fn remaining(&self) -> usize{
+        self.data.len() - self.position
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]>{
+        if len > self.remaining(){
+            bail!("unexpected end of data, wanted {} bytes but {} remain", len, self.remaining())
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N]>{
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.read_bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn read_compact_size(&mut self) -> Result<usize>{
+        let (value, min) = match u8::decode_from(self)?{
+            0xfd => (u16::from_le_bytes(self.read_array()?) as u64, 0xfd),
+            0xfe => (u32::from_le_bytes(self.read_array()?) as u64, 0x1_0000),
+            0xff => (u64::from_le_bytes(self.read_array()?), 0x1_0000_0000),
+            byte => return Ok(byte as usize)
+        };
+        if value < min{
+            bail!("non canonical compact size {}", value)
+        }
+        usize::try_from(value).map_err(|_| anyhow!("compact size {} too large", value))
+    }
+}
+
+pub fn write_compact_size(buf: &mut Vec<u8>, value: usize){
+    let value = value as u64;
+    match value{
+        0..=0xfc => buf.push(value as u8),
+        0xfd..=0xffff => {
+            buf.push(0xfd);
+            buf.extend((value as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(0xfe);
+            buf.extend((value as u32).to_le_bytes());
+        }
+        _ => {
+            buf.push(0xff);
+            buf.extend(value.to_le_bytes());
+        }
+    }
+}
+
+impl Encode for u8{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        buf.push(*self);
+    }
+}
+
+impl Decode for u8{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        Ok(reader.read_bytes(1)?[0])
+    }
+}
+
+impl Encode for u32{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        buf.extend(self.to_le_bytes());
+    }
+}
+
+impl Decode for u32{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        Ok(u32::from_le_bytes(reader.read_array()?))
+    }
+}
+
+impl Encode for usize{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        buf.extend((*self as u64).to_le_bytes());
+    }
+}
+
+impl Decode for usize{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        let value = u64::from_le_bytes(reader.read_array()?);
+        usize::try_from(value).map_err(|_| anyhow!("integer {} too large", value))
+    }
+}
+
+impl<const N: usize> Encode for [u8; N]{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        buf.extend_from_slice(self);
+    }
+}
+
+impl<const N: usize> Decode for [u8; N]{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        reader.read_array()
+    }
+}
+
+impl<T: Encode> Encode for Vec<T>{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        write_compact_size(buf, self.len());
+        for item in self.iter(){
+            item.encode_to(buf);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T>{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        let count = reader.read_compact_size()?;
+        //every item takes at least a byte so a bad count can not allocate more than the data
+        let mut items = Vec::with_capacity(count.min(reader.remaining()));
+        for _ in 0..count{
+            items.push(T::decode_from(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn compact_size_vectors(){
+        let vectors: [(usize, &str); 7] = [
+            (0, "00"),
+            (0xfc, "fc"),
+            (0xfd, "fdfd00"),
+            (0xffff, "fdffff"),
+            (0x1_0000, "fe00000100"),
+            (0xffff_ffff, "feffffffff"),
+            (0x1_0000_0000, "ff0000000001000000"),
+        ];
+        for (value, expected) in vectors{
+            let mut buf = Vec::new();
+            write_compact_size(&mut buf, value);
+            assert_eq!(hex::encode(&buf), expected);
+            assert_eq!(Reader::new(&buf).read_compact_size().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn rejects_non_canonical_data(){
+        assert!(Reader::new(&hex::decode("fdfc00").unwrap()).read_compact_size().is_err());
+        assert!(Reader::new(&hex::decode("fe0000ffff").unwrap()).read_compact_size().is_ok());
+        assert!(Reader::new(&hex::decode("fe00ff0000").unwrap()).read_compact_size().is_err());
+
+        //length claims more bytes than there are
+        assert!(Vec::<u8>::decode(&hex::decode("0501020304").unwrap()).is_err());
+        assert!(Vec::<u8>::decode(&hex::decode("0401020304ff").unwrap()).is_err());
+        assert_eq!(Vec::<u8>::decode(&hex::decode("0401020304").unwrap()).unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn integers_are_little_endian(){
+        assert_eq!(hex::encode(0x01020304u32.encode()), "04030201");
+        assert_eq!(hex::encode(300usize.encode()), "2c01000000000000");
+        assert_eq!(usize::decode(&300usize.encode()).unwrap(), 300);
+    }
+}
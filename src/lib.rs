@@ -4,4 +4,10 @@ pub mod network;
 pub mod miner;
 pub mod messages;
 pub mod transactions;
-pub mod ui;
\ No newline at end of file
+pub mod ui;
+pub mod chain;
+pub mod params;
+pub mod encoding;
+pub mod merkle;
+pub mod store;
+pub mod utxo_store;
\ No newline at end of file
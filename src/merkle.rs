@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+
+use crate::miner::{HashDigest, sha256};
+
+//leaves and inner nodes are hashed with different prefixes so an inner node can never pass as a transaction
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+fn hash_leaf(leaf: &HashDigest) -> HashDigest{
+    let mut message = vec![LEAF_PREFIX];
+    message.extend_from_slice(leaf);
+    sha256(message)
+}
+
+fn hash_node(left: &HashDigest, right: &HashDigest) -> HashDigest{
+    let mut message = vec![NODE_PREFIX];
+    message.extend_from_slice(left);
+    message.extend_from_slice(right);
+    sha256(message)
+}
+
+//hashes of the next level up, an odd node out is paired with itself
+fn next_level(level: &[HashDigest]) -> Vec<HashDigest>{
+    level.chunks(2)
+        .map(|pair| hash_node(&pair[0], pair.last().unwrap()))
+        .collect()
+}
+
+//root of the tree over the leaf hashes, all zeros when there are none,
+//the block decides what a leaf is so proofs are checked against the same hash it committed to
+pub fn merkle_root(leaves: &[HashDigest]) -> HashDigest{
+    let mut level: Vec<HashDigest> = leaves.iter().map(hash_leaf).collect();
+    if level.is_empty(){
+        return [0u8; 32]
+    }
+    while level.len() > 1{
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+//sibling hashes from a leaf up to the root
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof{
+    pub index: usize,
+    #[serde(with = "hex_hashes")]
+    pub branch: Vec<HashDigest>,
+}
+
+impl MerkleProof{
+    pub fn new(leaves: &[HashDigest], index: usize) -> Option<Self>{
+        if index >= leaves.len(){
+            return None
+        }
+        let mut level: Vec<HashDigest> = leaves.iter().map(hash_leaf).collect();
+        let mut position = index;
+        let mut branch = Vec::new();
+        while level.len() > 1{
+            let sibling = (position ^ 1).min(level.len() - 1);
+            branch.push(level[sibling]);
+            level = next_level(&level);
+            position /= 2;
+        }
+        Some(Self { index, branch })
+    }
+
+    //root the branch leads to when starting from leaf
+    pub fn root(&self, leaf: &HashDigest) -> HashDigest{
+        let mut hash = hash_leaf(leaf);
+        for (depth, sibling) in self.branch.iter().enumerate(){
+            hash = match (self.index >> depth) & 1{
+                0 => hash_node(&hash, sibling),
+                _ => hash_node(sibling, &hash),
+            };
+        }
+        hash
+    }
+
+    pub fn verify(&self, leaf: &HashDigest, merkle_root: &HashDigest) -> bool{
+        self.index >> self.branch.len() == 0 && self.root(leaf) == *merkle_root
+    }
+}
+
+mod hex_hashes{
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    use crate::miner::HashDigest;
+
+    pub fn serialize<S: Serializer>(hashes: &[HashDigest], serializer: S) -> Result<S::Ok, S::Error>{
+        serializer.collect_seq(hashes.iter().map(hex::encode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<HashDigest>, D::Error>{
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|hash| {
+                let bytes = hex::decode(hash).map_err(de::Error::custom)?;
+                bytes.try_into().map_err(|_| de::Error::custom("hash must be 32 bytes"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn txids(count: usize) -> Vec<HashDigest>{
+        (0..count).map(|index| sha256(index.to_string())).collect()
+    }
+
+    #[test]
+    fn root_of_small_trees(){
+        assert_eq!(merkle_root(&[]), [0u8; 32]);
+        let ids = txids(3);
+        assert_eq!(merkle_root(&ids[..1]), hash_leaf(&ids[0]));
+        let left = hash_node(&hash_leaf(&ids[0]), &hash_leaf(&ids[1]));
+        let right = hash_node(&hash_leaf(&ids[2]), &hash_leaf(&ids[2]));
+        assert_eq!(merkle_root(&ids), hash_node(&left, &right));
+    }
+
+    #[test]
+    fn proves_every_leaf(){
+        for count in 1..=9{
+            let ids = txids(count);
+            let root = merkle_root(&ids);
+            for (index, txid) in ids.iter().enumerate(){
+                let proof = MerkleProof::new(&ids, index).unwrap();
+                assert!(proof.verify(txid, &root));
+            }
+            assert!(MerkleProof::new(&ids, count).is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_proofs(){
+        let ids = txids(5);
+        let root = merkle_root(&ids);
+        let proof = MerkleProof::new(&ids, 2).unwrap();
+        assert!(!proof.verify(&ids[3], &root));
+        assert!(!proof.verify(&ids[2], &merkle_root(&ids[..4])));
+
+        let mut moved = proof.clone();
+        moved.index = 3;
+        assert!(!moved.verify(&ids[2], &root));
+
+        //an index past the end of the branch can not be used to fake a position
+        let mut padded = proof.clone();
+        padded.index += 1 << proof.branch.len();
+        assert!(!padded.verify(&ids[2], &root));
+
+        //inner nodes are not leaves
+        let inner = hash_node(&hash_leaf(&ids[0]), &hash_leaf(&ids[1]));
+        let short = MerkleProof { index: 0, branch: proof.branch[1..].to_vec() };
+        assert!(!short.verify(&inner, &root));
+    }
+
+    #[test]
+    fn proof_round_trips_as_json(){
+        let ids = txids(4);
+        let proof = MerkleProof::new(&ids, 1).unwrap();
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(serde_json::from_str::<MerkleProof>(&json).unwrap(), proof);
+    }
+}
@@ -7,25 +7,31 @@ use anyhow::Result;
 use std::{hash::{Hash, Hasher}};
 
 use crate::{
-    miner::{Block},
+    miner::{Block, HashDigest, get_timestamp},
     transactions::Transaction,
 };
 
-const TX_PER_BLOCK: usize = 10;
-
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Verack{
     pub index: usize,
     version: usize,
     pub height: usize,
+    //sender's clock, used for network adjusted time
+    #[serde(default)]
+    pub timestamp: usize,
+    //lowest height the sender can still send blocks from, 0 when it keeps every block
+    #[serde(default)]
+    pub pruned_height: usize,
 }
 
 impl Verack{
-    pub fn new(index: usize, version: usize, height: usize) -> Self{
+    pub fn new(index: usize, version: usize, height: usize, pruned_height: usize) -> Self{
         Self{
             index,
             version,
             height,
+            timestamp: get_timestamp(),
+            pruned_height,
         }
     }
 }
@@ -88,7 +94,7 @@ impl Mempool{
     pub fn new() -> Self{
         Self(HeapSet::new())
     }
-    pub fn get_inv(self) -> Vec<Transaction>{
+    pub fn get_inv(&self) -> Vec<Transaction>{
         self.0.get_vec().iter()
             .map(|txwf| txwf.transaction.clone())
             .collect()
@@ -104,20 +110,18 @@ impl Mempool{
     });
     }
 
-    pub fn get_next_transactions(&self) -> Vec<Transaction>{
+    //highest fee transactions that fit in max_size bytes, skipping any too large for the space left
+    pub fn get_next_transactions(&self, max_size: usize) -> Vec<Transaction>{
         let mut mempool_clone = self.0.clone();
         let mut txs = Vec::new();
-        for _ in 0..TX_PER_BLOCK{
-            match mempool_clone.pop(){
-                Some(tx) => {
-                    txs.push(tx.transaction);
-                }
-                None => {
-                    return txs
-                }
+        let mut size = 0;
+        while let Some(tx) = mempool_clone.pop(){
+            let tx_size = tx.transaction.size();
+            if size + tx_size <= max_size{
+                size += tx_size;
+                txs.push(tx.transaction);
             }
         }
-
         txs
     }
 
@@ -173,19 +177,20 @@ impl TransactionWithFee{
     }
 }
 
-// Equality: based on BOTH transaction and fee (for HashSet)
+// Equality: based on the txid only (for HashSet), so a copy with re-encoded
+// unlocking scripts is the same mempool entry
 impl PartialEq for TransactionWithFee {
     fn eq(&self, other: &Self) -> bool {
-        self.transaction == other.transaction
+        self.transaction.txid() == other.transaction.txid()
     }
 }
 
 impl Eq for TransactionWithFee {}
 
-// Hash: based on BOTH transaction and fee (must match equality)
+// Hash: based on the txid (must match equality)
 impl Hash for TransactionWithFee {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.transaction.hash(state);
+        self.transaction.txid().hash(state);
     }
 }
 
@@ -293,15 +298,36 @@ pub struct Ping;
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Pong;
 
+//locator holds hashes from the sender's chain, newest first,
+//blocks are sent from after the first one the receiver has on its active chain
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetBlocks{
     pub start_height: usize,
+    #[serde(default)]
+    pub locator: Vec<HashDigest>,
 }
 
 impl GetBlocks{
-    pub fn new(start_height: usize) -> Self{
+    pub fn new(start_height: usize, locator: Vec<HashDigest>) -> Self{
+        Self{
+            start_height,
+            locator,
+        }
+    }
+}
+
+//answer to a GetBlocks starting below the pruned height of the node asked
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlocksPruned{
+    pub start_height: usize,
+    pub pruned_height: usize,
+}
+
+impl BlocksPruned{
+    pub fn new(start_height: usize, pruned_height: usize) -> Self{
         Self{
-            start_height
+            start_height,
+            pruned_height,
         }
     }
 }
@@ -1,6 +1,6 @@
-use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, thread::{self, JoinHandle}, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::HashSet, sync::{Arc, atomic::{AtomicBool, Ordering}}, thread::{self, JoinHandle}, time::{SystemTime, UNIX_EPOCH}};
 
-use anyhow::Result;
+use anyhow::{Result, bail};
 
 #[allow(unused)]
 use log::{info, error, warn};
@@ -11,22 +11,22 @@ use sha2::{Digest, Sha256};
 use rand::RngCore;
 use tokio::sync::{RwLock, mpsc};
 
-use crate::network::{NetworkCommand, Node};
+use crate::{chain::{MAX_BLOCK_SIZE, hash_meets_target, is_valid_bits}, encoding::{Decode, Encode, Reader}, merkle::{MerkleProof, merkle_root}, network::{NetworkCommand, Node}, params::ChainParams};
 
 pub type HashDigest = [u8; 32];
 
-type Nonce = [u8; 16];
+pub type Nonce = [u8; 16];
 
-use crate::transactions::Transaction;
+use crate::transactions::{Transaction, is_coinbase};
 
 pub enum MiningCommand{
     Stop,
     UpdateBlock,
 }
 
-pub fn sha256(message: String) -> HashDigest{
+pub fn sha256(message: impl AsRef<[u8]>) -> HashDigest{
     let mut hasher = Sha256::new();
-    hasher.update(message.as_bytes());
+    hasher.update(message.as_ref());
     hasher.finalize().into()
 }
 
@@ -55,7 +55,7 @@ impl Block{
     pub fn new(
         transactions: Vec<Transaction>, 
         prev_hash: HashDigest, 
-        difficulty: usize, 
+        bits: u32, 
         version: usize, 
         height: usize 
     ) -> Self{
@@ -64,29 +64,102 @@ impl Block{
         let transaction_count = transactions.len();
 
         Self { 
-            block_header: BlockHeader::new(prev_hash, merkle_root, version, difficulty, height), 
+            block_header: BlockHeader::new(prev_hash, merkle_root, version, bits, height), 
             transactions, 
             transaction_count}
     }
 
+    //leaves are full hashes so the block commits to unlocking scripts too
     pub fn get_merkle_root(transactions: Vec<Transaction>) -> HashDigest{
-        Self::rec_merkle_root(transactions.iter().map(|tx| tx.serialize()).collect())
+        merkle_root(&transactions.iter().map(|tx| tx.full_hash()).collect::<Vec<_>>())
+    }
+
+    pub fn txids(&self) -> Vec<HashDigest>{
+        self.transactions.iter().map(|tx| tx.txid()).collect()
+    }
+
+    pub fn full_hashes(&self) -> Vec<HashDigest>{
+        self.transactions.iter().map(|tx| tx.full_hash()).collect()
+    }
+
+    //looked up by txid since that is what a payment is known by, but the leaf is the full hash,
+    //so it is returned with the branch and is what the proof has to be verified against
+    pub fn merkle_proof(&self, txid: &HashDigest) -> Option<(HashDigest, MerkleProof)>{
+        let index = self.txids().iter().position(|id| id == txid)?;
+        let full_hashes = self.full_hashes();
+        Some((full_hashes[index], MerkleProof::new(&full_hashes, index)?))
     }
 
     pub fn to_string(&self) -> String{
         serde_json::to_string(self).unwrap()
     }
 
-    fn update_nonce(&mut self, nonce: Nonce){
+    //encoded size in bytes
+    pub fn size(&self) -> usize{
+        self.encode().len()
+    }
+
+    pub fn update_nonce(&mut self, nonce: Nonce){
         self.block_header.nonce = nonce
     }
 
-    fn meets_difficulty(&self, hash: &str, target: usize) -> bool{
-        hash.starts_with(&"0".repeat(target))
+    pub fn meets_target(&self) -> bool{
+        hash_meets_target(&self.calculate_hash(), self.block_header.bits)
+    }
+
+    //single threaded nonce search, only sensible for easy targets
+    pub fn solve(&mut self){
+        while !self.meets_target(){
+            self.update_nonce(get_nonce());
+        }
+    }
+
+    //context free checks, anything depending on the chain is checked by the node
+    pub fn check(&self, params: &ChainParams) -> Result<()>{
+        if self.size() > MAX_BLOCK_SIZE{
+            bail!("block size {} exceeds the limit of {} bytes", self.size(), MAX_BLOCK_SIZE)
+        }
+        if self.transaction_count != self.transactions.len(){
+            bail!("transaction count {} does not match {} transactions", self.transaction_count, self.transactions.len())
+        }
+        let txids = self.txids();
+        //a repeated transaction would let two different lists share a merkle root
+        if txids.iter().collect::<HashSet<_>>().len() != txids.len(){
+            bail!("duplicate transaction in block")
+        }
+        if self.block_header.merkle_root != merkle_root(&self.full_hashes()){
+            bail!("merkle root does not match transactions")
+        }
+        if !is_valid_bits(self.block_header.bits, params.pow_limit_bits){
+            bail!("target {:08x} is above the pow limit", self.block_header.bits)
+        }
+        if !self.meets_target(){
+            bail!("hash does not meet target {:08x}", self.block_header.bits)
+        }
+        for tx in self.transactions.iter(){
+            tx.check()?;
+        }
+        match self.transactions.first(){
+            Some(tx) if is_coinbase(tx) => {
+                if tx.height != self.block_header.height{
+                    bail!("coinbase height {} does not match block", tx.height)
+                }
+            },
+            _ => bail!("first transaction is not a coinbase")
+        }
+        if self.transactions.iter().skip(1).any(is_coinbase){
+            bail!("more than one coinbase")
+        }
+        Ok(())
+    }
+
+    pub fn coinbase(&self) -> Option<&Transaction>{
+        self.transactions.first()
     }
     
+    //transactions are committed to through the merkle root so only the header is hashed
     pub fn calculate_hash(&self) -> HashDigest{
-        sha256(self.to_string())
+        self.block_header.hash()
     }
 
     pub fn mine(&mut self, stop: Arc<AtomicBool>, id: usize, network_tx: mpsc::Sender<NetworkCommand>){
@@ -96,12 +169,10 @@ impl Block{
 
         let mut count: usize = 1;
 
-        let target = self.block_header.difficulty;
-
         while !stop.load(Ordering::Relaxed){
             nonce = get_nonce();
             self.update_nonce(nonce);
-            if count%250000 == 0 && id==0{
+            if count.is_multiple_of(250000) && id==0{
                 if count < 1_000_000{
                     info!("each thread tried {},000 blocks", count/1_000);
                 }
@@ -110,8 +181,7 @@ impl Block{
                 }
             }
             count += 1;
-            let hash = self.calculate_hash();
-            if self.meets_difficulty(&String::from_utf8_lossy(&hash), target){
+            if self.meets_target(){
                 info!("Mined Block {}", self.block_header.height);
                 if let Err(e) = network_tx.try_send(NetworkCommand::Block(self.clone())){
                     error!("Issue sending messages: {}", e);
@@ -120,64 +190,26 @@ impl Block{
             }
         }
     }
-    
-    
-
-
-    fn rec_merkle_root(transactions: Vec<String>) -> HashDigest{
-        match transactions.len(){
-            0 => {
-                sha256("0000".to_string())
-            }
-            1 => {
-                let message = transactions[0].repeat(2);
-                sha256(message)
-                }
-            2 => {
-            let message = transactions[..2].join("");
-            sha256(message)
-            }
-            _ => {
-                let mut stack = Vec::new();
-                let mut message: String;
-                for pair in transactions.chunks(2) {
-                    if pair.len() == 2{
-                        message = pair[..2].join("");
-                        
-                    }
-                    else{
-                        message = pair[0].repeat(2);
-                    }
-                    stack.push(String::from_utf8_lossy(&sha256(message.clone())).to_string());
-                    message.clear();
-                }
-                Self::rec_merkle_root(stack)
-            }
-        }
-        
-    }
-
-
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BlockHeader{
     pub prev_hash: HashDigest, 
-    merkle_root: HashDigest, 
-    timestamp: usize,
-    difficulty: usize,
+    pub merkle_root: HashDigest, 
+    pub timestamp: usize,
+    pub bits: u32,
     nonce: Nonce,
     version: usize,
     pub height: usize,
 }
 
 impl BlockHeader{
-    pub fn new(prev_hash: HashDigest, merkle_root: HashDigest, version: usize, difficulty: usize, height: usize) -> Self{
+    pub fn new(prev_hash: HashDigest, merkle_root: HashDigest, version: usize, bits: u32, height: usize) -> Self{
         Self { 
             prev_hash, 
             merkle_root, 
             timestamp: get_timestamp(), 
-            difficulty, 
+            bits, 
             height,
             nonce: [0u8; 16], 
             version 
@@ -186,6 +218,56 @@ impl BlockHeader{
     pub fn to_string(&self) -> String{
         serde_json::to_string(self).unwrap()
     }
+
+    pub fn hash(&self) -> HashDigest{
+        sha256(self.encode())
+    }
+}
+
+//field order is version, prev_hash, merkle_root, timestamp, bits, nonce, height
+impl Encode for BlockHeader{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        self.version.encode_to(buf);
+        self.prev_hash.encode_to(buf);
+        self.merkle_root.encode_to(buf);
+        self.timestamp.encode_to(buf);
+        self.bits.encode_to(buf);
+        self.nonce.encode_to(buf);
+        self.height.encode_to(buf);
+    }
+}
+
+impl Decode for BlockHeader{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        let version = Decode::decode_from(reader)?;
+        let prev_hash = Decode::decode_from(reader)?;
+        let merkle_root = Decode::decode_from(reader)?;
+        let timestamp = Decode::decode_from(reader)?;
+        let bits = Decode::decode_from(reader)?;
+        let nonce = Decode::decode_from(reader)?;
+        let height = Decode::decode_from(reader)?;
+        Ok(Self { prev_hash, merkle_root, timestamp, bits, nonce, version, height })
+    }
+}
+
+//header followed by the transactions
+impl Encode for Block{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        self.block_header.encode_to(buf);
+        self.transactions.encode_to(buf);
+    }
+}
+
+impl Decode for Block{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        let block_header = BlockHeader::decode_from(reader)?;
+        let transactions: Vec<Transaction> = Vec::decode_from(reader)?;
+        Ok(Self { 
+            block_header, 
+            transaction_count: transactions.len(), 
+            transactions 
+        })
+    }
 }
 
 
@@ -218,7 +300,7 @@ pub async fn start_mine_handling(mut mining_rx : mpsc::Receiver<MiningCommand>,
 
     let mut stop = Arc::new(AtomicBool::new(false));
 
-    let block = node.write().await.get_next_block();
+    let block = node.write().await.get_next_block()?;
 
 
     let mut  handles = spawn_threads(block, Arc::clone(&stop), network_tx.clone());
@@ -239,7 +321,7 @@ pub async fn start_mine_handling(mut mining_rx : mpsc::Receiver<MiningCommand>,
                 }
 
                 stop = Arc::new(AtomicBool::new(false));
-                let block = node.write().await.get_next_block();
+                let block = node.write().await.get_next_block()?;
                 handles = spawn_threads(block, Arc::clone(&stop), network_tx.clone());
             
             }
@@ -256,3 +338,49 @@ pub async fn start_mine_handling(mut mining_rx : mpsc::Receiver<MiningCommand>,
     Ok(())
 } 
 
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn header_encoding_vector(){
+        let header = BlockHeader{
+            prev_hash: [0x22; 32],
+            merkle_root: [0x33; 32],
+            timestamp: 1767225600,
+            bits: 0x207fffff,
+            nonce: [0x44; 16],
+            version: 1,
+            height: 7,
+        };
+        let expected = concat!(
+            "0100000000000000",
+            "2222222222222222222222222222222222222222222222222222222222222222",
+            "3333333333333333333333333333333333333333333333333333333333333333",
+            "00b9556900000000",
+            "ffff7f20",
+            "44444444444444444444444444444444",
+            "0700000000000000",
+        );
+        assert_eq!(hex::encode(header.encode()), expected);
+        assert_eq!(hex::encode(header.hash()), "fe7a1525de942afaa59bc246d87b49dc3ee86c31d3705ae31bff53bf40662913");
+        assert_eq!(BlockHeader::decode(&header.encode()).unwrap().encode(), header.encode());
+    }
+
+    #[test]
+    fn block_hash_covers_header_only(){
+        let coinbase = Transaction::reward(10, vec![2; 33], 0, 1);
+        let mut block = Block::new(vec![coinbase], [0u8; 32], 0x207fffff, 0, 1);
+        assert_eq!(block.calculate_hash(), block.block_header.hash());
+
+        //the merkle root still ties the transactions to the hash
+        let hash = block.calculate_hash();
+        block.transactions[0].outputs[0].value = 11;
+        assert_eq!(block.calculate_hash(), hash);
+        assert_ne!(Block::get_merkle_root(block.transactions.clone()), block.block_header.merkle_root);
+
+        let decoded = Block::decode(&block.encode()).unwrap();
+        assert_eq!(decoded.calculate_hash(), hash);
+        assert_eq!(decoded.transactions, block.transactions);
+    }
+}
@@ -1,8 +1,8 @@
 use std::{
-    collections::HashMap, fs::File, net::{IpAddr, Ipv4Addr, SocketAddr}, path::Path, sync::Arc, time::Duration,
+    collections::HashMap, fs::File, io::BufReader, net::SocketAddr, path::{Path, PathBuf}, sync::Arc, time::Duration,
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result, anyhow, bail};
 
 use serde::{Deserialize, Serialize};
 use tokio::{
@@ -12,128 +12,797 @@ use tokio::{
 #[allow(unused)]
 use log::{error, info, warn};
 
-use crate::{messages::{GetBlocks, GetInv, GetPeerAddrs, Inv, Mempool, NewBlock, PeerAddrs, Ping, Pong, TransactionWithFee, Verack}, 
-    miner::{Block, BlockHeader, HashDigest, MiningCommand, sha256},
-    transactions::{Transaction, UTXOS, User, Wallet},
+use crate::{chain::{BlockEntry, BlockTree, MAX_BLOCK_SIZE, MAX_FUTURE_BLOCK_TIME, MEDIAN_TIME_SPAN, OrphanPool, RETARGET_INTERVAL, TimeOffsets, block_subsidy, block_work, difficulty, is_retarget_height, median_time, retarget}, messages::{BlocksPruned, GetBlocks, GetInv, GetPeerAddrs, Inv, Mempool, NewBlock, PeerAddrs, Ping, Pong, TransactionWithFee, Verack}, 
+    merkle::MerkleProof,
+    miner::{Block, BlockHeader, HashDigest, MiningCommand, get_timestamp},
+    params::{ChainParams, Network},
+    store::{BlockStore, PruneTarget, tmp_path, write_atomic},
+    transactions::{BlockUndo, SelectedInputs, Transaction, User, UtxoSet, UtxoView, Wallet, is_coinbase},
+    utxo_store::UtxoStore,
 };
-const DIFFICULTY: usize = 3;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+//the previous checkpoint of a node file
+fn backup_path(path: &Path) -> PathBuf{
+    let mut name = path.as_os_str().to_owned();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Node{
     pub user: User,
     pub height: usize,
     pub version: usize,
     mempool: Mempool,
+    //the active chain, its blocks are read from the store when needed, rebuilt from the store's
+    //headers on load so the node file only holds its tip
+    #[serde(skip)]
     headers: Vec<BlockHeader>,
-    pub block_chain: Vec<Block>,
-    pub difficulty: usize,
-    reward: usize,
-    utxos: UTXOS,
+    #[serde(default)]
+    tip: HashDigest,
+    //node files from before the block store held every active block here
+    #[serde(default, skip_serializing, rename = "block_chain")]
+    legacy_blocks: Vec<Block>,
+    #[serde(skip)]
+    store: BlockStore,
+    //node file checkpoints are written to, None for a node that only lives in memory
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    //block data older than the target is removed at each checkpoint
+    #[serde(default)]
+    pub prune: Option<PruneTarget>,
+    //blocks below this height may have been pruned, 0 on a node that has every block
+    #[serde(default)]
+    pruned_height: usize,
+    #[serde(skip)]
+    block_tree: BlockTree,
+    #[serde(skip)]
+    orphans: OrphanPool,
+    #[serde(skip)]
+    time_offsets: TimeOffsets,
+    pub bits: u32,
+    #[serde(default)]
+    pub network: Network,
+    #[serde(skip)]
+    utxos: UtxoStore,
     pub wallet: Wallet,
 }
 
 impl Node{
-    pub fn new() -> Self{
+    pub fn new(network: Network) -> Self{
         let user = User::new();
+        let params = network.params();
+        let genesis = params.genesis_block();
+        let mut block_tree = BlockTree::new();
+        block_tree.insert(genesis.calculate_hash(), BlockEntry::new(genesis.block_header.clone(), block_work(genesis.block_header.bits)));
+        let mut store = BlockStore::memory(params.magic);
+        store.append(&genesis).expect("memory stores can not fail");
 
         Self { 
             height: 0, 
             version: 0, 
             mempool: Mempool::new(), 
-            headers: Vec::new(),
-            block_chain: Vec::new(),
-            difficulty: DIFFICULTY,
+            headers: vec![genesis.block_header.clone()],
+            tip: genesis.calculate_hash(),
+            legacy_blocks: Vec::new(),
+            store,
+            path: None,
+            prune: None,
+            pruned_height: 0,
+            block_tree,
+            orphans: OrphanPool::new(),
+            time_offsets: TimeOffsets::new(),
+            bits: params.genesis_bits,
+            network,
             user: user.clone(),
-            reward: 10,
-            utxos: UTXOS::new(),
+            utxos: UtxoStore::memory(),
             wallet: Wallet::new(user.get_pub_key())
         }
     }
 
+    pub fn params(&self) -> ChainParams{
+        self.network.params()
+    }
+
     pub fn get_mempool_size(&self) -> usize{
         self.mempool.size()
     }
+    //a new node keeping its blocks in the network's block store, any blocks already there are removed
+    pub fn create(network: Network) -> Result<Self>{
+        let params = network.params();
+        let mut node = Self::new(network);
+        node.store = BlockStore::create(params.blocks_dir(), params.magic)?;
+        node.store.append(&params.genesis_block())?;
+        node.utxos = UtxoStore::create(params.chainstate_dir())?;
+        node.utxos.flush(&params.genesis_hash())?;
+        node.path = Some(params.node_file().into());
+        Ok(node)
+    }
+
+    //loads the node file, falling back to the previous checkpoint if it can not be read, and brings
+    //the chain, block store and utxo set back in line after a crash
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self>{
-        let file = File::open(path)?;
-        let node: Self = serde_json::from_reader(file)?;
+        let path = path.as_ref();
+        let _ = std::fs::remove_file(tmp_path(path));
+        let mut node = match Self::read_file(path){
+            Ok(node) => node,
+            Err(e) => {
+                let backup = backup_path(path);
+                warn!("Could not read {}: {:#}, loading {}", path.display(), e, backup.display());
+                Self::read_file(&backup).with_context(|| format!("could not read {} either", backup.display()))?
+            }
+        };
+        let params = node.params();
+        node.store = BlockStore::open(params.blocks_dir(), params.magic)?;
+        node.store.append(&params.genesis_block())?;
+        let legacy_blocks = std::mem::take(&mut node.legacy_blocks);
+        for block in legacy_blocks.iter(){
+            node.store.append(block)?;
+        }
+        if let Some(block) = legacy_blocks.last(){
+            node.tip = block.calculate_hash();
+        }
+        node.path = Some(path.to_path_buf());
+        node.utxos = match UtxoStore::open(params.chainstate_dir()){
+            Ok(utxos) => utxos,
+            Err(e) => {
+                warn!("Could not open the utxo set: {:#}, rebuilding it", e);
+                UtxoStore::create(params.chainstate_dir())?
+            }
+        };
+        node.restore_chain(!legacy_blocks.is_empty())?;
         Ok(node)
     }
 
-    pub fn store<P: AsRef<Path>>(&self, path: P) -> Result<()>{
-        let file = File::create(path)?;
-        serde_json::to_writer_pretty(&file, self)?;
+    //rebuilds the block tree and the active chain up to the checkpointed tip from the headers in the block store,
+    //then moves the utxo set to that tip if it was flushed at another block, and finally switches to any stored
+    //chain with more work
+    fn restore_chain(&mut self, reindex: bool) -> Result<()>{
+        let params = self.params();
+        self.block_tree = BlockTree::from_headers(self.store.headers().cloned().collect());
+        self.headers = self.chain_to(&self.tip);
+        let mut truncated = false;
+        if self.headers.is_empty(){
+            warn!("Block store has no header for tip {}, starting over from genesis", hex::encode(self.tip));
+            self.headers = self.chain_to(&params.genesis_hash());
+            truncated = true;
+        }
+        if self.headers.first().map(BlockHeader::hash) != Some(params.genesis_hash()){
+            bail!("block store does not start at the {:?} genesis block", self.network)
+        }
+        self.height = self.headers.len() - 1;
+        //the store only drops block data when pruning, and it may have pruned past the height named by a backup node file
+        while let Some(header) = self.headers.get(self.pruned_height.max(1))
+            && self.pruned_height > 0
+            && !self.store.contains(&header.hash()){
+            self.pruned_height = header.height + 1;
+        }
+        //blocks lost from the store take the rest of the chain with them
+        let missing = self.headers.iter()
+            .skip(self.pruned_height)
+            .position(|header| !self.store.contains(&header.hash()))
+            .map(|position| position + self.pruned_height);
+        if let Some(missing) = missing{
+            warn!("Block store is missing active block {}, rolling the chain back to {}", missing, missing - 1);
+            self.headers.truncate(missing);
+            self.height = missing - 1;
+            truncated = true;
+        }
+        let utxos_current = !reindex && !truncated && self.restore_utxos().unwrap_or_else(|e| {
+            warn!("Could not move the utxo set to the tip: {:#}", e);
+            false
+        });
+        if !utxos_current{
+            info!("Rebuilding the chain state from {} stored blocks", self.headers.len());
+            self.reindex()?;
+        }
+        self.bits = self.expected_bits(&self.get_prev_hash());
+        //blocks stored after the node file was last written
+        if let Some(best) = self.block_tree.best_tip()
+            && self.block_tree.get(&best).is_some_and(|entry| entry.chain_work > self.tip_work())
+            && let Err(e) = self.reorganize(best){
+            warn!("Could not switch to the stored chain with the most work: {:#}", e);
+        }
         Ok(())
     }
+
+    //moves the utxo set from the block it was last flushed at to the tip along the block tree, disconnecting
+    //with the stored undo data and connecting the stored blocks, false if it has to be rebuilt instead because
+    //that block is unknown or a block on the way is not stored
+    fn restore_utxos(&mut self) -> Result<bool>{
+        let tip = self.get_prev_hash();
+        let best = match self.utxos.best_block(){
+            Some(best) if best == tip => return Ok(true),
+            Some(best) => best,
+            None => return Ok(false)
+        };
+        let flushed = self.chain_to(&best);
+        if flushed.is_empty(){
+            return Ok(false)
+        }
+        info!("Moving the utxo set from block {} to the tip", hex::encode(best));
+        let fork = flushed.iter()
+            .zip(self.headers.iter())
+            .take_while(|(flushed, active)| flushed.hash() == active.hash())
+            .count();
+        for header in flushed[fork..].iter().rev(){
+            let hash = header.hash();
+            let (block, undo) = match (self.store.get(&hash)?, self.store.get_undo(&hash)?){
+                (Some(block), Some(undo)) => (block, undo),
+                _ => return Ok(false)
+            };
+            self.utxos.remove_block(&block, &undo)?;
+            self.utxos.sync(&header.prev_hash)?;
+        }
+        for header in self.headers[fork..].iter().cloned(){
+            let hash = header.hash();
+            let block = match self.store.get(&hash)?{
+                Some(block) => block,
+                None => return Ok(false)
+            };
+            let time = self.median_time_past(&header.prev_hash);
+            let undo = self.utxos.add_block(block, time)?;
+            self.store.put_undo(&hash, &undo)?;
+            self.utxos.sync(&hash)?;
+        }
+        self.utxos.flush(&tip)?;
+        Ok(true)
+    }
+
+    fn read_file(path: &Path) -> Result<Self>{
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    //headers from genesis up to hash, empty if hash is not in the block tree
+    fn chain_to(&self, hash: &HashDigest) -> Vec<BlockHeader>{
+        let mut headers = Vec::new();
+        let mut hash = *hash;
+        while let Some(entry) = self.block_tree.get(&hash){
+            headers.push(entry.header.clone());
+            hash = entry.header.prev_hash;
+        }
+        headers.reverse();
+        headers
+    }
+
+    //checkpoints the node, the cached utxo changes are written out first and the node file is replaced
+    //atomically with the one it replaces kept as a backup, old blocks are pruned once it names the new pruned height,
+    //blocks and their headers are already in the block store so the file only holds the tip and what lives in memory
+    pub fn store<P: AsRef<Path>>(&mut self, path: P) -> Result<()>{
+        let path = path.as_ref();
+        self.tip = self.get_prev_hash();
+        self.utxos.flush(&self.tip)?;
+        self.update_pruned_height();
+        let data = serde_json::to_vec(self)?;
+        if path.exists(){
+            std::fs::copy(path, backup_path(path))?;
+        }
+        write_atomic(path, &data)?;
+        self.prune_blocks()
+    }
+
+    fn update_pruned_height(&mut self){
+        if let Some(target) = self.prune{
+            self.pruned_height = self.pruned_height.max(self.store.prune_height(target, self.height));
+        }
+    }
+
+    fn prune_blocks(&mut self) -> Result<()>{
+        let pruned = self.store.prune(self.pruned_height)?;
+        if pruned > 0{
+            info!("Pruned {} block files below height {}", pruned, self.pruned_height);
+        }
+        Ok(())
+    }
+
+    //stores the node to the file it was created or loaded from
+    pub fn checkpoint(&mut self) -> Result<()>{
+        match self.path.clone(){
+            Some(path) => self.store(path),
+            None => Ok(())
+        }
+    }
+
+    pub fn pruned_height(&self) -> usize{
+        self.pruned_height
+    }
+
+    //handshake telling a peer how far our chain goes and which blocks we can still send
+    pub fn verack(&self, index: usize) -> Verack{
+        Verack::new(index, self.version, self.height, self.pruned_height)
+    }
     
-    pub fn new_transaction(&mut self, tx: Transaction) -> bool{
-        if !self.utxos.validate_transaction(tx.clone()){
-            return false
+    //view of the utxo set for transactions going into the next block
+    fn utxo_view(&self) -> UtxoView<'_>{
+        UtxoView::new(&self.utxos, self.height + 1, self.median_time_past(&self.get_prev_hash()), self.params().coinbase_maturity)
+    }
+
+    pub fn validate_transaction(&self, tx: &Transaction) -> Result<()>{
+        self.utxo_view().validate_transaction(tx)
+    }
+
+    //mature and immature wallet funds
+    pub fn balance(&self) -> (usize, usize){
+        self.wallet.balance(self.height + 1, self.params().coinbase_maturity)
+    }
+
+    pub fn get_inputs(&self, value: usize) -> Option<SelectedInputs>{
+        self.wallet.get_inputs(value, self.height + 1, self.params().coinbase_maturity)
+    }
+
+    pub fn new_transaction(&mut self, tx: Transaction) -> Result<()>{
+        self.validate_transaction(&tx)?;
+
+        let fee = self.utxos.get_fee(tx.clone())?;
+        if !self.mempool.add(tx, fee){
+            bail!("transaction already in the mempool")
+        }
+        Ok(())
+    }
+
+    pub fn validate_block(&self, block: &Block) -> Result<()>{
+        let header = &block.block_header;
+        if header.height != self.height + 1{
+            bail!("invalid height {} expected {}", header.height, self.height + 1)
+        }
+        if header.prev_hash != self.get_prev_hash(){
+            bail!("prev hash {} does not match tip", hex::encode(header.prev_hash))
+        }
+        if header.bits != self.bits{
+            bail!("invalid bits {:08x} expected {:08x}", header.bits, self.bits)
         }
+        block.check(&self.params())?;
+        let time = self.median_time_past(&header.prev_hash);
+        self.utxos.validate_block(block, block_subsidy(header.height), time, self.params().coinbase_maturity)
+    }
 
-        match self.utxos.get_fee(tx.clone()){
-            Some(fee) => {
-                self.mempool.add(tx, fee)
+    //rebuilds the utxo set from the active chain
+    fn reindex(&mut self) -> Result<()>{
+        if self.pruned_height > 0{
+            bail!("blocks below height {} are pruned so the chain state can not be rebuilt, start a new node", self.pruned_height)
+        }
+        self.utxos.clear()?;
+        self.wallet.clear_outputs();
+        for header in self.headers.clone(){
+            let block = self.read_block(&header.hash())?;
+            let time = self.median_time_past(&header.prev_hash);
+            //the genesis coinbase is never spendable
+            if header.height > 0{
+                self.wallet.update(block.clone());
+                let undo = self.utxos.add_block(block, time)?;
+                self.store.put_undo(&header.hash(), &undo)?;
             }
-            None => false
+            self.utxos.sync(&header.hash())?;
         }
+        self.utxos.flush(&self.get_prev_hash())?;
+        self.bits = self.expected_bits(&self.get_prev_hash());
+        Ok(())
+    }
+
+    fn read_block(&self, hash: &HashDigest) -> Result<Block>{
+        self.store.get(hash)?.ok_or_else(|| anyhow!("block {} is not stored", hex::encode(hash)))
+    }
+
+    fn read_undo(&self, hash: &HashDigest) -> Result<BlockUndo>{
+        self.store.get_undo(hash)?.ok_or_else(|| anyhow!("no undo data for block {}", hex::encode(hash)))
+    }
+
+    //block at height on the active chain, read from the store
+    pub fn active_block(&self, height: usize) -> Option<Block>{
+        let hash = self.headers.get(height)?.hash();
+        //blocks below the pruned height may be gone
+        if !self.store.contains(&hash){
+            return None
+        }
+        match self.read_block(&hash){
+            Ok(block) => Some(block),
+            Err(e) => {
+                error!("Could not read block {}: {}", height, e);
+                None
+            }
+        }
+    }
 
+    pub fn headers(&self) -> &[BlockHeader]{
+        &self.headers
     }
 
+    //target bits a block building on prev_hash must have
+    pub fn expected_bits(&self, prev_hash: &HashDigest) -> u32{
+        let params = self.params();
+        let parent = match self.block_tree.get(prev_hash){
+            Some(parent) => parent,
+            None => return params.genesis_bits
+        };
+        let header = &parent.header;
+        let height = header.height + 1;
+        if !params.retargeting || !is_retarget_height(height){
+            return header.bits
+        }
+        match self.get_ancestor(prev_hash, height - RETARGET_INTERVAL){
+            Some(first) => {
+                let timespan = header.timestamp.saturating_sub(first.header.timestamp);
+                let bits = retarget(header.bits, timespan, params.pow_limit_bits);
+                if bits != header.bits{
+                    info!("Retargeting at height {}: {:08x} -> {:08x}", height, header.bits, bits);
+                }
+                bits
+            }
+            None => header.bits
+        }
+    }
+
+    pub fn difficulty(&self) -> f64{
+        difficulty(self.bits, self.params().pow_limit_bits)
+    }
+
+    pub fn next_retarget(&self) -> usize{
+        (self.height / RETARGET_INTERVAL + 1) * RETARGET_INTERVAL
+    }
+
+    //walks back from hash to the block at height on the same branch
+    fn get_ancestor(&self, hash: &HashDigest, height: usize) -> Option<&BlockEntry>{
+        let mut entry = self.block_tree.get(hash)?;
+        while entry.height() > height{
+            entry = self.block_tree.get(&entry.header.prev_hash)?;
+        }
+        if entry.height() == height { Some(entry) } else { None }
+    }
+
+    //median timestamp of the last MEDIAN_TIME_SPAN blocks ending at hash
+    pub fn median_time_past(&self, hash: &HashDigest) -> usize{
+        let mut timestamps = Vec::new();
+        let mut next = self.block_tree.get(hash);
+        while let Some(entry) = next && timestamps.len() < MEDIAN_TIME_SPAN{
+            timestamps.push(entry.header.timestamp);
+            next = self.block_tree.get(&entry.header.prev_hash);
+        }
+        median_time(timestamps)
+    }
+
+    //our clock corrected by the median offset of our peers
+    pub fn adjusted_time(&self) -> usize{
+        (get_timestamp() as i64 + self.time_offsets.offset()) as usize
+    }
+
+    pub fn add_time_sample(&mut self, peer: SocketAddr, peer_time: usize){
+        self.time_offsets.add(peer.ip(), peer_time, get_timestamp());
+    }
+
+    //earliest time a block building on prev_hash may have, never behind network time
+    pub fn next_timestamp(&self, prev_hash: &HashDigest) -> usize{
+        self.adjusted_time().max(self.median_time_past(prev_hash) + 1)
+    }
+
+    fn check_timestamp(&self, header: &BlockHeader) -> Result<()>{
+        let median_time_past = self.median_time_past(&header.prev_hash);
+        if header.timestamp <= median_time_past{
+            bail!("timestamp {} is not after median time past {}", header.timestamp, median_time_past)
+        }
+        let adjusted_time = self.adjusted_time();
+        if header.timestamp > adjusted_time + MAX_FUTURE_BLOCK_TIME{
+            bail!("timestamp {} is more than {}s ahead of network time {}", header.timestamp, MAX_FUTURE_BLOCK_TIME, adjusted_time)
+        }
+        Ok(())
+    }
+
+    //header of the active block holding txid, the full hash that is its merkle leaf and the branch linking that leaf to the header's merkle root
+    pub fn get_merkle_proof(&self, txid: &HashDigest) -> Option<(BlockHeader, HashDigest, MerkleProof)>{
+        (0..=self.height).rev()
+            .filter_map(|height| self.active_block(height))
+            .find_map(|block| block.merkle_proof(txid).map(|(full_hash, proof)| (block.block_header.clone(), full_hash, proof)))
+    }
+
+    pub fn chain_work(&self, hash: &HashDigest) -> Option<u128>{
+        self.block_tree.get(hash).map(|entry| entry.chain_work)
+    }
+
+    pub fn tip_work(&self) -> u128{
+        match self.block_tree.get(&self.get_prev_hash()){
+            Some(entry) => entry.chain_work,
+            None => 0
+        }
+    }
+
+    fn is_active(&self, hash: &HashDigest, height: usize) -> bool{
+        self.headers.get(height).is_some_and(|header| header.hash() == *hash)
+    }
+
+    //adds a block then connects any orphans that were waiting on it
     pub fn add_block(&mut self, block: Block) -> bool{
-        if block.block_header.height != (self.height + 1) { warn!("Invalid height"); return false}
-        if self.utxos.validate_block(block.clone()){
-            for tx in block.transactions.clone(){
-                if tx.input_count != 0{
-                    self.mempool.remove(tx);
+        let hash = block.calculate_hash();
+        if !self.add_single_block(block){
+            return false
+        }
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop(){
+            for orphan in self.orphans.take_children(&parent){
+                let hash = orphan.calculate_hash();
+                info!("Connecting orphan block {}", orphan.block_header.height);
+                if self.add_single_block(orphan){
+                    parents.push(hash);
                 }
-            } 
-            self.block_chain.push(block.clone());
-            self.headers.push(block.block_header.clone());
-            self.height += 1;
-            self.wallet.update(block.clone());
-            self.utxos.add_block(block.clone());
-              
-        }else{
-            warn!("UTXOS rejected");
+            }
+        }
+        true
+    }
+
+    //keeps a block whose parent is unknown, returns false if it is already known or invalid
+    pub fn add_orphan(&mut self, block: Block) -> bool{
+        let hash = block.calculate_hash();
+        if self.block_tree.contains(&hash) || self.orphans.contains(&hash){
+            return false
+        }
+        if let Err(e) = block.check(&self.params()){
+            warn!("Rejected orphan block {}: {}", block.block_header.height, e);
+            return false
+        }
+        info!("Storing orphan block {}", block.block_header.height);
+        self.orphans.add(hash, block)
+    }
+
+    pub fn has_parent(&self, block: &Block) -> bool{
+        self.parent_height(&block.block_header).is_some()
+    }
+
+    fn parent_height(&self, header: &BlockHeader) -> Option<usize>{
+        self.block_tree.get(&header.prev_hash).map(|parent| parent.height())
+    }
+
+    //hashes of the active chain, dense near the tip then exponentially spaced, always ending at genesis
+    pub fn get_locator(&self) -> Vec<HashDigest>{
+        let mut locator = Vec::new();
+        let mut height = self.height;
+        let mut step = 1;
+        loop{
+            locator.push(self.headers[height].hash());
+            if height == 0{
+                break
+            }
+            if locator.len() >= 10{
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    //active blocks a peer asked for, a pruned node says so rather than sending a chain with a gap in it
+    pub fn blocks_for(&self, get_blocks: &GetBlocks) -> std::result::Result<Vec<Block>, BlocksPruned>{
+        let start_height = match self.find_fork_height(&get_blocks.locator){
+            Some(height) => height + 1,
+            None => get_blocks.start_height.max(1)
+        };
+        if start_height < self.pruned_height{
+            return Err(BlocksPruned::new(start_height, self.pruned_height))
+        }
+        Ok((start_height..=self.height)
+            .filter_map(|height| self.active_block(height))
+            .collect())
+    }
+
+    //height of the first locator hash found on our active chain
+    pub fn find_fork_height(&self, locator: &[HashDigest]) -> Option<usize>{
+        locator.iter()
+            .find_map(|hash| self.block_tree.get(hash)
+                .map(|entry| entry.height())
+                .filter(|height| self.is_active(hash, *height)))
+    }
+
+    fn add_single_block(&mut self, block: Block) -> bool{
+        let hash = block.calculate_hash();
+        if self.block_tree.contains(&hash){
+            return false
+        }
+        let height = block.block_header.height;
+        if let Err(e) = self.accept_block(&block){
+            warn!("Rejected block {}: {}", height, e);
+            return false
+        }
+
+        let parent_work = match self.block_tree.get(&block.block_header.prev_hash){
+            Some(parent) => parent.chain_work,
+            None => 0
+        };
+        let chain_work = parent_work + block_work(block.block_header.bits);
+        //stored as soon as it is accepted so side branches survive a restart too, reorganize removes it again if it fails validation
+        if let Err(e) = self.store.append(&block){
+            error!("Could not store block {}: {}", height, e);
+            return false
+        }
+        self.block_tree.insert(hash, BlockEntry::new(block.block_header, chain_work));
+
+        if chain_work <= self.tip_work(){
+            info!("Stored block {} on side branch", height);
+            return true
+        }
+        if let Err(e) = self.reorganize(hash){
+            warn!("Rejected block {}: {}", height, e);
             return false
         }
-        
         true
     }
 
-    pub fn get_next_transactions(&mut self) -> Vec<Transaction>{
-        let mut valid_transactions = true;
-        let txs = self.mempool.get_next_transactions();
-        for tx in txs.clone(){
-            if !self.utxos.validate_transaction(tx.clone()){
-                self.mempool.remove(tx.clone());
-                valid_transactions = false
+    //checks a block before it is stored in the tree, full validation happens on connection
+    fn accept_block(&self, block: &Block) -> Result<()>{
+        let header = &block.block_header;
+        let parent_height = match self.parent_height(header){
+            Some(height) => height,
+            None => bail!("unknown parent {}", hex::encode(header.prev_hash))
+        };
+        if header.height != parent_height + 1{
+            bail!("invalid height {} expected {}", header.height, parent_height + 1)
+        }
+        let expected_bits = self.expected_bits(&header.prev_hash);
+        if header.bits != expected_bits{
+            bail!("invalid bits {:08x} expected {:08x}", header.bits, expected_bits)
+        }
+        self.check_timestamp(header)?;
+        block.check(&self.params())
+    }
+
+    //switches the active chain to end at new_tip, restoring the old chain if any block fails
+    fn reorganize(&mut self, new_tip: HashDigest) -> Result<()>{
+        let mut branch = Vec::new();
+        let mut hash = new_tip;
+        while let Some(entry) = self.block_tree.get(&hash) && !self.is_active(&hash, entry.height()){
+            branch.push(hash);
+            hash = entry.header.prev_hash;
+        }
+        let fork_height = match branch.last(){
+            Some(first) => self.block_tree.get(first).unwrap().height() - 1,
+            None => return Ok(())
+        };
+        if fork_height + 1 < self.pruned_height{
+            bail!("fork point {} is below the pruned height {}", fork_height, self.pruned_height)
+        }
+        if fork_height < self.height{
+            info!("Reorganizing from height {} to fork point {}", self.height, fork_height);
+        }
+
+        //read before anything changes so a store error leaves the chain as it is
+        let blocks = branch.iter().rev()
+            .map(|hash| self.read_block(hash))
+            .collect::<Result<Vec<Block>>>()?;
+
+        let mut disconnected = Vec::new();
+        while self.height > fork_height{
+            disconnected.push(self.disconnect_tip()?);
+        }
+        for (index, block) in blocks.into_iter().enumerate(){
+            if let Err(e) = self.connect_block(block){
+                //a block is only dropped for failing validation, one the stores could not read or write is tried again later
+                if e.downcast_ref::<std::io::Error>().is_none(){
+                    let invalid: Vec<HashDigest> = branch.iter().rev().skip(index).copied().collect();
+                    for hash in invalid.iter(){
+                        self.block_tree.remove(hash);
+                    }
+                    if let Err(e) = self.store.remove(&invalid){
+                        error!("Could not remove invalid blocks from the block store: {:#}", e);
+                    }
+                }
+                while self.height > fork_height{
+                    self.disconnect_tip()?;
+                }
+                for block in disconnected.into_iter().rev(){
+                    self.connect_block(block).context("could not reconnect the previous chain")?;
+                }
+                return Err(e)
             }
         }
+        Ok(())
+    }
 
-        if valid_transactions{
-            txs
+    fn connect_block(&mut self, block: Block) -> Result<()>{
+        self.validate_block(&block)?;
+        let hash = block.calculate_hash();
+        let time = self.median_time_past(&block.block_header.prev_hash);
+        let undo = self.utxos.add_block(block.clone(), time)?;
+        //the undo data is stored before the block counts as connected so it can always be disconnected again
+        if let Err(e) = self.store.put_undo(&hash, &undo){
+            self.utxos.remove_block(&block, &undo)?;
+            return Err(e)
+        }
+        for tx in block.transactions.clone(){
+            if tx.input_count != 0{
+                self.mempool.remove(tx);
+            }
+        } 
+        self.headers.push(block.block_header.clone());
+        self.height += 1;
+        self.wallet.update(block.clone());
+        self.bits = self.expected_bits(&hash);
+        //the node file is written along with the utxo set so the two stay at the same tip,
+        //a failure does not make the block any less connected
+        if self.utxos.needs_flush() && let Err(e) = self.checkpoint(){
+            error!("Checkpoint failed: {:#}", e);
+        }
+        Ok(())
+    }
+
+    //removes the tip from the active chain, returning its transactions to the mempool
+    fn disconnect_tip(&mut self) -> Result<Block>{
+        let hash = self.get_prev_hash();
+        let block = self.read_block(&hash)?;
+        let undo = self.read_undo(&hash)?;
+        self.utxos.remove_block(&block, &undo)?;
+        self.headers.pop();
+        self.height -= 1;
+        self.wallet.revert(&block, &undo);
+        for tx in block.transactions.iter().filter(|tx| !is_coinbase(tx)){
+            if let Err(e) = self.new_transaction(tx.clone()){
+                info!("Dropping transaction {} of disconnected block: {:#}", hex::encode(tx.txid()), e);
+            }
+        }
+        self.bits = self.expected_bits(&self.get_prev_hash());
+        if self.utxos.needs_flush() && let Err(e) = self.checkpoint(){
+            error!("Checkpoint failed: {:#}", e);
+        }
+        Ok(block)
+    }
+
+    //transactions for the next block with their fees, dropping any that conflict with one already chosen,
+    //fees come from the view so a transaction spending an earlier one in the block pays its fee too
+    pub fn get_next_transactions(&mut self, max_size: usize) -> Result<Vec<(Transaction, usize)>>{
+        let txs = self.mempool.get_next_transactions(max_size);
+        let mut view = self.utxo_view();
+        let mut chosen = Vec::new();
+        let mut invalid = Vec::new();
+        for tx in txs{
+            match view.validate_transaction(&tx){
+                Ok(()) => {
+                    let fee = view.get_fee(&tx)?;
+                    view.apply(&tx);
+                    chosen.push((tx, fee));
+                }
+                //the utxo set could not be read, which says nothing about the transaction
+                Err(e) if e.downcast_ref::<std::io::Error>().is_some() => return Err(e),
+                Err(e) => {
+                    warn!("Invalid transaction {}: {:#}", hex::encode(tx.txid()), e);
+                    invalid.push(tx);
+                }
+            }
+        }
+
+        if invalid.is_empty(){
+            Ok(chosen)
         } else {
-            warn!("Invalid transaction: {:?}", self);
-            self.get_next_transactions()
+            for tx in invalid{
+                self.mempool.remove(tx);
+            }
+            self.get_next_transactions(max_size)
         }
     }
 
     pub fn get_prev_hash(&self) -> HashDigest{
-        match self.block_chain.last(){
-            Some(block) => {
-                block.calculate_hash()
-            }
-            None => {
-                sha256("00".to_string())
+        self.headers.last()
+            .expect("chain always holds the genesis block")
+            .hash()
+    }
+
+    //fills a template up to the block size limit, dropping the lowest fee transactions if the final block is still too big
+    pub fn get_next_block(&mut self) -> Result<Block>{
+        let empty_size = self.build_block(Vec::new()).size();
+        let mut transactions = self.get_next_transactions(MAX_BLOCK_SIZE.saturating_sub(empty_size))?;
+        loop{
+            let block = self.build_block(transactions.clone());
+            if block.size() <= MAX_BLOCK_SIZE || transactions.pop().is_none(){
+                return Ok(block)
             }
         }
     }
 
-    pub fn get_next_block(&mut self) -> Block{
-        let mut next_transactions = self.get_next_transactions();
-        next_transactions.push(Transaction::reward(self.reward, self.user.get_pub_key(), self.version));
-        Block::new(next_transactions, self.get_prev_hash(), self.difficulty, self.version, self.height.clone() + 1)
+    fn build_block(&self, transactions: Vec<(Transaction, usize)>) -> Block{
+        let height = self.height + 1;
+        let fees: usize = transactions.iter()
+            .map(|(_, fee)| fee)
+            .sum();
+        let mut next_transactions = vec![Transaction::reward(block_subsidy(height) + fees, self.user.get_pub_key(), self.version, height)];
+        next_transactions.extend(transactions.into_iter().map(|(tx, _)| tx));
+        let prev_hash = self.get_prev_hash();
+        let mut block = Block::new(next_transactions, prev_hash, self.bits, self.version, height);
+        block.block_header.timestamp = self.next_timestamp(&prev_hash);
+        block
     }
 
     
@@ -150,6 +819,7 @@ pub enum NetworkCommand{
 enum NetMessage{
     NewBlock(NewBlock),
     GetBlocks(GetBlocks),
+    BlocksPruned(BlocksPruned),
     Verack(Verack),
     Transaction(Transaction),
     GetInv(GetInv),
@@ -246,7 +916,7 @@ impl PeerManager{
     }
 
     async fn send(&self, peer: &SocketAddr, response: ConnectionResponse) -> Result<()>{
-        self.peers.get(&peer).unwrap().tx.send(response).await?;
+        self.peers.get(peer).unwrap().tx.send(response).await?;
         Ok(())
     }
 
@@ -275,7 +945,8 @@ async fn network_command_handling(
     peer_manager: Arc<Mutex<PeerManager>>, 
     node: Arc<RwLock<Node>>, 
     miner_tx: mpsc::Sender<MiningCommand>, 
-    handler_tx: mpsc::Sender<ConnectionEvent>
+    handler_tx: mpsc::Sender<ConnectionEvent>,
+    magic: [u8; 4]
 ){
 
     while let Some(msg) = network_rx.recv().await{
@@ -305,8 +976,17 @@ async fn network_command_handling(
             }
             NetworkCommand::Transaction(transaction) => {
                 info!("Transaction preparing");
-                if !node.read().await.utxos.validate_transaction(transaction.clone()) {continue}
-                let fee = node.read().await.utxos.get_fee(transaction.clone()).unwrap();
+                let checked = {
+                    let node_lock = node.read().await;
+                    node_lock.validate_transaction(&transaction).and_then(|_| node_lock.utxos.get_fee(transaction.clone()))
+                };
+                let fee = match checked{
+                    Ok(fee) => fee,
+                    Err(e) => {
+                        warn!("Rejected transaction {}: {:#}", hex::encode(transaction.txid()), e);
+                        continue
+                    }
+                };
                 info!("Fee: {}", fee);
                 {
                     let mut  node_lock = node.write().await;
@@ -340,21 +1020,21 @@ async fn network_command_handling(
                                                 
                             let new_peer_clone = peer.clone();
                             tokio::spawn(async move {
-                                    connection_receiver(reader, &new_peer_clone, event_tx_clone)
+                                    connection_receiver(reader, &new_peer_clone, event_tx_clone, magic)
                                     .await
                                     .expect("reader failed");
                                 });
 
                             tokio::spawn(async move {
-                                    connection_sender(writer, rx)
+                                    connection_sender(writer, rx, magic)
                                     .await
                                 });
 
                             tokio::time::sleep(Duration::from_millis(200)).await;
 
                             {
-                                let node_clone = node.read().await.clone();
-                                let msg = ConnectionResponse::send(NetMessage::Verack(Verack::new(0,node_clone.version,node_clone.height)).to_string());
+                                let verack = node.read().await.verack(0);
+                                let msg = ConnectionResponse::send(NetMessage::Verack(verack).to_string());
                                 let peer_manager_lock = peer_manager.lock().await;
                                 peer_manager_lock.send(&peer, msg).await.unwrap();
                             }
@@ -367,7 +1047,7 @@ async fn network_command_handling(
     }
 }
 
-async fn start_network_handler(mut handler_rx: mpsc::Receiver<ConnectionEvent> ,peer_manager: Arc<Mutex<PeerManager>>, node: Arc<RwLock<Node>>, handler_tx: mpsc::Sender<ConnectionEvent>, miner_tx: mpsc::Sender<MiningCommand>) -> Result<()>{
+async fn start_network_handler(mut handler_rx: mpsc::Receiver<ConnectionEvent> ,peer_manager: Arc<Mutex<PeerManager>>, node: Arc<RwLock<Node>>, handler_tx: mpsc::Sender<ConnectionEvent>, miner_tx: mpsc::Sender<MiningCommand>, magic: [u8; 4]) -> Result<()>{
         while let Some(event) = handler_rx.recv().await{
             let peer = event.peer;
             match event.connection_event_type{
@@ -385,14 +1065,20 @@ async fn start_network_handler(mut handler_rx: mpsc::Receiver<ConnectionEvent> ,
                         Ok(net_msg) => {
                             match net_msg{
                                 NetMessage::Verack(verack) => {
-                                    let node_clone = node.read().await.clone();
+                                    node.write().await.add_time_sample(peer, verack.timestamp);
+                                    let height = node.read().await.height;
                                     if verack.index == 0{
                                         {
-                                            peer_manager.lock().await.send(&peer,ConnectionResponse::send(NetMessage::Verack(Verack::new(1, node_clone.version, node_clone.height)).to_string())).await.unwrap();
+                                            let reply = node.read().await.verack(1);
+                                            peer_manager.lock().await.send(&peer,ConnectionResponse::send(NetMessage::Verack(reply).to_string())).await.unwrap();
                                         }
                                     }
-                                    if verack.height > node_clone.height{
-                                        let msg = NetMessage::GetBlocks(GetBlocks { start_height: node_clone.height + 1});
+                                    if verack.height > height && verack.pruned_height > height + 1{
+                                        info!("{} has pruned blocks below {}, not syncing from it", peer, verack.pruned_height);
+                                    }
+                                    else if verack.height > height{
+                                        let locator = node.read().await.get_locator();
+                                        let msg = NetMessage::GetBlocks(GetBlocks::new(height + 1, locator));
                                         {
                                             let peer_manager_lock = peer_manager.lock().await;
                                             peer_manager_lock.send(&peer, ConnectionResponse::send(msg.to_string())).await.unwrap();
@@ -402,9 +1088,7 @@ async fn start_network_handler(mut handler_rx: mpsc::Receiver<ConnectionEvent> ,
 
                                 NetMessage::GetInv(_) => {
                                     
-                                    let node_clone = node.read().await.clone();
-                                    let mempool = node_clone.mempool;
-                                    let msg = NetMessage::Inv(Inv::new(mempool.get_inv()));
+                                    let msg = NetMessage::Inv(Inv::new(node.read().await.mempool.get_inv()));
                                     
                                     response = Some(ConnectionResponse::send(msg.to_string()));
                                 }
@@ -412,7 +1096,7 @@ async fn start_network_handler(mut handler_rx: mpsc::Receiver<ConnectionEvent> ,
                                 NetMessage::Inv(inv) => {
                                     let mut txwf = Vec::new();
                                     for tx in inv.mempool.clone(){
-                                        if let Some(fee) = node.read().await.utxos.get_fee(tx.clone()) && node.read().await.utxos.validate_transaction(tx.clone()){
+                                        if let Ok(fee) = node.read().await.utxos.get_fee(tx.clone()) && node.read().await.validate_transaction(&tx).is_ok(){
                                             txwf.push(TransactionWithFee::new(tx, fee));
                                         }
                                     }
@@ -454,22 +1138,23 @@ async fn start_network_handler(mut handler_rx: mpsc::Receiver<ConnectionEvent> ,
                                                 
                                                 let new_peer_clone = new_peer.clone();
                                                 tokio::spawn(async move {
-                                                    connection_receiver(reader, &new_peer_clone, event_tx_clone)
+                                                    connection_receiver(reader, &new_peer_clone, event_tx_clone, magic)
                                                     .await
                                                     .expect("reader failed");
                                                 });
 
                                                 tokio::spawn(async move {
-                                                    connection_sender(writer, rx)
+                                                    connection_sender(writer, rx, magic)
                                                     .await
                                                 });
 
                                                 tokio::time::sleep(Duration::from_millis(100)).await;
 
                                                 {
-                                                let msg = ConnectionResponse::send(NetMessage::Verack(Verack::new(0,1,1,)).to_string());
+                                                let verack = node.read().await.verack(0);
+                                                let msg = ConnectionResponse::send(NetMessage::Verack(verack).to_string());
                                                 let peer_manager_lock = peer_manager.lock().await;
-                                                peer_manager_lock.send(&new_peer, msg).await.unwrap();
+                                                peer_manager_lock.send(new_peer, msg).await.unwrap();
                                                 }
                                             }
                                             
@@ -491,29 +1176,39 @@ async fn start_network_handler(mut handler_rx: mpsc::Receiver<ConnectionEvent> ,
                                 }
                                 
                                 NetMessage::Transaction(transaction) => {
-                                    let is_valid = {
+                                    let result = {
                                         node.write().await.new_transaction(transaction.clone())
                                     };
                                     
-                                    if is_valid{
-                                        let peer_manager_lock = peer_manager.lock().await;
-                                        peer_manager_lock.broadcast(
-                                            NetMessage::Transaction(
-                                                transaction
-                                            ).to_string())
-                                            .await;
-                                    }else{
-                                        warn!("Not new transaction: {:?}", transaction);
+                                    match result{
+                                        Ok(()) => {
+                                            let peer_manager_lock = peer_manager.lock().await;
+                                            peer_manager_lock.broadcast(
+                                                NetMessage::Transaction(
+                                                    transaction
+                                                ).to_string())
+                                                .await;
+                                        }
+                                        Err(e) => warn!("Not new transaction {}: {:#}", hex::encode(transaction.txid()), e)
                                     }
 
                                 }
 
                                 NetMessage::NewBlock(new_block) => {
                                     let block = new_block.block;
-                                    let is_new = {
+                                    let (is_new, missing_ancestors) = {
                                         let mut  node_lock = node.write().await;
-                                        node_lock.add_block(block.clone())
+                                        if node_lock.has_parent(&block){
+                                            (node_lock.add_block(block.clone()), None)
+                                        }else if node_lock.add_orphan(block.clone()){
+                                            (false, Some(GetBlocks::new(node_lock.height + 1, node_lock.get_locator())))
+                                        }else{
+                                            (false, None)
+                                        }
                                     };
+                                    if let Some(get_blocks) = missing_ancestors{
+                                        response = Some(ConnectionResponse::send(NetMessage::GetBlocks(get_blocks).to_string()));
+                                    }
                                     if is_new{
                                     {
                                         let peer_manager_lock = peer_manager.lock().await;
@@ -524,13 +1219,23 @@ async fn start_network_handler(mut handler_rx: mpsc::Receiver<ConnectionEvent> ,
                                 }
 
                                 NetMessage::GetBlocks(get_blocks) => {
-                                    for block in &node.read().await.block_chain[get_blocks.start_height-1..]{
-                                        let msg = NetMessage::NewBlock(NewBlock::new(block.clone()));
-                                        peer_manager.lock().await.send(&peer, ConnectionResponse::send(msg.to_string())).await.unwrap();
-                                        tokio::time::sleep(Duration::from_millis(100)).await;
+                                    let blocks = node.read().await.blocks_for(&get_blocks);
+                                    match blocks{
+                                        Ok(blocks) => for block in blocks{
+                                            let msg = NetMessage::NewBlock(NewBlock::new(block));
+                                            peer_manager.lock().await.send(&peer, ConnectionResponse::send(msg.to_string())).await.unwrap();
+                                        }
+                                        Err(pruned) => {
+                                            info!("{} asked for blocks from {} but blocks below {} are pruned", peer, pruned.start_height, pruned.pruned_height);
+                                            response = Some(ConnectionResponse::send(NetMessage::BlocksPruned(pruned).to_string()));
+                                        }
                                     }
                                 }   
 
+                                NetMessage::BlocksPruned(pruned) => {
+                                    warn!("{} can not send blocks from {}, it pruned everything below {}", peer, pruned.start_height, pruned.pruned_height);
+                                }
+
                                 }
 
                                 if let Some(response) = response{
@@ -555,12 +1260,13 @@ async fn start_network_handler(mut handler_rx: mpsc::Receiver<ConnectionEvent> ,
 async fn connection_receiver(
     mut reader: OwnedReadHalf, 
     peer: &SocketAddr, 
-    tx: mpsc::Sender<ConnectionEvent>
+    tx: mpsc::Sender<ConnectionEvent>,
+    magic: [u8; 4]
 ) -> Result<()>{
     loop{
-        let mut len_bytes = [0u8; 4];
+        let mut magic_bytes = [0u8; 4];
         
-        match reader.read_exact(&mut len_bytes).await{
+        match reader.read_exact(&mut magic_bytes).await{
             Ok(_) => {},
             Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
                 tx.send(ConnectionEvent::close(peer.clone())).await?;
@@ -574,6 +1280,15 @@ async fn connection_receiver(
 
         }
 
+        //peers on another network are dropped
+        if magic_bytes != magic{
+            warn!("Wrong network magic {} from: {}", hex::encode(magic_bytes), peer);
+            tx.send(ConnectionEvent::close(*peer)).await?;
+            return Ok(())
+        }
+
+        let mut len_bytes = [0u8; 4];
+        reader.read_exact(&mut len_bytes).await?;
         let len = u32::from_be_bytes(len_bytes) as usize;
         let mut buf = vec![0u8; len];
         reader.read_exact(&mut buf).await?;
@@ -587,7 +1302,8 @@ async fn connection_receiver(
 
 async fn connection_sender(
     mut writer: OwnedWriteHalf, 
-    mut rx: mpsc::Receiver<ConnectionResponse>
+    mut rx: mpsc::Receiver<ConnectionResponse>,
+    magic: [u8; 4]
 ){
     while let Some(response) = rx.recv().await{
         match response.connection_response_type{
@@ -599,6 +1315,7 @@ async fn connection_sender(
                 info!("Sending: {}", message);
                 let bytes = message.as_bytes();
                 let len = (bytes.len() as u32).to_be_bytes();
+                writer.write_all(&magic).await.unwrap();
                 writer.write_all(&len).await.unwrap();
                 writer.write_all(bytes).await.unwrap();           
             }
@@ -624,6 +1341,7 @@ pub async fn start_network_handling(
 ) -> Result<()>{
 
     info!("Starting Network Handling ...");
+    let magic = node.read().await.params().magic;
     
     let listener = TcpListener::bind(addr).await?;
     info!("Listening on: {}", addr);
@@ -638,7 +1356,7 @@ pub async fn start_network_handling(
     let node_clone = Arc::clone(&node);
     let miner_tx_clone = miner_tx.clone();
     tokio::spawn(async move {
-        start_network_handler(rx, peer_manager_clone, node_clone, event_tx_clone, miner_tx_clone)
+        start_network_handler(rx, peer_manager_clone, node_clone, event_tx_clone, miner_tx_clone, magic)
         .await
         .expect("Network handler failed");
     });
@@ -650,7 +1368,7 @@ pub async fn start_network_handling(
     let miner_tx_clone = miner_tx.clone();
     let handler_tx_clone = event_tx.clone();
     tokio::spawn(async move {
-        network_command_handling(network_rx, peer_manager_clone, node_clone, miner_tx_clone, handler_tx_clone)
+        network_command_handling(network_rx, peer_manager_clone, node_clone, miner_tx_clone, handler_tx_clone, magic)
         .await
     });
 
@@ -674,16 +1392,686 @@ pub async fn start_network_handling(
         //spawning connection receiver for peer
         let event_tx_clone = event_tx.clone();
         tokio::spawn(async move {
-            connection_receiver(reader, &peer, event_tx_clone)
+            connection_receiver(reader, &peer, event_tx_clone, magic)
             .await
             .expect("reader failed");
         });
 
         //spawning connect sender for peer
         tokio::spawn(async move {
-            connection_sender(writer, rx)
+            connection_sender(writer, rx, magic)
             .await
         });
     }
 }
 
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::{chain::MAX_MONEY, encoding::Encode, miner::sha256, store::MIN_BLOCKS_TO_KEEP, transactions::{SIGHASH_ALL, SIGHASH_ANYONECANPAY}};
+
+    const POW_LIMIT_BITS: u32 = 0x207fffff;
+
+    fn test_node() -> Node{
+        Node::new(Network::Regtest)
+    }
+
+    fn solved(mut block: Block) -> Block{
+        block.solve();
+        block
+    }
+
+    #[test]
+    fn accepts_valid_block(){
+        let mut node = test_node();
+        let block = solved(node.get_next_block().unwrap());
+        assert!(node.add_block(block));
+        assert_eq!(node.height, 1);
+        assert_eq!(node.wallet.value, 10);
+    }
+
+    #[test]
+    fn rejects_bad_headers(){
+        let mut node = test_node();
+
+        let mut block = node.get_next_block().unwrap();
+        block.block_header.prev_hash = sha256("other");
+        assert!(node.validate_block(&solved(block)).is_err());
+
+        let mut block = node.get_next_block().unwrap();
+        block.block_header.height = 2;
+        assert!(node.validate_block(&solved(block)).is_err());
+
+        let mut block = node.get_next_block().unwrap();
+        block.block_header.bits = 0x2100ffff;
+        assert!(node.validate_block(&block).is_err());
+        assert!(block.check(&node.params()).is_err());
+
+        let block = solved(node.get_next_block().unwrap());
+        assert!(node.validate_block(&block).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_transactions(){
+        let mut node = test_node();
+
+        //merkle root no longer commits to the transactions
+        let mut block = node.get_next_block().unwrap();
+        block.transactions.push(Transaction::reward(10, node.user.get_pub_key(), 0, 1));
+        assert!(block.check(&node.params()).is_err());
+
+        //coinbase not in first position
+        let mut block = node.get_next_block().unwrap();
+        block.transactions.clear();
+        assert!(block.check(&node.params()).is_err());
+
+        //coinbase for the wrong height
+        let coinbase = Transaction::reward(10, node.user.get_pub_key(), 0, 2);
+        let block = tip_block(&node, vec![coinbase], 1);
+        assert!(block.check(&node.params()).is_err());
+
+        //coinbase pays more than the reward
+        let coinbase = Transaction::reward(11, node.user.get_pub_key(), 0, 1);
+        let block = tip_block(&node, vec![coinbase], 1);
+        assert!(block.check(&node.params()).is_ok());
+        assert!(node.validate_block(&block).is_err());
+    }
+
+    fn next_block(node: &Node, parent: Option<&Block>, height: usize) -> Block{
+        let prev_hash = match parent{
+            Some(parent) => parent.calculate_hash(),
+            None => node.params().genesis_hash()
+        };
+        let coinbase = Transaction::reward(10, node.user.get_pub_key(), 0, height);
+        let mut block = Block::new(vec![coinbase], prev_hash, node.expected_bits(&prev_hash), 0, height);
+        block.block_header.timestamp = node.next_timestamp(&prev_hash);
+        solved(block)
+    }
+
+    //solved block on the node's tip with the given transactions
+    fn tip_block(node: &Node, transactions: Vec<Transaction>, height: usize) -> Block{
+        let prev_hash = node.get_prev_hash();
+        let mut block = Block::new(transactions, prev_hash, node.bits, 0, height);
+        block.block_header.timestamp = node.next_timestamp(&prev_hash);
+        solved(block)
+    }
+
+    #[test]
+    fn pruned_nodes_answer_deep_requests_gracefully(){
+        let mut node = test_node();
+        let params = node.params();
+        node.store = BlockStore::memory(params.magic).with_max_segment_size(2048);
+        node.store.append(&params.genesis_block()).unwrap();
+        let mut other = test_node();
+        let ours = mine_blocks(&mut node, MIN_BLOCKS_TO_KEEP + 50);
+
+        node.prune = Some(PruneTarget::Blocks(0));
+        node.update_pruned_height();
+        node.prune_blocks().unwrap();
+        let pruned_height = node.pruned_height();
+        assert!(pruned_height > 0 && pruned_height <= node.height + 1 - MIN_BLOCKS_TO_KEEP);
+        assert!(node.active_block(1).is_none());
+        assert!(node.active_block(node.height).is_some());
+        assert_eq!(node.headers().len(), node.height + 1);
+
+        //a peer at genesis is told the blocks are gone instead of getting a chain with a gap
+        let deep = GetBlocks::new(1, vec![params.genesis_hash()]);
+        let refused = node.blocks_for(&deep).unwrap_err();
+        assert_eq!((refused.start_height, refused.pruned_height), (1, pruned_height));
+        //one close to the tip is still served
+        let near = GetBlocks::new(node.height - 9, vec![ours[node.height - 11].calculate_hash()]);
+        assert_eq!(node.blocks_for(&near).unwrap().len(), 10);
+
+        //a longer chain forking below the pruned height can not be switched to
+        let theirs = mine_blocks(&mut other, 10);
+        for block in theirs.iter().take(3){
+            node.add_block(block.clone());
+        }
+        let tip = node.get_prev_hash();
+        assert!(node.reorganize(theirs[2].calculate_hash()).is_err());
+        assert_eq!(node.get_prev_hash(), tip);
+        assert!(node.reindex().is_err());
+    }
+
+    fn mine_blocks(node: &mut Node, count: usize) -> Vec<Block>{
+        mine_blocks_from(node, &[], count)
+    }
+
+    //mines count blocks on top of the given chain
+    fn mine_blocks_from(node: &mut Node, chain: &[Block], count: usize) -> Vec<Block>{
+        let mut blocks: Vec<Block> = chain.to_vec();
+        for _ in 0..count{
+            let block = next_block(node, blocks.last(), blocks.len() + 1);
+            assert!(node.add_block(block.clone()));
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    //mines until the first coinbase can be spent in the next block
+    fn mine_to_maturity(node: &mut Node) -> Vec<Block>{
+        let maturity = node.params().coinbase_maturity;
+        mine_blocks(node, maturity)
+    }
+
+    #[test]
+    fn coinbase_collects_fees(){
+        let mut node = test_node();
+        let blocks = mine_to_maturity(&mut node);
+        let height = node.height + 1;
+        let tx = spend(&node, &blocks[0].transactions[0], 7);
+        assert!(node.new_transaction(tx).is_ok());
+
+        let block = solved(node.get_next_block().unwrap());
+        assert_eq!(block.transactions[0].output_value().unwrap(), block_subsidy(height) + 3);
+        assert!(node.validate_block(&block).is_ok());
+
+        let mut greedy = block.clone();
+        greedy.transactions[0] = Transaction::reward(block_subsidy(height) + 4, node.user.get_pub_key(), 0, height);
+        let greedy = tip_block(&node, greedy.transactions, height);
+        assert!(node.validate_block(&greedy).is_err());
+
+        let value = node.wallet.value;
+        assert!(node.add_block(block));
+        assert_eq!(node.wallet.value, value + block_subsidy(height));
+    }
+
+    #[test]
+    fn pruned_node_files_stay_small(){
+        let dir = std::env::temp_dir().join(format!("coin_net_node_pruned_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("node.json");
+        let mut node = test_node();
+        let params = node.params();
+        node.store = BlockStore::memory(params.magic).with_max_segment_size(2048);
+        node.store.append(&params.genesis_block()).unwrap();
+        node.prune = Some(PruneTarget::Blocks(0));
+        //paying someone else keeps the wallet from growing along with the chain
+        let payee = User::new().get_pub_key();
+        let mine = |node: &mut Node, count: usize| {
+            for _ in 0..count{
+                let height = node.height + 1;
+                let coinbase = Transaction::reward(10, payee.clone(), 0, height);
+                assert!(node.add_block(tip_block(node, vec![coinbase], height)));
+            }
+        };
+
+        //nothing left in the node file grows with the chain
+        mine(&mut node, MIN_BLOCKS_TO_KEEP + 20);
+        node.store(&path).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < 1024);
+        let store_size = node.store.size();
+        assert!(node.pruned_height() > 0);
+        assert!(!node.store.has_undo(&node.headers()[1].hash()));
+
+        mine(&mut node, 100);
+        node.store(&path).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < 1024);
+        assert!(node.store.size() <= store_size + 2048);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restores_the_chain_from_the_block_store(){
+        let dir = std::env::temp_dir().join(format!("coin_net_node_restore_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("node.json");
+        let mut node = test_node();
+        let mut other = test_node();
+        let chain = mine_blocks(&mut node, 5);
+        node.store(&path).unwrap();
+        let json = std::fs::read_to_string(&path).unwrap();
+        assert!(!json.contains('\n') && !json.contains("headers") && !json.contains("block_tree"));
+
+        //a side branch and blocks connected after the checkpoint are only in the block store
+        let side = mine_blocks(&mut other, 2);
+        for block in side.iter(){
+            assert!(node.add_block(block.clone()));
+        }
+        mine_blocks_from(&mut node, &chain, 3);
+
+        let mut restored = Node::read_file(&path).unwrap();
+        assert_eq!(restored.tip, chain[4].calculate_hash());
+        restored.store = std::mem::take(&mut node.store);
+        restored.restore_chain(false).unwrap();
+        assert_eq!(restored.get_prev_hash(), node.get_prev_hash());
+        let hashes = |node: &Node| node.headers().iter().map(BlockHeader::hash).collect::<Vec<_>>();
+        assert_eq!(hashes(&restored), hashes(&node));
+        assert_eq!((restored.height, restored.bits), (node.height, node.bits));
+        assert_eq!(restored.tip_work(), node.tip_work());
+        assert!(restored.block_tree.contains(&side[1].calculate_hash()));
+        assert_eq!(restored.utxos.size(), node.utxos.size());
+        assert_eq!(restored.balance(), node.balance());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restores_utxos_flushed_past_the_node_file(){
+        for pruned in [false, true]{
+            let dir = std::env::temp_dir().join(format!("coin_net_node_crash_{}_{}", pruned, std::process::id()));
+            let path = dir.join("node.json");
+            let mut node = test_node();
+            let params = node.params();
+            node.utxos = UtxoStore::create(dir.join("chainstate")).unwrap();
+            if pruned{
+                node.store = BlockStore::memory(params.magic).with_max_segment_size(2048);
+                node.store.append(&params.genesis_block()).unwrap();
+                node.prune = Some(PruneTarget::Blocks(0));
+            }
+            let chain = mine_blocks(&mut node, MIN_BLOCKS_TO_KEEP + 20);
+            node.store(&path).unwrap();
+            assert_eq!(node.pruned_height() > 0, pruned);
+
+            //a crash after the outputs of a side branch and then of the longer chain were flushed but before
+            //the node file was written again
+            let mut other = test_node();
+            other.store = BlockStore::memory(params.magic);
+            other.store.append(&params.genesis_block()).unwrap();
+            for block in chain[..chain.len() - 2].iter(){
+                assert!(other.add_block(block.clone()));
+            }
+            let side = mine_blocks_from(&mut other, &chain[..chain.len() - 2], 3);
+            for block in side[chain.len() - 2..].iter(){
+                assert!(node.add_block(block.clone()));
+            }
+            assert_eq!(node.get_prev_hash(), side.last().unwrap().calculate_hash());
+            node.utxos.flush(&node.get_prev_hash()).unwrap();
+            mine_blocks_from(&mut node, &side, 2);
+            node.utxos.flush(&node.get_prev_hash()).unwrap();
+
+            let mut restored = Node::read_file(&path).unwrap();
+            assert_eq!(restored.tip, chain.last().unwrap().calculate_hash());
+            restored.store = std::mem::take(&mut node.store);
+            restored.utxos = UtxoStore::open(dir.join("chainstate")).unwrap();
+            assert_ne!(restored.utxos.best_block(), Some(restored.tip));
+            restored.restore_chain(false).unwrap();
+            assert_eq!(restored.get_prev_hash(), node.get_prev_hash());
+            assert_eq!(restored.utxos.size(), node.utxos.size());
+            assert_eq!(restored.balance(), node.balance());
+            assert_eq!(restored.pruned_height(), node.pruned_height());
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn keeps_blocks_the_utxo_set_could_not_read(){
+        let dir = std::env::temp_dir().join(format!("coin_net_node_utxos_{}", std::process::id()));
+        let mut node = test_node();
+        node.utxos = UtxoStore::create(&dir).unwrap();
+        let mut chain = mine_to_maturity(&mut node);
+        node.utxos.flush(&node.get_prev_hash()).unwrap();
+        assert!(node.new_transaction(spend(&node, &chain[0].transactions[0], 7)).is_ok());
+        let block = solved(node.get_next_block().unwrap());
+        let hash = block.calculate_hash();
+
+        let coins = dir.join("coins.dat");
+        let data = std::fs::read(&coins).unwrap();
+        std::fs::write(&coins, []).unwrap();
+        let height = node.height;
+        assert!(!node.add_block(block.clone()));
+        assert_eq!(node.height, height);
+        assert!(node.block_tree.contains(&hash));
+        assert!(node.store.contains(&hash));
+
+        //once the outputs can be read again the block is connected along with its child
+        std::fs::write(&coins, data).unwrap();
+        chain.push(block);
+        mine_blocks_from(&mut node, &chain, 1);
+        assert_eq!(node.height, height + 2);
+        assert!(node.is_active(&hash, height + 1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn spend(node: &Node, tx: &Transaction, value: usize) -> Transaction{
+        let inputs = vec![((tx.txid(), 0), tx.outputs[0].clone())];
+        Transaction::new(0, node.user.clone(), inputs, vec![(hex::encode(node.user.get_pub_key()), value)])
+    }
+
+    #[test]
+    fn rejects_immature_coinbase_spend(){
+        let mut node = test_node();
+        let maturity = node.params().coinbase_maturity;
+        let blocks = mine_blocks(&mut node, maturity - 1);
+        let tx = spend(&node, &blocks[0].transactions[0], 9);
+        assert!(node.validate_transaction(&tx).is_err());
+        assert!(node.new_transaction(tx.clone()).is_err());
+        assert_eq!(node.balance(), (0, block_subsidy(1) * (maturity - 1)));
+        assert!(node.get_inputs(1).is_none());
+
+        let coinbase = Transaction::reward(block_subsidy(maturity) + 1, node.user.get_pub_key(), 0, maturity);
+        let block = tip_block(&node, vec![coinbase, tx.clone()], maturity);
+        assert!(node.validate_block(&block).is_err());
+
+        mine_blocks_from(&mut node, &blocks, 1);
+        assert_eq!(node.balance().0, block_subsidy(1));
+        assert!(node.validate_transaction(&tx).is_ok());
+    }
+
+    #[test]
+    fn rejects_double_spend_in_block(){
+        let mut node = test_node();
+        let blocks = mine_to_maturity(&mut node);
+        let height = node.height + 1;
+        let first = spend(&node, &blocks[0].transactions[0], 9);
+        let second = spend(&node, &blocks[0].transactions[0], 8);
+        assert!(node.validate_transaction(&first).is_ok());
+        assert!(node.validate_transaction(&second).is_ok());
+
+        let coinbase = Transaction::reward(block_subsidy(height), node.user.get_pub_key(), 0, height);
+        let block = tip_block(&node, vec![coinbase, first, second], height);
+        assert!(node.validate_block(&block).is_err());
+        assert!(!node.add_block(block.clone()));
+        assert_eq!(node.height, height - 1);
+        //so it is not read back and connected again on every load
+        assert!(!node.store.contains(&block.calculate_hash()));
+    }
+
+    #[test]
+    fn mempool_rejects_malformed_transactions(){
+        let mut node = test_node();
+        let blocks = mine_to_maturity(&mut node);
+        let mut miscounted = spend(&node, &blocks[0].transactions[0], 9);
+        miscounted.output_count = 5;
+        let error = node.new_transaction(miscounted).unwrap_err();
+        assert!(error.to_string().contains("counts do not match"));
+        let error = node.new_transaction(Transaction::unsigned(0, &[], vec![])).unwrap_err();
+        assert!(error.to_string().contains("coinbase"));
+        let coinbase = Transaction::reward(1, node.user.get_pub_key(), 0, node.height + 1);
+        assert!(node.new_transaction(coinbase).is_err());
+        assert_eq!(node.get_mempool_size(), 0);
+
+        //so the template the miner works on still passes Block::check
+        let block = solved(node.get_next_block().unwrap());
+        assert!(block.check(&node.params()).is_ok());
+        assert!(node.add_block(block));
+    }
+
+    #[test]
+    fn rejects_overflowing_outputs(){
+        let mut node = test_node();
+        let blocks = mine_to_maturity(&mut node);
+        let coinbase = &blocks[0].transactions[0];
+        let inputs = vec![((coinbase.txid(), 0), coinbase.outputs[0].clone())];
+        let paying = |value| {
+            let mut output = coinbase.outputs[0].clone();
+            output.value = value;
+            output
+        };
+        let overflowing = Transaction::with_outputs(0, node.user.clone(), inputs.clone(), vec![paying(usize::MAX), paying(2)]);
+        assert!(overflowing.output_value().is_err());
+        assert!(node.utxos.get_fee(overflowing.clone()).is_err());
+        assert!(node.new_transaction(overflowing).is_err());
+
+        let out_of_range = Transaction::with_outputs(0, node.user.clone(), inputs, vec![paying(MAX_MONEY + 1)]);
+        let error = node.new_transaction(out_of_range).unwrap_err();
+        assert!(error.to_string().contains("out of range"));
+        assert_eq!(node.get_mempool_size(), 0);
+    }
+
+    #[test]
+    fn accepts_chained_spends_in_block(){
+        let mut node = test_node();
+        let blocks = mine_to_maturity(&mut node);
+        let height = node.height + 1;
+        let first = spend(&node, &blocks[0].transactions[0], 9);
+        let second = spend(&node, &first, 7);
+        assert!(node.validate_transaction(&second).is_err());
+
+        let coinbase = Transaction::reward(block_subsidy(height) + 3, node.user.get_pub_key(), 0, height);
+        let chained = tip_block(&node, vec![coinbase.clone(), first.clone(), second.clone()], height);
+        let reversed = tip_block(&node, vec![coinbase, second, first], height);
+        assert!(node.validate_block(&reversed).is_err());
+        let value = node.wallet.value;
+        assert!(node.add_block(chained));
+        assert_eq!(node.wallet.value, value + block_subsidy(height));
+    }
+
+    #[test]
+    fn template_collects_fees_of_chained_spends(){
+        let mut node = test_node();
+        let blocks = mine_to_maturity(&mut node);
+        let height = node.height + 1;
+        let first = spend(&node, &blocks[0].transactions[0], 6);
+        let second = spend(&node, &first, 5);
+        assert!(node.new_transaction(first).is_ok());
+        //the mempool only checks against confirmed outputs, so the child goes in directly
+        assert!(node.mempool.add(second, 1));
+
+        let block = solved(node.get_next_block().unwrap());
+        assert_eq!(block.transactions.len(), 3);
+        assert_eq!(block.transactions[0].output_value().unwrap(), block_subsidy(height) + 5);
+        assert!(node.add_block(block));
+    }
+
+    #[test]
+    fn template_skips_conflicting_transactions(){
+        let mut node = test_node();
+        let blocks = mine_to_maturity(&mut node);
+        assert!(node.new_transaction(spend(&node, &blocks[0].transactions[0], 9)).is_ok());
+        assert!(node.new_transaction(spend(&node, &blocks[0].transactions[0], 8)).is_ok());
+
+        let block = solved(node.get_next_block().unwrap());
+        assert_eq!(block.transactions.len(), 2);
+        assert!(node.add_block(block));
+    }
+
+    #[test]
+    fn malleated_copies_share_txid(){
+        let mut node = test_node();
+        let blocks = mine_to_maturity(&mut node);
+        let funding = &blocks[0].transactions[0];
+        let tx = spend(&node, funding, 9);
+        assert!(node.new_transaction(tx.clone()).is_ok());
+
+        //a different valid unlocking script changes the full hash but not the txid
+        let mut malleated = tx.clone();
+        malleated.sign_p2pkh_input(&node.user, 0, &funding.outputs[0], SIGHASH_ALL | SIGHASH_ANYONECANPAY).unwrap();
+        assert_ne!(malleated.full_hash(), tx.full_hash());
+        assert_eq!(malleated.txid(), tx.txid());
+        assert!(node.new_transaction(malleated.clone()).is_err());
+        assert_eq!(node.get_mempool_size(), 1);
+
+        //once the copy is mined the original leaves the mempool and children of either still spend it
+        let height = node.height + 1;
+        let coinbase = Transaction::reward(block_subsidy(height), node.user.get_pub_key(), 0, height);
+        assert!(node.add_block(tip_block(&node, vec![coinbase, malleated], height)));
+        assert_eq!(node.get_mempool_size(), 0);
+        assert!(node.validate_transaction(&spend(&node, &tx, 8)).is_ok());
+    }
+
+    #[test]
+    fn rejects_oversized_block(){
+        let node = test_node();
+        let mut coinbase = Transaction::reward(0, node.user.get_pub_key(), 0, 1);
+        let output = coinbase.outputs[0].clone();
+        let output_size = output.encode().len();
+        coinbase.outputs = vec![output; MAX_BLOCK_SIZE / output_size + 1];
+        coinbase.output_count = coinbase.outputs.len();
+        let block = Block::new(vec![coinbase], node.get_prev_hash(), node.bits, 0, 1);
+        let error = block.check(&node.params()).unwrap_err();
+        assert!(error.to_string().contains("exceeds the limit"));
+    }
+
+    #[test]
+    fn template_fits_size_limit(){
+        let mut node = test_node();
+        let maturity = node.params().coinbase_maturity;
+        let blocks = mine_blocks(&mut node, maturity + 2);
+        let txs: Vec<Transaction> = (0..3)
+            .map(|index| spend(&node, &blocks[index].transactions[0], 9 - index))
+            .collect();
+        for tx in txs.iter(){
+            assert!(node.new_transaction(tx.clone()).is_ok());
+        }
+
+        //the lowest fee transaction is left out once the space runs out
+        let max_size = txs[1].size() + txs[2].size();
+        let chosen = node.get_next_transactions(max_size).unwrap();
+        assert_eq!(chosen.len(), 2);
+        assert!(!chosen.iter().any(|(tx, _)| *tx == txs[0]));
+        assert_eq!(node.get_next_transactions(MAX_BLOCK_SIZE).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn proves_transactions_in_active_chain(){
+        let mut node = test_node();
+        let blocks = mine_to_maturity(&mut node);
+        assert!(node.new_transaction(spend(&node, &blocks[0].transactions[0], 9)).is_ok());
+        let block = solved(node.get_next_block().unwrap());
+        assert!(node.add_block(block.clone()));
+
+        for tx in block.transactions.iter(){
+            let (header, full_hash, proof) = node.get_merkle_proof(&tx.txid()).unwrap();
+            assert_eq!(header.hash(), block.calculate_hash());
+            assert_eq!(full_hash, tx.full_hash());
+            assert!(proof.verify(&full_hash, &header.merkle_root));
+        }
+        //the leaf is not the txid once a transaction is signed
+        let signed = &block.transactions[1];
+        let (header, _, proof) = node.get_merkle_proof(&signed.txid()).unwrap();
+        assert!(!proof.verify(&signed.txid(), &header.merkle_root));
+        assert!(node.get_merkle_proof(&sha256("missing")).is_none());
+    }
+
+    #[test]
+    fn rejects_duplicate_transactions(){
+        let mut node = test_node();
+        let blocks = mine_to_maturity(&mut node);
+        let tx = spend(&node, &blocks[0].transactions[0], 9);
+        let height = node.height + 1;
+        let coinbase = Transaction::reward(block_subsidy(height), node.user.get_pub_key(), 0, height);
+        let block = tip_block(&node, vec![coinbase, tx.clone(), tx], height);
+        let error = block.check(&node.params()).unwrap_err();
+        assert!(error.to_string().contains("duplicate"));
+    }
+
+    #[test]
+    fn reorganizes_to_most_work(){
+        let mut node = test_node();
+        let mut other = test_node();
+        let mut ours = mine_blocks(&mut node, 2);
+        let theirs = mine_blocks(&mut other, 3);
+        assert_eq!(node.wallet.value, 20);
+
+        //equal work stays on our chain
+        assert!(node.add_block(theirs[0].clone()));
+        assert!(node.add_block(theirs[1].clone()));
+        assert_eq!(node.get_prev_hash(), ours[1].calculate_hash());
+
+        assert!(node.add_block(theirs[2].clone()));
+        assert_eq!(node.height, 3);
+        assert_eq!(node.get_prev_hash(), theirs[2].calculate_hash());
+        assert_eq!(node.wallet.value, 0);
+        assert_eq!(node.utxos.size(), 3);
+
+        //switching back restores our outputs
+        for height in 3..=4{
+            let block = next_block(&node, ours.last(), height);
+            assert!(node.add_block(block.clone()));
+            ours.push(block);
+        }
+        assert_eq!(node.height, 4);
+        assert_eq!(node.get_prev_hash(), ours[3].calculate_hash());
+        assert_eq!(node.wallet.value, 40);
+        assert_eq!(node.utxos.size(), 4);
+    }
+
+    #[test]
+    fn rejects_unknown_parent(){
+        let mut node = test_node();
+        let mut other = test_node();
+        let theirs = mine_blocks(&mut other, 2);
+        assert!(!node.add_block(theirs[1].clone()));
+        assert_eq!(node.height, 0);
+    }
+
+    #[test]
+    fn connects_orphans_when_parent_arrives(){
+        let mut node = test_node();
+        let mut other = test_node();
+        let theirs = mine_blocks(&mut other, 3);
+
+        for block in theirs[1..].iter().rev(){
+            assert!(!node.has_parent(block));
+            assert!(node.add_orphan(block.clone()));
+        }
+        assert!(!node.add_orphan(theirs[2].clone()));
+
+        assert!(node.add_block(theirs[0].clone()));
+        assert_eq!(node.height, 3);
+        assert_eq!(node.orphans.size(), 0);
+    }
+
+    #[test]
+    fn retargets_fast_blocks(){
+        let mut node = Node::new(Network::Test);
+        let pow_limit_bits = node.params().pow_limit_bits;
+        let blocks = mine_blocks(&mut node, 2 * RETARGET_INTERVAL - 1);
+        assert_eq!(node.next_retarget(), 2 * RETARGET_INTERVAL);
+
+        //every block shares a timestamp so the window was far too fast
+        assert_eq!(node.bits, retarget(pow_limit_bits, 0, pow_limit_bits));
+        assert!(node.difficulty() > 1.0);
+        let mut block = next_block(&node, blocks.last(), 2 * RETARGET_INTERVAL);
+        assert!(node.validate_block(&block).is_ok());
+        block.block_header.bits = pow_limit_bits;
+        assert!(!node.add_block(solved(block)));
+    }
+
+    #[test]
+    fn rejects_bad_timestamps(){
+        let mut node = test_node();
+        let blocks = mine_blocks(&mut node, MEDIAN_TIME_SPAN);
+        let median_time_past = node.median_time_past(&node.get_prev_hash());
+        assert_eq!(median_time_past, blocks[MEDIAN_TIME_SPAN / 2].block_header.timestamp);
+
+        let mut block = next_block(&node, blocks.last(), MEDIAN_TIME_SPAN + 1);
+        block.block_header.timestamp = median_time_past;
+        let error = node.accept_block(&solved(block.clone())).unwrap_err();
+        assert!(error.to_string().contains("median time past"));
+
+        block.block_header.timestamp = node.adjusted_time() + MAX_FUTURE_BLOCK_TIME + 60;
+        let error = node.accept_block(&solved(block.clone())).unwrap_err();
+        assert!(error.to_string().contains("ahead of network time"));
+
+        //peers running ahead move network time forward
+        for port in 1..=3{
+            node.add_time_sample(SocketAddr::from(([127, 0, 0, 1], port)), get_timestamp() + 120);
+        }
+        assert!(node.accept_block(&solved(block.clone())).is_ok());
+        assert!(node.add_block(solved(block)));
+    }
+
+    #[test]
+    fn regtest_never_retargets(){
+        let mut node = test_node();
+        mine_blocks(&mut node, 2 * RETARGET_INTERVAL);
+        assert_eq!(node.bits, POW_LIMIT_BITS);
+    }
+
+    #[test]
+    fn networks_do_not_share_blocks(){
+        let mut node = test_node();
+        let mut other = Node::new(Network::Test);
+        let theirs = mine_blocks(&mut other, 1);
+        assert_ne!(node.get_prev_hash(), other.params().genesis_hash());
+        assert!(!node.add_block(theirs[0].clone()));
+    }
+
+    #[test]
+    fn locator_finds_fork(){
+        let mut node = test_node();
+        let mut other = test_node();
+        let ours = mine_blocks(&mut node, 2);
+        for block in ours.iter(){
+            assert!(other.add_block(block.clone()));
+        }
+        let block = next_block(&other, ours.last(), 3);
+        assert!(other.add_block(block));
+
+        let locator = other.get_locator();
+        assert_eq!(locator.len(), 4);
+        assert_eq!(node.find_fork_height(&locator), Some(2));
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    miner::{Block, HashDigest},
+    transactions::Transaction,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Network{
+    #[default]
+    Main,
+    Test,
+    Regtest,
+}
+
+impl Network{
+    //no argument selects main
+    pub fn from_arg(arg: Option<&str>) -> Result<Self>{
+        match arg{
+            None | Some("main") => Ok(Network::Main),
+            Some("test") => Ok(Network::Test),
+            Some("regtest") => Ok(Network::Regtest),
+            Some(arg) => Err(anyhow!("Invalid network '{}' expected 'main', 'test' or 'regtest'", arg)),
+        }
+    }
+
+    pub fn params(&self) -> ChainParams{
+        match self{
+            Network::Main => ChainParams {
+                network: *self,
+                magic: *b"CNmn",
+                port: 8333,
+                ui_port: 3000,
+                data_dir: "configs",
+                pow_limit_bits: 0x1f00ffff,
+                genesis_bits: 0x1e010000,
+                genesis_timestamp: 1767225600,
+                genesis_nonce: 8_965_051,
+                genesis_hash: "0000000bd12975c854bb356edb70f1169eed5d9219f4670abfed311561c5fe21",
+                retargeting: true,
+                coinbase_maturity: 100
+            },
+            Network::Test => ChainParams {
+                network: *self,
+                magic: *b"CNts",
+                port: 18333,
+                ui_port: 3001,
+                data_dir: "configs/test",
+                pow_limit_bits: 0x2000ffff,
+                genesis_bits: 0x2000ffff,
+                genesis_timestamp: 1767225600,
+                genesis_nonce: 936,
+                genesis_hash: "009513fd594f9ca943d5354d4c2eac45962d7580213598552f4b83026a7c370c",
+                retargeting: true,
+                coinbase_maturity: 100
+            },
+            Network::Regtest => ChainParams {
+                network: *self,
+                magic: *b"CNrg",
+                port: 18444,
+                ui_port: 3002,
+                data_dir: "configs/regtest",
+                pow_limit_bits: 0x207fffff,
+                genesis_bits: 0x207fffff,
+                genesis_timestamp: 1767225600,
+                genesis_nonce: 0,
+                genesis_hash: "1e8f302e78586da173817ffba23f7dbf84f4c9d67a783169ce66d224aba01407",
+                retargeting: false,
+                coinbase_maturity: 10
+            },
+        }
+    }
+}
+
+//everything that differs between networks
+#[derive(Clone, Copy, Debug)]
+pub struct ChainParams{
+    pub network: Network,
+    //sent before every message so networks can not talk to each other
+    pub magic: [u8; 4],
+    pub port: u16,
+    pub ui_port: u16,
+    pub data_dir: &'static str,
+    //easiest target a block may have
+    pub pow_limit_bits: u32,
+    pub genesis_bits: u32,
+    genesis_timestamp: usize,
+    //found once by mining the genesis block, the hash is what nodes check each other's chains against
+    genesis_nonce: u128,
+    genesis_hash: &'static str,
+    pub retargeting: bool,
+    //confirmations a coinbase output needs before it can be spent
+    pub coinbase_maturity: usize,
+}
+
+impl ChainParams{
+    pub fn net_addr(&self) -> String{
+        format!("0.0.0.0:{}", self.port)
+    }
+
+    pub fn node_file(&self) -> String{
+        format!("{}/node.json", self.data_dir)
+    }
+
+    //segment files and index of the block store
+    pub fn blocks_dir(&self) -> String{
+        format!("{}/blocks", self.data_dir)
+    }
+
+    //utxo set of the active chain
+    pub fn chainstate_dir(&self) -> String{
+        format!("{}/chainstate", self.data_dir)
+    }
+
+    pub fn bootstrap_file(&self) -> String{
+        format!("{}/Bootstrap.json", self.data_dir)
+    }
+
+    //the genesis block is trusted as is, its coinbase pays to a hash nobody holds the key for
+    pub fn genesis_block(&self) -> Block{
+        let mut coinbase = Transaction::reward(0, Vec::new(), 0, 0);
+        coinbase.timestamp = self.genesis_timestamp;
+        let mut block = Block::new(vec![coinbase], [0u8; 32], self.genesis_bits, 0, 0);
+        block.block_header.timestamp = self.genesis_timestamp;
+        block.update_nonce(self.genesis_nonce.to_le_bytes());
+        block
+    }
+
+    pub fn genesis_hash(&self) -> HashDigest{
+        hex::decode(self.genesis_hash)
+            .ok()
+            .and_then(|hash| hash.try_into().ok())
+            .expect("genesis hash is 32 bytes of hex")
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn genesis_blocks_match_their_hashes(){
+        for network in [Network::Main, Network::Test, Network::Regtest]{
+            let params = network.params();
+            let genesis = params.genesis_block();
+            assert_eq!(genesis.calculate_hash(), params.genesis_hash(), "{:?}", network);
+            assert!(genesis.check(&params).is_ok(), "{:?}", network);
+        }
+    }
+}
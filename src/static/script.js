@@ -5,6 +5,7 @@ const AddressBook = document.getElementById("address-list")
 const RecipientList = document.getElementById("recipient-list")
 const submit = document.getElementById('submit')
 const fee = document.getElementById('fee')
+const data = document.getElementById('data')
 
 let address_book = new Map(); //label : address
 let recipients = []; //label: amount
@@ -12,6 +13,7 @@ let recipients = []; //label: amount
 function clear_transaction(){
     RecipientList.innerHTML = ''
     fee.value = ''
+    data.value = ''
 }
 
 //Address Book Handling -------------------------------------------------------
@@ -187,7 +189,8 @@ async function  updateStatus() {
         const data = await response.json();
         document.getElementById("height").textContent = data.height
         document.getElementById("mempool").textContent = data.mempool_size
-        document.getElementById("difficulty").textContent = data.difficulty
+        document.getElementById("difficulty").textContent = data.difficulty.toFixed(2)
+        document.getElementById("next-retarget").textContent = data.next_retarget
     } catch(error) {
         console.error("Failed to fetch node status", error);
     }
@@ -195,7 +198,9 @@ async function  updateStatus() {
         const response = await fetch('/api/user_status');
         const data = await response.json();
         document.getElementById('user-address').textContent = data.pk
+        document.getElementById('schnorr-address').textContent = data.schnorr_address ?? '-'
         document.getElementById('funds').textContent = data.amount
+        document.getElementById('immature').textContent = data.immature
     } catch(error) {
         console.error("Failed to fetch user status")
     }
@@ -207,7 +212,8 @@ submit.addEventListener('click',  async () =>{
     const transaction = {
         to: recipients.map(item => address_book.get(item[0])),
         to_amount: recipients.map(item => item[1]),
-        fee: feeValue
+        fee: feeValue,
+        data: data.value
     };
 
     try{
@@ -0,0 +1,879 @@
+use std::{
+    collections::HashMap, fs::{self, File, OpenOptions}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, str::FromStr,
+};
+
+use anyhow::{Result, anyhow, bail};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encoding::{Decode, Encode, Reader},
+    miner::{Block, BlockHeader, HashDigest},
+    transactions::BlockUndo,
+};
+
+//a new segment is started once the next record would take the current one past this size
+pub const MAX_SEGMENT_SIZE: usize = 16 * 1024 * 1024;
+//each record is the network magic and the little endian u32 length of the encoded block or undo data that follows
+const RECORD_HEADER_SIZE: usize = 8;
+const INDEX_FILE: &str = "index.dat";
+
+fn segment_name(segment: u32) -> String{
+    format!("blk{:05}.dat", segment)
+}
+
+fn undo_name(segment: u32) -> String{
+    format!("rev{:05}.dat", segment)
+}
+
+fn segment_number(name: &str) -> Option<u32>{
+    name.strip_prefix("blk")?.strip_suffix(".dat")?.parse().ok()
+}
+
+//a pruned node still keeps this many blocks below its tip so it can follow reorganizations
+pub const MIN_BLOCKS_TO_KEEP: usize = 288;
+
+//how much block data a pruned node keeps, either the last number of blocks or a size in bytes,
+//whole segments are removed so a little more than the target is usually kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PruneTarget{
+    Blocks(usize),
+    Bytes(usize),
+}
+
+//a number of blocks like "1000" or a size like "500MB" or "2GB"
+impl FromStr for PruneTarget{
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self>{
+        let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()){
+            Some(split) => s.split_at(split),
+            None => (s, "")
+        };
+        let number: usize = number.parse().map_err(|_| anyhow!("invalid prune target '{}'", s))?;
+        let unit_size: usize = match unit.to_ascii_uppercase().as_str(){
+            "" => return Ok(Self::Blocks(number)),
+            "MB" => 1 << 20,
+            "GB" => 1 << 30,
+            _ => bail!("invalid prune target '{}', expected blocks or a size in MB or GB", s)
+        };
+        match number.checked_mul(unit_size){
+            Some(bytes) => Ok(Self::Bytes(bytes)),
+            None => bail!("prune target '{}' is too large", s)
+        }
+    }
+}
+
+//a segment with the bytes and highest block stored in it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentInfo{
+    pub segment: u32,
+    pub size: usize,
+    pub max_height: usize,
+}
+
+//where a record sits in the block or undo file of a segment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockLocation{
+    pub segment: u32,
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl BlockLocation{
+    fn end(&self) -> usize{
+        self.offset + self.len
+    }
+}
+
+//fixed size record of the index file, appended after its block is written and again after its undo data is,
+//the last entry for a hash is the one that counts
+//
+//  hash          32 bytes
+//  header        108 bytes
+//  segment       4 bytes
+//  offset        8 bytes
+//  len           8 bytes     0 once the segment is pruned
+//  undo offset   8 bytes     in the undo file of the same segment
+//  undo len      8 bytes     0 until the block is first connected
+#[derive(Clone, Debug)]
+struct IndexEntry{
+    hash: HashDigest,
+    header: BlockHeader,
+    //None once the block is pruned, its header stays for the block tree
+    location: Option<BlockLocation>,
+    undo: Option<BlockLocation>,
+}
+
+const INDEX_ENTRY_SIZE: usize = 176;
+
+impl IndexEntry{
+    //bytes taken by the block and undo records, counting record headers
+    fn size(&self) -> usize{
+        [self.location, self.undo].iter()
+            .flatten()
+            .map(|location| location.len + RECORD_HEADER_SIZE)
+            .sum()
+    }
+}
+
+impl Encode for IndexEntry{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        self.hash.encode_to(buf);
+        self.header.encode_to(buf);
+        let segment = self.location.or(self.undo).map_or(0, |location| location.segment);
+        let (offset, len) = self.location.map_or((0, 0), |location| (location.offset, location.len));
+        let (undo_offset, undo_len) = self.undo.map_or((0, 0), |undo| (undo.offset, undo.len));
+        segment.encode_to(buf);
+        offset.encode_to(buf);
+        len.encode_to(buf);
+        undo_offset.encode_to(buf);
+        undo_len.encode_to(buf);
+    }
+}
+
+impl Decode for IndexEntry{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        let hash = Decode::decode_from(reader)?;
+        let header = Decode::decode_from(reader)?;
+        let segment = Decode::decode_from(reader)?;
+        let offset = Decode::decode_from(reader)?;
+        let len = usize::decode_from(reader)?;
+        let undo_offset = usize::decode_from(reader)?;
+        let undo_len = usize::decode_from(reader)?;
+        let location = Some(BlockLocation { segment, offset, len }).filter(|location| location.len > 0);
+        let undo = Some(BlockLocation { segment, offset: undo_offset, len: undo_len }).filter(|undo| undo.len > 0);
+        Ok(Self { hash, header, location, undo })
+    }
+}
+
+//append only block storage, blocks are written once to numbered segment files and read back on demand
+//through an index by hash and height, without a directory everything stays in memory, the undo data
+//of a block goes to the undo file numbered like its segment once the block is connected, the index
+//keeps the header of every block it was given so the block tree can be rebuilt from it
+#[derive(Debug, Default)]
+pub struct BlockStore{
+    dir: Option<PathBuf>,
+    magic: [u8; 4],
+    //segment and undo file contents when there is no directory
+    memory: Vec<Vec<u8>>,
+    undo_memory: Vec<Vec<u8>>,
+    entries: HashMap<HashDigest, IndexEntry>,
+    heights: HashMap<usize, Vec<HashDigest>>,
+    //hashes in the order they were appended
+    order: Vec<HashDigest>,
+    segment: u32,
+    segment_size: usize,
+    max_segment_size: usize,
+}
+
+impl BlockStore{
+    pub fn memory(magic: [u8; 4]) -> Self{
+        Self {
+            magic,
+            max_segment_size: MAX_SEGMENT_SIZE,
+            ..Default::default()
+        }
+    }
+
+    //smaller segments can be pruned sooner
+    pub fn with_max_segment_size(mut self, max_segment_size: usize) -> Self{
+        self.max_segment_size = max_segment_size;
+        self
+    }
+
+    //opens the store in dir, creating it if needed, and loads its index
+    pub fn open<P: AsRef<Path>>(dir: P, magic: [u8; 4]) -> Result<Self>{
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut store = Self {
+            dir: Some(dir.clone()),
+            magic,
+            max_segment_size: MAX_SEGMENT_SIZE,
+            ..Default::default()
+        };
+        let index_path = dir.join(INDEX_FILE);
+        let mut entries = Vec::new();
+        if index_path.exists(){
+            let data = fs::read(&index_path)?;
+            //an entry cut short by a crash refers to nothing that has to be kept
+            for chunk in data.chunks_exact(INDEX_ENTRY_SIZE){
+                entries.push(IndexEntry::decode(chunk)?);
+            }
+        }
+        //pruning removes the oldest segments so the numbers need not start at zero
+        for file in fs::read_dir(&dir)?{
+            if let Some(segment) = file?.file_name().to_str().and_then(segment_number){
+                store.segment = store.segment.max(segment);
+            }
+        }
+        let mut segment_sizes = Vec::new();
+        let mut undo_sizes = Vec::new();
+        for segment in 0..=store.segment{
+            segment_sizes.push(file_len(&dir.join(segment_name(segment)))?);
+            undo_sizes.push(file_len(&dir.join(undo_name(segment)))?);
+        }
+
+        //records are written before the entries pointing at them so an entry reaching past the end of a file was
+        //never completed, anything indexed after it is dropped as well
+        let complete = |entry: &IndexEntry| {
+            entry.location.is_none_or(|location| location.end() <= segment_sizes.get(location.segment as usize).copied().unwrap_or(0))
+                && entry.undo.is_none_or(|undo| undo.end() <= undo_sizes.get(undo.segment as usize).copied().unwrap_or(0))
+        };
+        let valid = entries.iter()
+            .position(|entry| !complete(entry))
+            .unwrap_or(entries.len());
+        if valid * INDEX_ENTRY_SIZE != file_len(&index_path)?{
+            warn!("Dropping {} incomplete block store index entries", entries.len() - valid);
+            OpenOptions::new().create(true).write(true).truncate(false).open(&index_path)?.set_len((valid * INDEX_ENTRY_SIZE) as u64)?;
+            entries.truncate(valid);
+        }
+        for entry in entries{
+            store.insert(entry);
+        }
+
+        //a record written without its index entry is cut off so the next block starts where the index expects,
+        //undo records are located by the size of their file so one left over in there does no harm
+        let indexed_end = store.entries.values()
+            .filter_map(|entry| entry.location)
+            .filter(|location| location.segment == store.segment)
+            .map(|location| location.end())
+            .max()
+            .unwrap_or(0);
+        if segment_sizes[store.segment as usize] > indexed_end{
+            warn!("Truncating unindexed data at the end of {}", segment_name(store.segment));
+            OpenOptions::new().write(true).open(dir.join(segment_name(store.segment)))?.set_len(indexed_end as u64)?;
+        }
+        store.segment_size = indexed_end;
+        Ok(store)
+    }
+
+    //opens an empty store in dir, removing any blocks stored there before
+    pub fn create<P: AsRef<Path>>(dir: P, magic: [u8; 4]) -> Result<Self>{
+        if dir.as_ref().exists(){
+            fs::remove_dir_all(&dir)?;
+        }
+        Self::open(dir, magic)
+    }
+
+    fn insert(&mut self, entry: IndexEntry){
+        let (hash, height) = (entry.hash, entry.header.height);
+        if self.entries.insert(hash, entry).is_none(){
+            self.heights.entry(height).or_default().push(hash);
+            self.order.push(hash);
+        }
+    }
+
+    //whether the block can be read back, a pruned block only has its header
+    pub fn contains(&self, hash: &HashDigest) -> bool{
+        self.location(hash).is_some()
+    }
+
+    pub fn has_undo(&self, hash: &HashDigest) -> bool{
+        self.entries.get(hash).is_some_and(|entry| entry.undo.is_some())
+    }
+
+    //blocks that can be read back
+    pub fn len(&self) -> usize{
+        self.entries.values()
+            .filter(|entry| entry.location.is_some())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+
+    pub fn location(&self, hash: &HashDigest) -> Option<BlockLocation>{
+        self.entries.get(hash).and_then(|entry| entry.location)
+    }
+
+    pub fn header(&self, hash: &HashDigest) -> Option<&BlockHeader>{
+        self.entries.get(hash).map(|entry| &entry.header)
+    }
+
+    //headers of every block ever stored, pruned or not, in the order they were appended
+    pub fn headers(&self) -> impl Iterator<Item = &BlockHeader>{
+        self.order.iter().map(|hash| &self.entries[hash].header)
+    }
+
+    //every block at height whose header is stored, on the active chain or not
+    pub fn hashes_at(&self, height: usize) -> &[HashDigest]{
+        self.heights.get(&height).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    //hashes of all stored headers in the order they were appended
+    pub fn hashes(&self) -> &[HashDigest]{
+        &self.order
+    }
+
+    //bytes of block and undo data stored, counting record headers
+    pub fn size(&self) -> usize{
+        self.entries.values()
+            .map(IndexEntry::size)
+            .sum()
+    }
+
+    //every segment holding blocks, oldest first
+    pub fn segments(&self) -> Vec<SegmentInfo>{
+        let mut segments: HashMap<u32, SegmentInfo> = HashMap::new();
+        for entry in self.entries.values(){
+            let segment = match entry.location{
+                Some(location) => location.segment,
+                None => continue
+            };
+            let info = segments.entry(segment)
+                .or_insert(SegmentInfo { segment, size: 0, max_height: 0 });
+            info.size += entry.size();
+            info.max_height = info.max_height.max(entry.header.height);
+        }
+        let mut segments: Vec<SegmentInfo> = segments.into_values().collect();
+        segments.sort_by_key(|info| info.segment);
+        segments
+    }
+
+    //lowest height to keep blocks from so the store stays within target, never closer than
+    //MIN_BLOCKS_TO_KEEP to tip
+    pub fn prune_height(&self, target: PruneTarget, tip: usize) -> usize{
+        let limit = (tip + 1).saturating_sub(MIN_BLOCKS_TO_KEEP);
+        let height = match target{
+            PruneTarget::Blocks(blocks) => (tip + 1).saturating_sub(blocks),
+            PruneTarget::Bytes(budget) => {
+                let mut size = self.size();
+                let mut height = 0;
+                for info in self.segments().iter().filter(|info| info.segment != self.segment){
+                    if size <= budget{
+                        break
+                    }
+                    size -= info.size;
+                    height = height.max(info.max_height + 1);
+                }
+                height
+            }
+        };
+        height.min(limit)
+    }
+
+    //removes every finished segment whose blocks are all below height along with its undo file, returning how
+    //many were removed, the headers of the removed blocks stay in the index
+    pub fn prune(&mut self, height: usize) -> Result<usize>{
+        let pruned: Vec<u32> = self.segments().iter()
+            .filter(|info| info.segment != self.segment && info.max_height < height)
+            .map(|info| info.segment)
+            .collect();
+        if pruned.is_empty(){
+            return Ok(0)
+        }
+        for entry in self.entries.values_mut(){
+            if entry.location.is_some_and(|location| pruned.contains(&location.segment)){
+                entry.location = None;
+                entry.undo = None;
+            }
+        }
+        //the index stops pointing at the segments before they are deleted
+        self.write_index()?;
+        for segment in pruned.iter(){
+            match &self.dir{
+                Some(dir) => {
+                    fs::remove_file(dir.join(segment_name(*segment)))?;
+                    //a segment whose blocks were never connected has no undo file
+                    let undo = dir.join(undo_name(*segment));
+                    if undo.exists(){
+                        fs::remove_file(undo)?;
+                    }
+                }
+                None => {
+                    self.memory[*segment as usize] = Vec::new();
+                    if let Some(undo) = self.undo_memory.get_mut(*segment as usize){
+                        *undo = Vec::new();
+                    }
+                }
+            }
+        }
+        Ok(pruned.len())
+    }
+
+    //forgets blocks that failed validation so they are not read again, their records stay in the
+    //segments until those are pruned
+    pub fn remove(&mut self, hashes: &[HashDigest]) -> Result<()>{
+        if !hashes.iter().any(|hash| self.contains(hash)){
+            return Ok(())
+        }
+        self.entries.retain(|hash, _| !hashes.contains(hash));
+        self.drop_unindexed();
+        self.write_index()
+    }
+
+    //removes hashes no longer in the index from the height and append order lists
+    fn drop_unindexed(&mut self){
+        self.order.retain(|hash| self.entries.contains_key(hash));
+        for hashes in self.heights.values_mut(){
+            hashes.retain(|hash| self.entries.contains_key(hash));
+        }
+        self.heights.retain(|_, hashes| !hashes.is_empty());
+    }
+
+    //replaces the index file with one entry per stored header
+    fn write_index(&self) -> Result<()>{
+        let dir = match &self.dir{
+            Some(dir) => dir,
+            None => return Ok(())
+        };
+        let mut index = Vec::with_capacity(self.order.len() * INDEX_ENTRY_SIZE);
+        for hash in self.order.iter(){
+            self.entries[hash].encode_to(&mut index);
+        }
+        write_atomic(&dir.join(INDEX_FILE), &index)
+    }
+
+    //data preceded by the network magic and its length
+    fn record(&self, data: Vec<u8>) -> Vec<u8>{
+        let mut record = self.magic.to_vec();
+        (data.len() as u32).encode_to(&mut record);
+        record.extend(data);
+        record
+    }
+
+    //writes block to the end of the current segment, does nothing if it is already stored
+    pub fn append(&mut self, block: &Block) -> Result<BlockLocation>{
+        let hash = block.calculate_hash();
+        if let Some(location) = self.location(&hash){
+            return Ok(location)
+        }
+        let record = self.record(block.encode());
+        if self.segment_size > 0 && self.segment_size + record.len() > self.max_segment_size{
+            self.segment += 1;
+            self.segment_size = 0;
+        }
+        let location = BlockLocation {
+            segment: self.segment,
+            offset: self.segment_size + RECORD_HEADER_SIZE,
+            len: record.len() - RECORD_HEADER_SIZE
+        };
+        let entry = IndexEntry { hash, header: block.block_header.clone(), location: Some(location), undo: None };
+        match &self.dir{
+            Some(dir) => {
+                //the block is on disk before the index points at it
+                append_file(&dir.join(segment_name(self.segment)), &record)?;
+                append_file(&dir.join(INDEX_FILE), &entry.encode())?;
+            }
+            None => {
+                if self.memory.len() <= self.segment as usize{
+                    self.memory.push(Vec::new());
+                }
+                self.memory[self.segment as usize].extend(&record);
+            }
+        }
+        self.segment_size += record.len();
+        self.insert(entry);
+        Ok(location)
+    }
+
+    //writes the undo data of a stored block to the undo file of its segment, does nothing if it is already
+    //stored since connecting a block to the same ancestors always spends the same outputs
+    pub fn put_undo(&mut self, hash: &HashDigest, undo: &BlockUndo) -> Result<()>{
+        let mut entry = match self.entries.get(hash){
+            Some(entry) if entry.undo.is_some() => return Ok(()),
+            Some(entry) if entry.location.is_some() => entry.clone(),
+            _ => bail!("block {} is not stored", hex::encode(hash))
+        };
+        let record = self.record(undo.encode());
+        let segment = entry.location.expect("checked above").segment;
+        let start = match &self.dir{
+            Some(dir) => {
+                let path = dir.join(undo_name(segment));
+                let start = file_len(&path)?;
+                append_file(&path, &record)?;
+                start
+            }
+            None => {
+                if self.undo_memory.len() <= segment as usize{
+                    self.undo_memory.resize(segment as usize + 1, Vec::new());
+                }
+                let data = &mut self.undo_memory[segment as usize];
+                data.extend(&record);
+                data.len() - record.len()
+            }
+        };
+        entry.undo = Some(BlockLocation { segment, offset: start + RECORD_HEADER_SIZE, len: record.len() - RECORD_HEADER_SIZE });
+        if let Some(dir) = &self.dir{
+            //the undo data is on disk before the index points at it
+            append_file(&dir.join(INDEX_FILE), &entry.encode())?;
+        }
+        self.entries.insert(*hash, entry);
+        Ok(())
+    }
+
+    //reads a block back from its segment, None if it was never stored
+    pub fn get(&self, hash: &HashDigest) -> Result<Option<Block>>{
+        let location = match self.location(hash){
+            Some(location) => location,
+            None => return Ok(None)
+        };
+        let block = Block::decode(&self.read_record(&segment_name(location.segment), &self.memory, location)?)?;
+        if block.calculate_hash() != *hash{
+            bail!("stored block does not match hash {}", hex::encode(hash))
+        }
+        Ok(Some(block))
+    }
+
+    //undo data written when the block was connected, None if it never was
+    pub fn get_undo(&self, hash: &HashDigest) -> Result<Option<BlockUndo>>{
+        let location = match self.entries.get(hash).and_then(|entry| entry.undo){
+            Some(location) => location,
+            None => return Ok(None)
+        };
+        Ok(Some(BlockUndo::decode(&self.read_record(&undo_name(location.segment), &self.undo_memory, location)?)?))
+    }
+
+    //the data of the record at location in the file name, or its segment of memory when there is no directory
+    fn read_record(&self, name: &str, memory: &[Vec<u8>], location: BlockLocation) -> Result<Vec<u8>>{
+        let start = location.offset - RECORD_HEADER_SIZE;
+        let len = location.len + RECORD_HEADER_SIZE;
+        let record = match &self.dir{
+            Some(dir) => {
+                let mut file = File::open(dir.join(name))?;
+                file.seek(SeekFrom::Start(start as u64))?;
+                let mut record = vec![0u8; len];
+                file.read_exact(&mut record)?;
+                record
+            }
+            None => match memory.get(location.segment as usize).and_then(|segment| segment.get(start..start + len)){
+                Some(record) => record.to_vec(),
+                None => bail!("record outside of {}", name)
+            }
+        };
+        if record[..4] != self.magic{
+            bail!("bad magic at {} in {}", start, name)
+        }
+        if u32::decode(&record[4..RECORD_HEADER_SIZE])? as usize != location.len{
+            bail!("length mismatch at {} in {}", start, name)
+        }
+        Ok(record[RECORD_HEADER_SIZE..].to_vec())
+    }
+}
+
+//writes data beside path and renames it over, so path holds either the old or the new contents even after a crash
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()>{
+    let tmp = tmp_path(path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_parent(path)
+}
+
+pub fn tmp_path(path: &Path) -> PathBuf{
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+//makes a rename in the directory durable, directories can not be opened for this on every platform
+fn sync_parent(path: &Path) -> Result<()>{
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()){
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+fn append_file(path: &Path, data: &[u8]) -> Result<()>{
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(data)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+fn file_len(path: &Path) -> Result<usize>{
+    match path.exists(){
+        true => Ok(fs::metadata(path)?.len() as usize),
+        false => Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::{params::Network, transactions::{Script, TxOutput, UtxoEntry}};
+
+    fn test_dir(name: &str) -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("coin_net_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn blocks(count: usize) -> Vec<Block>{
+        let genesis = Network::Regtest.params().genesis_block();
+        let mut blocks = vec![genesis];
+        for height in 1..count{
+            let prev_hash = blocks[height - 1].calculate_hash();
+            let coinbase = crate::transactions::Transaction::reward(10, vec![height as u8], 0, height);
+            blocks.push(Block::new(vec![coinbase], prev_hash, 0x207fffff, 0, height));
+        }
+        blocks
+    }
+
+    #[test]
+    fn reads_back_appended_blocks(){
+        let dir = test_dir("store_reads");
+        let magic = Network::Regtest.params().magic;
+        let chain = blocks(7);
+        let mut store = BlockStore::open(&dir, magic).unwrap();
+        for block in chain[..5].iter(){
+            store.append(block).unwrap();
+        }
+        //appending again does not write a second copy
+        let location = store.location(&chain[2].calculate_hash()).unwrap();
+        assert_eq!(store.append(&chain[2]).unwrap(), location);
+        assert_eq!(store.len(), 5);
+
+        let reopened = BlockStore::open(&dir, magic).unwrap();
+        assert_eq!(reopened.hashes(), store.hashes());
+        for block in chain[..5].iter(){
+            assert_eq!(reopened.get(&block.calculate_hash()).unwrap().unwrap().calculate_hash(), block.calculate_hash());
+        }
+        assert_eq!(reopened.hashes_at(3), &[chain[3].calculate_hash()]);
+        assert!(reopened.get(&[7u8; 32]).unwrap().is_none());
+
+        //blocks appended after reopening go after the existing ones
+        let mut reopened = reopened;
+        let extra = chain[6].clone();
+        let location = reopened.append(&extra).unwrap();
+        assert_eq!(location.offset, store.segment_size + RECORD_HEADER_SIZE);
+        assert!(BlockStore::open(&dir, magic).unwrap().get(&extra.calculate_hash()).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn starts_new_segments(){
+        let dir = test_dir("store_segments");
+        let magic = Network::Regtest.params().magic;
+        let blocks = blocks(5);
+        let mut store = BlockStore::open(&dir, magic).unwrap();
+        //room for two blocks per segment
+        store.max_segment_size = 2 * (blocks[1].size() + RECORD_HEADER_SIZE) + 1;
+        for block in blocks.iter(){
+            store.append(block).unwrap();
+        }
+        let segments: Vec<u32> = blocks.iter().map(|block| store.location(&block.calculate_hash()).unwrap().segment).collect();
+        assert_eq!(segments, vec![0, 0, 1, 1, 2]);
+        assert!(dir.join(segment_name(2)).exists());
+
+        let mut reopened = BlockStore::open(&dir, magic).unwrap();
+        assert_eq!(reopened.segment, 2);
+        assert_eq!(reopened.get(&blocks[3].calculate_hash()).unwrap().unwrap().calculate_hash(), blocks[3].calculate_hash());
+        let next = Block::new(Vec::new(), blocks[4].calculate_hash(), 0x207fffff, 0, 5);
+        assert_eq!(reopened.append(&next).unwrap().segment, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prunes_old_segments(){
+        let dir = test_dir("store_prune");
+        let magic = Network::Regtest.params().magic;
+        let blocks = blocks(7);
+        let mut store = BlockStore::open(&dir, magic).unwrap();
+        store.max_segment_size = 2 * (blocks[1].size() + RECORD_HEADER_SIZE) + 1;
+        for block in blocks.iter(){
+            store.append(block).unwrap();
+        }
+        let record = blocks[1].size() + RECORD_HEADER_SIZE;
+        assert_eq!(store.segments()[1], SegmentInfo { segment: 1, size: 2 * record, max_height: 3 });
+        for block in blocks[..4].iter(){
+            store.put_undo(&block.calculate_hash(), &BlockUndo::new()).unwrap();
+        }
+
+        //segment 1 still holds block 3 and the current segment is never removed
+        assert_eq!(store.prune(3).unwrap(), 1);
+        assert!(!dir.join(undo_name(0)).exists());
+        assert!(dir.join(undo_name(1)).exists());
+        assert!(!store.has_undo(&blocks[1].calculate_hash()));
+        assert!(store.has_undo(&blocks[3].calculate_hash()));
+        assert_eq!(store.prune(100).unwrap(), 2);
+        assert!(!dir.join(undo_name(1)).exists());
+        assert_eq!(store.len(), 1);
+        assert!(!dir.join(segment_name(0)).exists());
+        assert!(store.get(&blocks[1].calculate_hash()).unwrap().is_none());
+        //headers of pruned blocks are kept
+        assert_eq!(store.header(&blocks[1].calculate_hash()).unwrap().hash(), blocks[1].calculate_hash());
+        assert!(!store.contains(&blocks[1].calculate_hash()));
+
+        let mut reopened = BlockStore::open(&dir, magic).unwrap();
+        assert_eq!((reopened.segment, reopened.len()), (3, 1));
+        assert_eq!(reopened.headers().count(), 7);
+        assert!(reopened.get(&blocks[6].calculate_hash()).unwrap().is_some());
+        let next = Block::new(Vec::new(), blocks[6].calculate_hash(), 0x207fffff, 0, 7);
+        assert_eq!(reopened.append(&next).unwrap().segment, 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stores_undo_data_beside_blocks(){
+        let dir = test_dir("store_undo");
+        let magic = Network::Regtest.params().magic;
+        let chain = blocks(3);
+        let output = TxOutput::new(5, Script::P2PKHOutput(vec![1; 20]));
+        let undo = BlockUndo { spent: vec![(([3; 32], 1), UtxoEntry::new(output, 4, 0, true))] };
+        let mut store = BlockStore::open(&dir, magic).unwrap();
+        for block in chain.iter(){
+            store.append(block).unwrap();
+        }
+        let hash = chain[1].calculate_hash();
+        store.put_undo(&hash, &undo).unwrap();
+        //written once, connecting the block again spends the same outputs
+        let undo_size = fs::metadata(dir.join(undo_name(0))).unwrap().len();
+        store.put_undo(&hash, &BlockUndo::new()).unwrap();
+        assert_eq!(fs::metadata(dir.join(undo_name(0))).unwrap().len(), undo_size);
+        assert!(store.put_undo(&[7u8; 32], &undo).is_err());
+
+        let reopened = BlockStore::open(&dir, magic).unwrap();
+        assert_eq!(reopened.get_undo(&hash).unwrap().unwrap().encode(), undo.encode());
+        assert!(reopened.get_undo(&chain[2].calculate_hash()).unwrap().is_none());
+        assert_eq!(reopened.len(), 3);
+        assert_eq!(reopened.size(), store.size());
+
+        //undo data cut short by a crash is forgotten while its block is kept
+        let rev = OpenOptions::new().write(true).open(dir.join(undo_name(0))).unwrap();
+        rev.set_len(undo_size - 1).unwrap();
+        let reopened = BlockStore::open(&dir, magic).unwrap();
+        assert!(reopened.contains(&hash));
+        assert!(!reopened.has_undo(&hash));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn forgets_removed_blocks(){
+        let dir = test_dir("store_remove");
+        let magic = Network::Regtest.params().magic;
+        let chain = blocks(4);
+        let mut store = BlockStore::open(&dir, magic).unwrap();
+        for block in chain[..3].iter(){
+            store.append(block).unwrap();
+        }
+        store.remove(&[chain[2].calculate_hash()]).unwrap();
+        assert!(!store.contains(&chain[2].calculate_hash()));
+        assert!(store.hashes_at(2).is_empty());
+
+        let mut reopened = BlockStore::open(&dir, magic).unwrap();
+        assert_eq!(reopened.hashes(), &[chain[0].calculate_hash(), chain[1].calculate_hash()]);
+        assert!(reopened.get(&chain[2].calculate_hash()).unwrap().is_none());
+        reopened.append(&chain[3]).unwrap();
+        assert!(BlockStore::open(&dir, magic).unwrap().get(&chain[3].calculate_hash()).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prune_height_follows_the_target(){
+        let magic = Network::Regtest.params().magic;
+        let blocks = blocks(7);
+        let mut store = BlockStore::memory(magic);
+        store.max_segment_size = 2 * (blocks[1].size() + RECORD_HEADER_SIZE) + 1;
+        for block in blocks.iter(){
+            store.append(block).unwrap();
+        }
+        let record = blocks[1].size() + RECORD_HEADER_SIZE;
+        let tip = 1000;
+        assert_eq!(store.prune_height(PruneTarget::Blocks(500), tip), 501);
+        //never closer to the tip than the minimum
+        assert_eq!(store.prune_height(PruneTarget::Blocks(10), tip), tip + 1 - MIN_BLOCKS_TO_KEEP);
+        assert_eq!(store.prune_height(PruneTarget::Blocks(10), 100), 0);
+        //dropping segment 0 brings seven records down to five
+        assert_eq!(store.prune_height(PruneTarget::Bytes(5 * record), tip), 2);
+        assert_eq!(store.prune_height(PruneTarget::Bytes(3 * record), tip), 4);
+        assert_eq!(store.prune_height(PruneTarget::Bytes(0), tip), 6);
+        assert_eq!(store.prune_height(PruneTarget::Bytes(100 * record), tip), 0);
+
+        store.prune(2).unwrap();
+        assert!(store.get(&blocks[0].calculate_hash()).unwrap().is_none());
+        assert!(store.get(&blocks[2].calculate_hash()).unwrap().is_some());
+    }
+
+    #[test]
+    fn parses_prune_targets(){
+        assert_eq!("1000".parse::<PruneTarget>().unwrap(), PruneTarget::Blocks(1000));
+        assert_eq!("550MB".parse::<PruneTarget>().unwrap(), PruneTarget::Bytes(550 << 20));
+        assert_eq!("2gb".parse::<PruneTarget>().unwrap(), PruneTarget::Bytes(2 << 30));
+        assert!("".parse::<PruneTarget>().is_err());
+        assert!("10TB".parse::<PruneTarget>().is_err());
+        assert!("MB".parse::<PruneTarget>().is_err());
+        assert!("99999999999GB".parse::<PruneTarget>().is_err());
+        assert!(format!("{}MB", usize::MAX >> 19).parse::<PruneTarget>().is_err());
+    }
+
+    #[test]
+    fn ignores_torn_index_entries(){
+        let dir = test_dir("store_torn");
+        let magic = Network::Regtest.params().magic;
+        let blocks = blocks(3);
+        let mut store = BlockStore::open(&dir, magic).unwrap();
+        for block in blocks.iter(){
+            store.append(block).unwrap();
+        }
+        append_file(&dir.join(INDEX_FILE), &[1, 2, 3]).unwrap();
+        let reopened = BlockStore::open(&dir, magic).unwrap();
+        assert_eq!(reopened.len(), 3);
+        assert_eq!(fs::metadata(dir.join(INDEX_FILE)).unwrap().len() as usize, 3 * INDEX_ENTRY_SIZE);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovers_from_torn_segment_writes(){
+        let dir = test_dir("store_torn_segment");
+        let magic = Network::Regtest.params().magic;
+        let blocks = blocks(3);
+        let mut store = BlockStore::open(&dir, magic).unwrap();
+        for block in blocks.iter(){
+            store.append(block).unwrap();
+        }
+        let segment = dir.join(segment_name(0));
+        let size = store.segment_size as u64;
+
+        //a record that never got its index entry
+        append_file(&segment, &[9u8; 10]).unwrap();
+        let reopened = BlockStore::open(&dir, magic).unwrap();
+        assert_eq!(reopened.len(), 3);
+        assert_eq!(fs::metadata(&segment).unwrap().len(), size);
+
+        //an index entry whose record was cut short
+        OpenOptions::new().write(true).open(&segment).unwrap().set_len(size - 5).unwrap();
+        let mut reopened = BlockStore::open(&dir, magic).unwrap();
+        assert_eq!(reopened.len(), 2);
+        assert!(!reopened.contains(&blocks[2].calculate_hash()));
+        assert_eq!(fs::metadata(dir.join(INDEX_FILE)).unwrap().len() as usize, 2 * INDEX_ENTRY_SIZE);
+        reopened.append(&blocks[2]).unwrap();
+        assert_eq!(fs::metadata(&segment).unwrap().len(), size);
+        assert!(BlockStore::open(&dir, magic).unwrap().get(&blocks[2].calculate_hash()).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_writes_replace_the_whole_file(){
+        let dir = test_dir("atomic_write");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("node.json");
+        write_atomic(&path, b"first version").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert!(!tmp_path(&path).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn memory_store_matches_disk_layout(){
+        let magic = Network::Regtest.params().magic;
+        let mut store = BlockStore::memory(magic);
+        let blocks = blocks(3);
+        for block in blocks.iter(){
+            store.append(block).unwrap();
+        }
+        let location = store.location(&blocks[1].calculate_hash()).unwrap();
+        assert_eq!(location.offset, blocks[0].size() + 2 * RECORD_HEADER_SIZE);
+        assert_eq!(store.get(&blocks[1].calculate_hash()).unwrap().unwrap().calculate_hash(), blocks[1].calculate_hash());
+        //a different network's magic is caught on read
+        store.magic = Network::Main.params().magic;
+        assert!(store.get(&blocks[1].calculate_hash()).is_err());
+    }
+}
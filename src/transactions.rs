@@ -1,111 +1,442 @@
-use crate::{miner::{Block, sha256, get_timestamp}};
+use crate::{chain::is_money_range, encoding::{Decode, Encode, Reader, write_compact_size}, miner::{Block, HashDigest, sha256, get_timestamp}};
 
-use std::{collections::HashMap};
+use std::{collections::{HashMap, HashSet}, io};
 use k256::{ecdsa::{Signature, SigningKey, VerifyingKey, signature::Signer}};
 use k256::ecdsa::signature::Verifier;
-use log::{info, warn};
-use rand_core::OsRng;
+use k256::schnorr;
+use rand_core::{OsRng, RngCore};
 use sha2::{Digest, Sha256};
 use serde::{Deserialize, Serialize, de::{self, Visitor}};
-use anyhow::{Result};
+use anyhow::{Context, Result, bail};
 
 pub fn is_coinbase(transaction: &Transaction) -> bool{
     transaction.input_count == 0
 }
 
-#[derive(Clone, Debug)]
-pub struct UTXOS(HashMap<([u8; 32], usize), TxOutput>);
+//locktimes below this are block heights, from it on unix times
+pub const LOCKTIME_THRESHOLD: usize = 500_000_000;
+//an input with this sequence opts out of the transaction locktime and of relative locks
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+//relative locks of the sequence field, a set disable flag turns them off
+pub const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+//with the type flag the lock counts units of 512 seconds instead of blocks
+pub const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+const SEQUENCE_GRANULARITY: usize = 9;
+
+//largest payload a data carrier output may hold
+pub const MAX_DATA_CARRIER_SIZE: usize = 80;
+
+fn final_sequence() -> u32{
+    SEQUENCE_FINAL
+}
+
+//an unspent output along with where it was created
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UtxoEntry{
+    pub output: TxOutput,
+    pub height: usize,
+    //median time past before the block that created it, relative time locks count from here
+    #[serde(default)]
+    pub time: usize,
+    pub coinbase: bool,
+}
+
+impl UtxoEntry{
+    pub fn new(output: TxOutput, height: usize, time: usize, coinbase: bool) -> Self{
+        Self { output, height, time, coinbase }
+    }
+
+    //coinbase outputs can only be spent once they have maturity confirmations
+    pub fn is_mature(&self, spend_height: usize, maturity: usize) -> bool{
+        !self.coinbase || spend_height >= self.height + maturity
+    }
+}
+
+//field order is output, height, time, coinbase as a single 0 or 1 byte
+impl Encode for UtxoEntry{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        self.output.encode_to(buf);
+        self.height.encode_to(buf);
+        self.time.encode_to(buf);
+        (self.coinbase as u8).encode_to(buf);
+    }
+}
+
+impl Decode for UtxoEntry{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        let output = TxOutput::decode_from(reader)?;
+        let height = usize::decode_from(reader)?;
+        let time = usize::decode_from(reader)?;
+        let coinbase = match u8::decode_from(reader)?{
+            0 => false,
+            1 => true,
+            byte => bail!("invalid coinbase flag {}", byte)
+        };
+        Ok(Self { output, height, time, coinbase })
+    }
+}
+
+//unspent outputs by txid and output index, blocks are connected to and disconnected from it
+//through the provided methods so the in memory set and the disk store share the consensus rules,
+//a store that can not be read returns the io error instead of reporting the output as missing
+pub trait UtxoSet{
+    fn get_entry(&self, output_hash: [u8; 32], index: usize) -> io::Result<Option<UtxoEntry>>;
+
+    fn insert(&mut self, hash: [u8; 32], index: usize, entry: UtxoEntry) -> io::Result<()>;
+
+    fn remove(&mut self, hash: [u8; 32], index: usize) -> io::Result<Option<UtxoEntry>>;
+
+    fn size(&self) -> usize;
+
+    fn get(&self, output_hash: [u8; 32], index: usize) -> io::Result<Option<TxOutput>>{
+        Ok(self.get_entry(output_hash, index)?.map(|entry| entry.output))
+    }
+
+    fn get_fee(&self, transaction: Transaction) -> Result<usize>
+        where Self: Sized{
+        UtxoView::new(self, 0, 0, 0).get_fee(&transaction)
+    }
+
+    //spend_height is the height of the block the transaction would be mined in and time the median time past before it
+    fn validate_transaction(&self, transaction: Transaction, spend_height: usize, time: usize, maturity: usize) -> Result<()>
+        where Self: Sized{
+        UtxoView::new(self, spend_height, time, maturity).validate_transaction(&transaction)
+    }
+
+    fn add_transaction(&mut self, transaction: Transaction, height: usize, time: usize) -> io::Result<SpentOutputs>{
+        let hash = transaction.txid();
+        let coinbase = is_coinbase(&transaction);
+        let mut spent = Vec::new();
+        for input in transaction.inputs{
+            if let Some(entry) = self.remove(input.prev, input.output_index)?{
+                spent.push(((input.prev, input.output_index), entry));
+            }
+        }
+        //data carriers can never be spent so they are not tracked
+        for (index, output) in transaction.outputs.iter().enumerate().filter(|(_, output)| !output.script.is_data_carrier()){
+            self.insert(hash, index, UtxoEntry::new(output.clone(), height, time, coinbase))?;
+        }
+        Ok(spent)
+    }
+
+    fn remove_transaction(&mut self, transaction: &Transaction) -> io::Result<()>{
+        let hash = transaction.txid();
+        for index in 0..transaction.outputs.len(){
+            self.remove(hash, index)?;
+        }
+        Ok(())
+    }
+
+    //reward is the block subsidy, the coinbase may also claim every fee in the block
+    //time is the median time past before the block, locktimes are checked against it
+    fn validate_block(&self, block: &Block, reward: usize, time: usize, maturity: usize) -> Result<()>
+        where Self: Sized{
+        let coinbase_value = match block.coinbase(){
+            Some(coinbase) => coinbase.output_value()?,
+            None => bail!("missing coinbase")
+        };
+        //transactions are applied in order so later ones may spend earlier outputs but not the same outpoint twice
+        let mut view = UtxoView::new(self, block.block_header.height, time, maturity);
+        let mut fees: usize = 0;
+        for (index, tx) in block.transactions.iter().enumerate().skip(1){
+            view.validate_transaction(tx).with_context(|| format!("invalid transaction {}", index))?;
+            fees = fees.checked_add(view.get_fee(tx)?).context("block fees overflow")?;
+            view.apply(tx);
+        }
+        let claimable = reward.checked_add(fees).context("block reward overflows")?;
+        if coinbase_value > claimable{
+            bail!("coinbase pays {} but reward plus fees is {}", coinbase_value, claimable)
+        }
+        Ok(())
+    }
+
+    fn add_block(&mut self, block: Block, time: usize) -> io::Result<BlockUndo>{
+        let mut undo = BlockUndo::new();
+        let height = block.block_header.height;
+        for tx in block.transactions{
+            undo.spent.extend(self.add_transaction(tx, height, time)?);
+        }
+        Ok(undo)
+    }
+
+    //reverses add_block, restoring every output the block spent
+    fn remove_block(&mut self, block: &Block, undo: &BlockUndo) -> io::Result<()>{
+        for tx in block.transactions.iter().rev(){
+            self.remove_transaction(tx)?;
+        }
+        for ((hash, index), entry) in undo.restored(block){
+            self.insert(hash, index, entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UTXOS(HashMap<([u8; 32], usize), UtxoEntry>);
 
 impl UTXOS{
     pub fn new() -> Self{
         Self(HashMap::new())
     }
 
-    pub fn add(&mut self, hash: [u8; 32], index: usize, output: TxOutput){
-        self.0.insert((hash, index), output);
+    pub fn add(&mut self, hash: [u8; 32], index: usize, entry: UtxoEntry){
+        self.0.insert((hash, index), entry);
     }
+}
 
-    fn get(&self, output_hash: [u8; 32], index: usize) -> Option<TxOutput>{
-        self.0.get(&(output_hash, index)).cloned()
+impl UtxoSet for UTXOS{
+    fn get_entry(&self, output_hash: [u8; 32], index: usize) -> io::Result<Option<UtxoEntry>>{
+        Ok(self.0.get(&(output_hash, index)).cloned())
     }
 
-    pub fn get_fee(&self, transaction: Transaction) -> Option<usize>{
-        let mut total_in: usize = 0;
-        for input in transaction.inputs.iter(){
-            match self.get(input.prev, input.output_index){
-                Some(a) => {
-                    total_in += a.value;
-                },
-                None => {
-                    println!("Not in utxos: {}, {}", hex::encode(input.prev), input.output_index);
-                    return None
-                }
-            }
-        }
-        let total_out: usize = transaction.outputs.iter()
-            .map(|o| o.value)
-            .sum();
+    fn insert(&mut self, hash: [u8; 32], index: usize, entry: UtxoEntry) -> io::Result<()>{
+        self.add(hash, index, entry);
+        Ok(())
+    }
 
-        if total_out > total_in{
-            return None
-        }else{
-            Some(total_in - total_out)
+    fn remove(&mut self, hash: [u8; 32], index: usize) -> io::Result<Option<UtxoEntry>>{
+        Ok(self.0.remove(&(hash, index)))
+    }
+
+    fn size(&self) -> usize{
+        self.0.len()
+    }
+}
+
+//outputs created and spent on top of a utxo set without modifying it
+pub struct UtxoView<'a>{
+    base: &'a dyn UtxoSet,
+    added: HashMap<([u8; 32], usize), UtxoEntry>,
+    spent: HashSet<([u8; 32], usize)>,
+    //height of the block being built or validated
+    height: usize,
+    //median time past before that block
+    time: usize,
+    maturity: usize,
+}
+
+impl<'a> UtxoView<'a>{
+    pub fn new(base: &'a dyn UtxoSet, height: usize, time: usize, maturity: usize) -> Self{
+        Self { 
+            base, 
+            added: HashMap::new(), 
+            spent: HashSet::new(),
+            height,
+            time,
+            maturity
         }
+    }
 
+    fn get(&self, output_hash: [u8; 32], index: usize) -> io::Result<Option<TxOutput>>{
+        Ok(self.get_entry(output_hash, index)?.map(|entry| entry.output))
     }
 
-    pub fn validate_transaction(&self, transaction: Transaction) -> bool{
-        if is_coinbase(&transaction){return true}
+    fn get_entry(&self, output_hash: [u8; 32], index: usize) -> io::Result<Option<UtxoEntry>>{
+        let key = (output_hash, index);
+        if self.spent.contains(&key){
+            return Ok(None)
+        }
+        match self.added.get(&key){
+            Some(entry) => Ok(Some(entry.clone())),
+            None => self.base.get_entry(output_hash, index)
+        }
+    }
 
-        if self.get_fee(transaction.clone()) == None{
-            warn!("NO fee for: {:?}", transaction);
-            return false
+    //values come from peers so both sums are checked
+    pub fn get_fee(&self, transaction: &Transaction) -> Result<usize>{
+        let mut total_in: usize = 0;
+        for input in transaction.inputs.iter(){
+            let output = match self.get(input.prev, input.output_index)?{
+                Some(output) => output,
+                None => bail!("input {}:{} is not in the utxo set", hex::encode(input.prev), input.output_index)
+            };
+            total_in = total_in.checked_add(output.value).context("transaction input values overflow")?;
         }
-        
-        for input in transaction.inputs.clone(){
-            let utxo = self.get(input.prev, input.output_index).unwrap();
-            let script = Script::concat(input.script.clone(), utxo.script.clone());
-            if script.validate_script(&transaction.clone(), input.output_index, &utxo){
-                warn!("Invalid script");
-                return false
-            }
+        if !is_money_range(total_in){
+            bail!("transaction inputs are out of range")
+        }
+        let total_out = transaction.output_value()?;
+        match total_in.checked_sub(total_out){
+            Some(fee) => Ok(fee),
+            None => bail!("outputs worth {} exceed inputs worth {}", total_out, total_in)
         }
-        true
     }
 
+    //script failures carry a ScriptError that can be downcast from the returned error
+    pub fn validate_transaction(&self, transaction: &Transaction) -> Result<()>{
+        transaction.check()?;
+        //validate_block skips the coinbase, anywhere else one could be used to mint coins
+        if is_coinbase(transaction){
+            bail!("coinbase is only valid as the first transaction of a block")
+        }
 
-    pub fn add_transaction(&mut self, transaction: Transaction){
-        let hash = sha256(transaction.serialize().clone());
-        for input in transaction.inputs{
-            self.0.remove(&(input.prev, input.output_index));
+        let outpoints: HashSet<([u8; 32], usize)> = transaction.inputs.iter()
+            .map(|input| (input.prev, input.output_index))
+            .collect();
+        if outpoints.len() != transaction.inputs.len(){
+            bail!("transaction spends the same output twice")
         }
-        for (index, output) in transaction.outputs.iter().enumerate(){
-            self.0.insert((hash, index), output.clone());
+
+        if !transaction.is_final(self.height, self.time){
+            bail!("transaction locked until {} at height {}", transaction.locktime, self.height)
         }
+
+        //outputs were range checked above, so only the inputs can fail here
+        self.get_fee(transaction)?;
         
+        for (index, input) in transaction.inputs.iter().enumerate(){
+            let entry = self.get_entry(input.prev, input.output_index)?.context("input is not in the utxo set")?;
+            if !entry.is_mature(self.height, self.maturity){
+                bail!("coinbase from height {} spent before maturity at height {}", entry.height, self.height)
+            }
+            if !input.relative_lock_satisfied(&entry, self.height, self.time){
+                bail!("input {} relatively locked by sequence {:08x}", index, input.sequence)
+            }
+            let utxo = entry.output;
+            let script = Script::concat(input.script.clone(), utxo.script.clone());
+            script.validate_script(transaction, index, &utxo)
+                .with_context(|| format!("script of input {} failed", index))?;
+        }
+        Ok(())
     }
-    pub fn validate_block(&self, block: Block) -> bool{
-        for tx in block.transactions.clone(){
-            if !self.validate_transaction(tx){
-                warn!("Invalid block"); return false
+
+    //spends the inputs and adds the outputs of an already validated transaction
+    pub fn apply(&mut self, transaction: &Transaction){
+        for input in transaction.inputs.iter(){
+            let key = (input.prev, input.output_index);
+            if self.added.remove(&key).is_none(){
+                self.spent.insert(key);
             }
         }
-        true
+        let hash = transaction.txid();
+        let coinbase = is_coinbase(transaction);
+        for (index, output) in transaction.outputs.iter().enumerate().filter(|(_, output)| !output.script.is_data_carrier()){
+            self.added.insert((hash, index), UtxoEntry::new(output.clone(), self.height, self.time, coinbase));
+        }
     }
+}
 
-    pub fn add_block(&mut self, block: Block) -> bool{
-        for tx in block.transactions{
-            self.add_transaction(tx);
+//outputs spent by a transaction or block, keyed by the output they were
+pub type SpentOutputs = Vec<(([u8; 32], usize), UtxoEntry)>;
+
+//outputs spent by a block, needed to disconnect it again
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BlockUndo{
+    pub spent: SpentOutputs,
+}
+
+impl BlockUndo{
+    pub fn new() -> Self{
+        Self { spent: Vec::new() }
+    }
+
+    //spent outputs that existed before the block, skipping ones created and spent inside it
+    fn restored(&self, block: &Block) -> Vec<(([u8; 32], usize), UtxoEntry)>{
+        let created: HashSet<[u8; 32]> = block.transactions.iter()
+            .map(|tx| tx.txid())
+            .collect();
+        self.spent.iter()
+            .filter(|((hash, _), _)| !created.contains(hash))
+            .cloned()
+            .collect()
+    }
+}
+
+//field order is the number of spent outputs then each one's txid, output index and entry
+impl Encode for BlockUndo{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        write_compact_size(buf, self.spent.len());
+        for ((hash, index), entry) in self.spent.iter(){
+            hash.encode_to(buf);
+            index.encode_to(buf);
+            entry.encode_to(buf);
+        }
+    }
+}
+
+impl Decode for BlockUndo{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        let count = reader.read_compact_size()?;
+        let mut spent = Vec::with_capacity(count.min(reader.remaining()));
+        for _ in 0..count{
+            let hash = Decode::decode_from(reader)?;
+            let index = Decode::decode_from(reader)?;
+            spent.push(((hash, index), UtxoEntry::decode_from(reader)?));
         }
-        true
+        Ok(Self { spent })
     }
 }
 
+//outputs chosen to fund a transaction and their total value
+pub type SelectedInputs = (Vec<(([u8; 32], usize), TxOutput)>, usize);
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Wallet{
     pub value: usize,
     utxos: UTXOS,
     pub pub_key: Vec<u8>,
+    //multisig outputs listing our key, they need co-signers so they are not part of value
+    #[serde(default = "UTXOS::new")]
+    multisig: UTXOS,
+    //redeem scripts we handed out P2SH addresses for, by hex script hash
+    #[serde(default)]
+    scripts: HashMap<String, Script>,
+    //outputs paying to one of those scripts, also not part of value
+    #[serde(default = "UTXOS::new")]
+    p2sh: UTXOS,
+}
+
+//receive addresses with this prefix pay to a hex script hash, anything else is a hex public key
+pub const P2SH_ADDRESS_PREFIX: &str = "p2sh:";
+//receive addresses with this prefix pay to a hex x-only key spent with a schnorr signature
+pub const SCHNORR_ADDRESS_PREFIX: &str = "schnorr:";
+
+//x-only key of a compressed public key, the schnorr key of the same secret has the same x coordinate
+pub fn xonly_key(pub_key: &[u8]) -> Option<Vec<u8>>{
+    match pub_key{
+        [0x02 | 0x03, x @ ..] if x.len() == 32 => Some(x.to_vec()),
+        _ => None
+    }
+}
+
+//locking script paying to address
+pub fn address_script(address: &str) -> Result<Script>{
+    if let Some(key) = address.strip_prefix(SCHNORR_ADDRESS_PREFIX){
+        let key = hex::decode(key)?;
+        if key.len() != 32 || schnorr::VerifyingKey::from_bytes(&key).is_err(){
+            bail!("invalid x-only key in address {}", address)
+        }
+        return Ok(Script::SchnorrOutput(key))
+    }
+    match address.strip_prefix(P2SH_ADDRESS_PREFIX){
+        Some(hash) => {
+            let hash = hex::decode(hash)?;
+            if hash.len() != 32{
+                bail!("script hash of {} bytes in address {}", hash.len(), address)
+            }
+            Ok(Script::P2SHOutput(hash))
+        }
+        None => Ok(Script::P2PKHOutput(sha256(address).to_vec()))
+    }
+}
+
+//picks entries that pass spendable until their value is above value
+fn select_inputs(utxos: &UTXOS, value: usize, spendable: impl Fn(&UtxoEntry) -> bool) -> Option<SelectedInputs>{
+    let mut cur_val: usize = 0;
+    let mut utxo_clone = utxos.clone();
+    utxo_clone.0.retain(|_, entry| spendable(entry));
+    let mut inputs = Vec::new();
+    while cur_val <= value{
+        if let Some(key) = utxo_clone.0.keys().next().cloned() {
+            let output = utxo_clone.0.remove(&key).unwrap().output;
+            cur_val += output.value;
+            inputs.push((key, output));
+        } else{
+            return None
+        }
+    }
+    Some((inputs, cur_val))
 }
 
 impl Wallet{
@@ -113,43 +444,218 @@ impl Wallet{
         Self { 
             value: 0, 
             utxos: UTXOS::new(),
-            pub_key: pub_key
+            pub_key,
+            multisig: UTXOS::new(),
+            scripts: HashMap::new(),
+            p2sh: UTXOS::new(),
         }
     }
 
+    //P2PKH and schnorr outputs to our key are spendable by us alone and count towards value
+    fn is_own(&self, script: &Script) -> bool{
+        let pk_hash = sha256(hex::encode(&self.pub_key)).to_vec();
+        script.P2PKHOutput_pubkey_hash() == Some(pk_hash)
+            || script.schnorr_key().is_some_and(|key| Some(key) == xonly_key(&self.pub_key))
+    }
+
+    pub fn schnorr_address(&self) -> Option<String>{
+        xonly_key(&self.pub_key).map(|key| format!("{}{}", SCHNORR_ADDRESS_PREFIX, hex::encode(key)))
+    }
+
+    fn is_cosigner(&self, script: &Script) -> bool{
+        script.multisig_keys().is_some_and(|(_, keys)| keys.contains(&self.pub_key))
+    }
+
+    //redeem script behind a P2SH locking script if we handed out its address
+    pub fn redeem_script(&self, script: &Script) -> Option<Script>{
+        script.p2sh_script_hash().and_then(|hash| self.scripts.get(&hex::encode(hash)).cloned())
+    }
+
+    //remembers redeem_script so outputs paying to it are tracked and returns the address to receive them on
+    pub fn p2sh_address(&mut self, redeem_script: Script) -> String{
+        let hash = hex::encode(redeem_script.script_hash());
+        self.scripts.insert(hash.clone(), redeem_script);
+        format!("{}{}", P2SH_ADDRESS_PREFIX, hash)
+    }
+
+    //forgets every tracked output, keeping the keys and redeem scripts so the chain can be scanned again
+    pub fn clear_outputs(&mut self){
+        self.value = 0;
+        self.utxos = UTXOS::new();
+        self.multisig = UTXOS::new();
+        self.p2sh = UTXOS::new();
+    }
+
+    //the wallet does not track times since locks are checked by the node
     pub fn update(&mut self, block: Block){
+        let height = block.block_header.height;
         for tx in block.transactions{
             for input in tx.clone().inputs{
-                if let Some(output) = self.utxos.get(input.prev, input.output_index){
-                    self.value -= output.value;
-                    self.utxos.0.remove(&(input.prev, input.output_index));
+                if let Some(entry) = self.utxos.0.remove(&(input.prev, input.output_index)){
+                    self.value -= entry.output.value;
                 }
+                self.multisig.0.remove(&(input.prev, input.output_index));
+                self.p2sh.0.remove(&(input.prev, input.output_index));
             }
-            let pk_hash = sha256(hex::encode(self.pub_key.clone())).to_vec();
-            let tx_hash = sha256(tx.clone().serialize());
+            let tx_hash = tx.txid();
+            let coinbase = is_coinbase(&tx);
             for (index, output) in tx.outputs.iter().cloned().enumerate(){
-                if let Some(hash) = output.clone().script.P2PKHOutput_pubkey_hash() && hash == pk_hash {
-                    self.utxos.add(tx_hash, index, output.clone());
+                if self.is_own(&output.script){
                     self.value += output.value;
+                    self.utxos.add(tx_hash, index, UtxoEntry::new(output, height, 0, coinbase));
+                }
+                else if self.is_cosigner(&output.script){
+                    self.multisig.add(tx_hash, index, UtxoEntry::new(output, height, 0, coinbase));
+                }
+                else if self.redeem_script(&output.script).is_some(){
+                    self.p2sh.add(tx_hash, index, UtxoEntry::new(output, height, 0, coinbase));
                 }
             }
         }
     }
 
-    pub fn get_inputs(&self, value: usize) -> Option<(Vec<(([u8; 32], usize), TxOutput)>, usize)>{
-        let mut cur_val: usize = 0;
-        let mut utxo_clone = self.utxos.clone();
-        let mut inputs = Vec::new();
-        while cur_val <= value{
-            if let Some(key) = utxo_clone.0.keys().next().cloned() {
-                let output = utxo_clone.0.remove(&key).unwrap();
-                cur_val += output.value;
-                inputs.push((key, output));
-            } else{
-                return None
+    pub fn revert(&mut self, block: &Block, undo: &BlockUndo){
+        for tx in block.transactions.iter(){
+            let tx_hash = tx.txid();
+            for index in 0..tx.outputs.len(){
+                if let Some(entry) = self.utxos.0.remove(&(tx_hash, index)){
+                    self.value -= entry.output.value;
+                }
+                self.multisig.0.remove(&(tx_hash, index));
+                self.p2sh.0.remove(&(tx_hash, index));
+            }
+        }
+        for ((hash, index), entry) in undo.restored(block){
+            if self.is_own(&entry.output.script){
+                self.value += entry.output.value;
+                self.utxos.add(hash, index, entry);
+            }
+            else if self.is_cosigner(&entry.output.script){
+                self.multisig.add(hash, index, entry);
+            }
+            else if self.redeem_script(&entry.output.script).is_some(){
+                self.p2sh.add(hash, index, entry);
+            }
+        }
+    }
+
+    //mature and immature funds for a transaction mined at spend_height
+    pub fn balance(&self, spend_height: usize, maturity: usize) -> (usize, usize){
+        let mature: usize = self.utxos.0.values()
+            .filter(|entry| entry.is_mature(spend_height, maturity))
+            .map(|entry| entry.output.value)
+            .sum();
+        (mature, self.value - mature)
+    }
+
+    //only picks outputs that can be spent at spend_height
+    pub fn get_inputs(&self, value: usize, spend_height: usize, maturity: usize) -> Option<SelectedInputs>{
+        select_inputs(&self.utxos, value, |entry| entry.is_mature(spend_height, maturity))
+    }
+
+    pub fn multisig_outputs(&self) -> Vec<(([u8; 32], usize), UtxoEntry)>{
+        self.multisig.0.iter().map(|(key, entry)| (*key, entry.clone())).collect()
+    }
+
+    //P2SH outputs paying to our scripts along with the redeem script each needs
+    pub fn p2sh_outputs(&self) -> Vec<(([u8; 32], usize), UtxoEntry, Script)>{
+        self.p2sh.0.iter()
+            .filter_map(|(key, entry)| Some((*key, entry.clone(), self.redeem_script(&entry.output.script)?)))
+            .collect()
+    }
+
+    //multisig outputs locked by script that can be spent at spend_height
+    pub fn get_multisig_inputs(&self, script: &Script, value: usize, spend_height: usize, maturity: usize) -> Option<SelectedInputs>{
+        select_inputs(&self.multisig, value, |entry| entry.output.script == *script && entry.is_mature(spend_height, maturity))
+    }
+}
+
+//spend of multisig outputs passed between the key holders, each adds their signatures
+//until every input has enough of them
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PartialTransaction{
+    pub transaction: Transaction,
+    //outputs being spent, one per input
+    spent: Vec<TxOutput>,
+    //per input one slot for each key of its locking script
+    signatures: Vec<Vec<Option<Vec<u8>>>>,
+}
+
+impl PartialTransaction{
+    pub fn new(version: usize, inputs: Vec<(([u8; 32], usize), TxOutput)>, outputs: Vec<TxOutput>) -> Result<Self>{
+        let mut signatures = Vec::new();
+        for ((hash, index), output) in inputs.iter(){
+            match output.script.multisig_keys(){
+                Some((_, keys)) => signatures.push(vec![None; keys.len()]),
+                None => bail!("output {}:{} is not a multisig output", hex::encode(hash), index)
             }
         }
-        Some((inputs, cur_val))
+        Ok(Self { 
+            transaction: Transaction::unsigned(version, &inputs, outputs), 
+            spent: inputs.into_iter().map(|(_, output)| output).collect(), 
+            signatures 
+        })
+    }
+
+    //signs every input that lists user's key, returns how many were signed
+    pub fn sign(&mut self, user: &User) -> usize{
+        let pubkey = user.get_pub_key();
+        let mut signed = 0;
+        for (index, output) in self.spent.iter().enumerate(){
+            let position = match output.script.multisig_keys(){
+                Some((_, keys)) => keys.iter().position(|key| *key == pubkey),
+                None => None
+            };
+            if let Some(position) = position && let Some(slot) = self.signatures.get_mut(index).and_then(|sigs| sigs.get_mut(position)){
+                *slot = user.sign_input(&self.transaction, index, output, SIGHASH_ALL);
+                signed += 1;
+            }
+        }
+        signed
+    }
+
+    //takes the signatures of a copy that was signed by someone else
+    pub fn combine(&mut self, other: &PartialTransaction) -> Result<()>{
+        if self.transaction != other.transaction || self.spent != other.spent{
+            bail!("partial transactions spend different outputs")
+        }
+        for (sigs, other_sigs) in self.signatures.iter_mut().zip(other.signatures.iter()){
+            for (sig, other_sig) in sigs.iter_mut().zip(other_sigs.iter()){
+                if sig.is_none(){
+                    *sig = other_sig.clone();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //signatures still needed across all inputs
+    pub fn missing(&self) -> usize{
+        self.spent.iter()
+            .zip(self.signatures.iter())
+            .map(|(output, sigs)| {
+                let required = output.script.multisig_keys().map_or(0, |(required, _)| required);
+                required.saturating_sub(sigs.iter().flatten().count())
+            })
+            .sum()
+    }
+
+    pub fn is_complete(&self) -> bool{
+        self.missing() == 0
+    }
+
+    //fills in the unlocking scripts once complete, signatures stay in the order of their keys
+    pub fn finalize(&self) -> Option<Transaction>{
+        if !self.is_complete(){
+            return None
+        }
+        let mut transaction = self.transaction.clone();
+        for (index, (output, sigs)) in self.spent.iter().zip(self.signatures.iter()).enumerate(){
+            let (required, _) = output.script.multisig_keys()?;
+            let sigs = sigs.iter().flatten().take(required).cloned().collect();
+            transaction.inputs[index].script = Script::MultisigInput(sigs);
+        }
+        Some(transaction)
     }
 }
 
@@ -157,7 +663,7 @@ impl Serialize for UTXOS{
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
         where
             S: serde::Serializer {
-        let map: HashMap<String, &TxOutput> = self.0
+        let map: HashMap<String, &UtxoEntry> = self.0
             .iter()
             .map(|((hash, idx), output)| {
                 let key = format!("{}:{}", hex::encode(hash), idx);
@@ -185,7 +691,7 @@ impl <'de>Deserialize<'de> for UTXOS{
                 where
                     A: serde::de::MapAccess<'de>, {
                 let mut utxos = HashMap::new();
-                while let Some((key, value)) = map.next_entry::<String, TxOutput>()? {
+                while let Some((key, value)) = map.next_entry::<String, UtxoEntry>()? {
 
                     let parts: Vec<&str> = key.split(":").collect();
                     if parts.len() != 2{
@@ -266,17 +772,47 @@ impl User{
         self.private_key.sign(&Sha256::digest(message))
     }
 
+    //signature for input_index followed by its sighash type, None when the type can not sign that input
+    pub fn sign_input(&self, tx: &Transaction, input_index: usize, utxo: &TxOutput, sighash_type: u8) -> Option<Vec<u8>>{
+        let sighash = compute_sig_hash(tx.clone(), input_index, utxo, sighash_type)?;
+        let mut sig = self.sign(hex::encode(sighash)).to_vec();
+        sig.push(sighash_type);
+        Some(sig)
+    }
+
     pub fn get_pub_key(&self) -> Vec<u8>{
         self.public_key.to_sec1_bytes().to_vec()
     }
 
+    //the same secret used for BIP340 schnorr signatures
+    fn schnorr_signing_key(&self) -> schnorr::SigningKey{
+        schnorr::SigningKey::from_bytes(&self.private_key.to_bytes()).expect("an ecdsa secret is a valid schnorr secret")
+    }
+
+    //32 byte x-only key schnorr outputs pay to
+    pub fn get_schnorr_pub_key(&self) -> Vec<u8>{
+        self.schnorr_signing_key().verifying_key().to_bytes().to_vec()
+    }
+
+    //BIP340 signature over the raw sighash followed by its sighash type
+    pub fn sign_input_schnorr(&self, tx: &Transaction, input_index: usize, utxo: &TxOutput, sighash_type: u8) -> Option<Vec<u8>>{
+        let sighash = compute_sig_hash(tx.clone(), input_index, utxo, sighash_type)?;
+        let mut aux_rand = [0u8; 32];
+        OsRng.fill_bytes(&mut aux_rand);
+        let signature = self.schnorr_signing_key().sign_raw(&sighash, &aux_rand).ok()?;
+        let mut sig = signature.to_bytes().to_vec();
+        sig.push(sighash_type);
+        Some(sig)
+    }
+
     fn get_pub_key_hash(&self) -> Vec<u8>{
         sha256(hex::encode(&self.get_pub_key())).to_vec()
     }
 }
 
+//mirrors User::sign which signs the digest of the hex encoded sighash
 fn verify_sig(public_key: VerifyingKey, message_hash: [u8; 32], signature: Signature) -> bool{
-    public_key.verify(&message_hash, &signature).is_ok()
+    public_key.verify(&Sha256::digest(hex::encode(message_hash)), &signature).is_ok()
         
 }
 
@@ -300,22 +836,66 @@ impl <'de>Deserialize<'de>for User{
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Transaction{
-    timestamp: usize,
+    pub timestamp: usize,
     version: usize,
     pub input_count: usize,
     pub inputs: Vec<TxInput>,
-    output_count: usize,
+    pub output_count: usize,
     pub outputs: Vec<TxOutput>,
+    //set on coinbases so two of them never share a hash
+    #[serde(default)]
+    pub height: usize,
+    //earliest block height or time the transaction may be mined at, 0 for none
+    #[serde(default)]
+    pub locktime: usize,
 }
 
 impl Transaction{
-    pub fn serialize(&self) -> String{
-        serde_json::to_string(self).unwrap()
+    //identifies the transaction for spending and relay, unlocking scripts are left out
+    //so changing how an input is satisfied can not change the id later inputs refer to
+    pub fn txid(&self) -> HashDigest{
+        let mut stripped = self.clone();
+        for input in stripped.inputs.iter_mut(){
+            input.script = Script::empty();
+        }
+        sha256(stripped.encode())
+    }
+
+    //hash of every byte including unlocking scripts, blocks commit to these
+    pub fn full_hash(&self) -> HashDigest{
+        sha256(self.encode())
+    }
+
+    //bytes the transaction takes up in a block
+    pub fn size(&self) -> usize{
+        self.encode().len()
+    }
+
+    //errors instead of wrapping when the values of a peer's transaction do not fit
+    pub fn output_value(&self) -> Result<usize>{
+        self.outputs.iter()
+            .try_fold(0usize, |total, o| total.checked_add(o.value))
+            .context("transaction output values overflow")
+    }
+
+    //context free checks shared by blocks and the mempool, where a coinbase may appear is up to the caller
+    pub fn check(&self) -> Result<()>{
+        if self.input_count != self.inputs.len() || self.output_count != self.outputs.len(){
+            bail!("transaction input/output counts do not match")
+        }
+        if !self.outputs.iter().all(|o| is_money_range(o.value)) || !is_money_range(self.output_value()?){
+            bail!("transaction outputs are out of range")
+        }
+        if !self.has_valid_data_outputs(){
+            bail!("data carrier output larger than {} bytes", MAX_DATA_CARRIER_SIZE)
+        }
+        Ok(())
     }
 
-    pub fn reward(reward: usize, pubkey: Vec<u8>, version: usize) -> Self{
+    pub fn reward(reward: usize, pubkey: Vec<u8>, version: usize, height: usize) -> Self{
         Self { 
             timestamp: get_timestamp(),
+            height,
             version, 
             input_count: 0, 
             inputs: Vec::new(), 
@@ -323,62 +903,285 @@ impl Transaction{
             outputs:vec![TxOutput{
                 value: reward,
                 script: Script::P2PKHOutput(sha256(hex::encode(&pubkey)).to_vec())
-            }]
+            }],
+            locktime: 0,
         }
     }
 
     pub fn new(version: usize, user: User, inputs: Vec<(([u8; 32], usize), TxOutput)>, outputs: Vec<(String, usize)>) -> Self{
-        let mut transaction = Transaction{
+        let outputs = outputs.iter()
+            .map(|(pub_key, amount)| TxOutput::new(*amount, Script::P2PKHOutput(sha256(pub_key.clone()).to_vec())))
+            .collect();
+        Self::with_outputs(version, user, inputs, outputs)
+    }
+
+    //inputs have empty unlocking scripts until they are signed
+    pub fn unsigned(version: usize, inputs: &[(([u8; 32], usize), TxOutput)], outputs: Vec<TxOutput>) -> Self{
+        Transaction{
             timestamp: get_timestamp(),
             version,
             input_count: inputs.len(),
             inputs: inputs.iter().map(|((hash, index), _ouput)| TxInput{
                     prev: hash.clone(), 
                     output_index: index.clone(), 
-                    script: Script::empty()
+                    script: Script::empty(),
+                    sequence: SEQUENCE_FINAL
                 })
                 .collect(),
             output_count: outputs.len(),
-            outputs: outputs.iter().map(|(pub_key, amount)| TxOutput{
-                value: amount.clone(),
-                script: Script::P2PKHOutput(sha256(pub_key.clone()).to_vec())
-            })
-            .collect(),
-        };
+            outputs,
+            height: 0,
+            locktime: 0,
+        }
+    }
+
+    //spends P2PKH and schnorr outputs of user to outputs with any locking script
+    pub fn with_outputs(version: usize, user: User, inputs: Vec<(([u8; 32], usize), TxOutput)>, outputs: Vec<TxOutput>) -> Self{
+        let mut transaction = Self::unsigned(version, &inputs, outputs);
         for (index,(_, output)) in inputs.iter().enumerate(){
-            let sig = user.sign(hex::encode(compute_sig_hash(transaction.clone(), index, &output))).to_vec();
-            let pubkey = user.get_pub_key();
-            transaction.inputs[index].script = Script::P2PKHInput(sig, pubkey);
+            let signed = match output.script.schnorr_key(){
+                Some(_) => transaction.sign_schnorr_input(&user, index, output, SIGHASH_ALL),
+                None => transaction.sign_p2pkh_input(&user, index, output, SIGHASH_ALL)
+            };
+            signed.expect("every input can be signed with SIGHASH_ALL");
+        }
+        transaction
+    }
+
+    //sets the unlocking script of a P2PKH input, other sighash types let the rest of the transaction change afterwards
+    pub fn sign_p2pkh_input(&mut self, user: &User, input_index: usize, utxo: &TxOutput, sighash_type: u8) -> Result<()>{
+        let sig = match user.sign_input(self, input_index, utxo, sighash_type){
+            Some(sig) => sig,
+            None => bail!("sighash type {:02x} can not sign input {}", sighash_type, input_index)
+        };
+        self.inputs[input_index].script = Script::P2PKHInput(sig, user.get_pub_key());
+        Ok(())
+    }
+
+    //key path spend of a schnorr output, the unlocking script is just the signature
+    pub fn sign_schnorr_input(&mut self, user: &User, input_index: usize, utxo: &TxOutput, sighash_type: u8) -> Result<()>{
+        let sig = match user.sign_input_schnorr(self, input_index, utxo, sighash_type){
+            Some(sig) => sig,
+            None => bail!("sighash type {:02x} can not sign input {}", sighash_type, input_index)
+        };
+        self.inputs[input_index].script = Script::SchnorrInput(sig);
+        Ok(())
+    }
+
+    //pays to receive addresses, either public keys, P2SH or schnorr addresses, with an optional data carrier output
+    pub fn to_addresses(version: usize, user: User, inputs: Vec<(([u8; 32], usize), TxOutput)>, outputs: Vec<(String, usize)>, payload: Option<Vec<u8>>) -> Result<Self>{
+        let mut outputs = outputs.iter()
+            .map(|(address, amount)| Ok(TxOutput::new(*amount, address_script(address)?)))
+            .collect::<Result<Vec<TxOutput>>>()?;
+        if let Some(payload) = payload{
+            outputs.push(TxOutput::data(payload)?);
         }
+        Ok(Self::with_outputs(version, user, inputs, outputs))
+    }
+
+    //payloads of the data carrier outputs in output order
+    pub fn data_payloads(&self) -> Vec<Vec<u8>>{
+        self.outputs.iter()
+            .filter_map(|output| output.script.data_payload())
+            .collect()
+    }
+
+    //every data carrier is a single push within MAX_DATA_CARRIER_SIZE
+    pub fn has_valid_data_outputs(&self) -> bool{
+        self.outputs.iter()
+            .filter(|output| output.script.is_data_carrier())
+            .all(|output| output.script.data_payload().is_some_and(|payload| payload.len() <= MAX_DATA_CARRIER_SIZE))
+    }
+
+    //claims an HTLC output as its recipient by revealing the preimage of its hash
+    pub fn htlc_claim(version: usize, user: User, input: (([u8; 32], usize), TxOutput), preimage: Vec<u8>, outputs: Vec<TxOutput>) -> Self{
+        let mut transaction = Self::unsigned(version, std::slice::from_ref(&input), outputs);
+        let sig = user.sign_input(&transaction, 0, &input.1, SIGHASH_ALL).expect("the only input can be signed");
+        transaction.inputs[0].script = Script::HTLCClaimInput(sig, user.get_pub_key(), preimage);
         transaction
     }
+
+    //returns an HTLC output to its sender, the transaction can not be mined before the script's locktime
+    pub fn htlc_refund(version: usize, user: User, input: (([u8; 32], usize), TxOutput), outputs: Vec<TxOutput>) -> Result<Self>{
+        let locktime = match input.1.script.htlc_locktime(){
+            Some(locktime) => locktime,
+            None => bail!("output is not an HTLC")
+        };
+        let mut transaction = Self::unsigned(version, std::slice::from_ref(&input), outputs);
+        transaction.locktime = locktime;
+        //a final sequence would switch the locktime off
+        transaction.inputs[0].sequence = SEQUENCE_FINAL - 1;
+        let sig = user.sign_input(&transaction, 0, &input.1, SIGHASH_ALL).expect("the only input can be signed");
+        transaction.inputs[0].script = Script::HTLCRefundInput(sig, user.get_pub_key());
+        Ok(transaction)
+    }
+
+    //a locktime is compared to the height of the block the transaction goes in or to the median time past before it
+    pub fn is_final(&self, height: usize, time: usize) -> bool{
+        if self.locktime == 0{
+            return true
+        }
+        let cutoff = if self.locktime < LOCKTIME_THRESHOLD { height } else { time };
+        self.locktime < cutoff || self.inputs.iter().all(|input| input.sequence == SEQUENCE_FINAL)
+    }
+
+    //CHECKLOCKTIMEVERIFY, the transaction locktime has to be of the same kind and at least lock
+    fn check_locktime(&self, lock: usize, input_index: usize) -> bool{
+        (lock < LOCKTIME_THRESHOLD) == (self.locktime < LOCKTIME_THRESHOLD)
+            && lock <= self.locktime
+            && self.inputs[input_index].sequence != SEQUENCE_FINAL
+    }
+
+    //CHECKSEQUENCEVERIFY, the input has to carry a relative lock of the same kind and at least lock
+    fn check_sequence(&self, lock: u32, input_index: usize) -> bool{
+        if lock & SEQUENCE_DISABLE_FLAG != 0{
+            return true
+        }
+        let sequence = self.inputs[input_index].sequence;
+        sequence & SEQUENCE_DISABLE_FLAG == 0
+            && (lock & SEQUENCE_TYPE_FLAG) == (sequence & SEQUENCE_TYPE_FLAG)
+            && (lock & SEQUENCE_LOCKTIME_MASK) <= (sequence & SEQUENCE_LOCKTIME_MASK)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TxInput{
     prev: [u8; 32],
     output_index: usize,
-    script: Script
+    script: Script,
+    //relative lock on the spent output, see the SEQUENCE_ constants
+    #[serde(default = "final_sequence")]
+    pub sequence: u32,
+}
+
+impl TxInput{
+    //entry is the output being spent, height and time are those used for the transaction locktime
+    fn relative_lock_satisfied(&self, entry: &UtxoEntry, height: usize, time: usize) -> bool{
+        if self.sequence & SEQUENCE_DISABLE_FLAG != 0{
+            return true
+        }
+        let lock = (self.sequence & SEQUENCE_LOCKTIME_MASK) as usize;
+        if self.sequence & SEQUENCE_TYPE_FLAG != 0{
+            time >= entry.time + (lock << SEQUENCE_GRANULARITY)
+        } else {
+            height >= entry.height + lock
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct TxOutput{
-    value: usize,
+    pub value: usize,
     script: Script,
 }
 
-fn compute_sig_hash(tx: Transaction, input_index: usize, utxo: &TxOutput) -> [u8; 32]{
+impl TxOutput{
+    pub fn new(value: usize, script: Script) -> Self{
+        Self { value, script }
+    }
+
+    //unspendable output holding payload, it carries no value
+    pub fn data(payload: Vec<u8>) -> Result<Self>{
+        if payload.len() > MAX_DATA_CARRIER_SIZE{
+            bail!("data payload of {} bytes exceeds the limit of {} bytes", payload.len(), MAX_DATA_CARRIER_SIZE)
+        }
+        Ok(Self::new(0, Script::DataOutput(payload)))
+    }
+}
+
+//the byte appended to every signature picks which parts of the transaction it commits to
+//ALL signs every output, NONE no output and SINGLE only the output at the input's index
+pub const SIGHASH_ALL: u8 = 0x01;
+pub const SIGHASH_NONE: u8 = 0x02;
+pub const SIGHASH_SINGLE: u8 = 0x03;
+//combined with one of the above only the signed input is committed to so anyone can add more
+pub const SIGHASH_ANYONECANPAY: u8 = 0x80;
+
+//None for an unknown type or SINGLE without an output at input_index
+fn compute_sig_hash(tx: Transaction, input_index: usize, utxo: &TxOutput, sighash_type: u8) -> Option<[u8; 32]>{
+    let base_type = sighash_type & !SIGHASH_ANYONECANPAY;
+    if !(SIGHASH_ALL..=SIGHASH_SINGLE).contains(&base_type) || input_index >= tx.inputs.len(){
+        return None
+    }
     let mut modified_tx = tx.clone();
     for input in &mut modified_tx.inputs{
         input.script = Script::empty();
     }
-    
     modified_tx.inputs[input_index].script = utxo.script.clone();
-    sha256(modified_tx.serialize())
-
-}
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+    match base_type{
+        SIGHASH_NONE => modified_tx.outputs.clear(),
+        SIGHASH_SINGLE => {
+            if input_index >= modified_tx.outputs.len(){
+                return None
+            }
+            modified_tx.outputs.truncate(input_index + 1);
+            //outputs before ours only keep their position
+            for output in modified_tx.outputs.iter_mut().take(input_index){
+                *output = TxOutput::new(usize::MAX, Script::empty());
+            }
+        }
+        _ => {}
+    }
+    //without ALL the other inputs may update their sequence
+    if base_type != SIGHASH_ALL{
+        for (index, input) in modified_tx.inputs.iter_mut().enumerate(){
+            if index != input_index{
+                input.sequence = 0;
+            }
+        }
+    }
+    if sighash_type & SIGHASH_ANYONECANPAY != 0{
+        modified_tx.inputs = vec![modified_tx.inputs.swap_remove(input_index)];
+    }
+
+    let mut message = modified_tx.encode();
+    message.push(sighash_type);
+    Some(sha256(message))
+}
+
+//an ECDSA signature is only accepted with s in the lower half of the curve order,
+//otherwise anyone could negate s and get a second valid signature
+fn is_low_s(sig: &[u8]) -> bool{
+    match sig.split_last().map(|(_, sig)| Signature::from_slice(sig)){
+        Some(Ok(signature)) => signature.normalize_s().is_none(),
+        _ => true
+    }
+}
+
+//sig ends with its sighash type
+fn check_signature(sig: &[u8], pubkey: &[u8], tx: &Transaction, input_index: usize, utxo: &TxOutput) -> bool{
+    let (sighash_type, sig) = match sig.split_last(){
+        Some((sighash_type, sig)) => (*sighash_type, sig),
+        None => return false
+    };
+    match compute_sig_hash(tx.clone(), input_index, utxo, sighash_type){
+        Some(sighash) => verify_sig_bytes(pubkey, sighash, sig),
+        None => false
+    }
+}
+
+//sig is a 64 byte BIP340 signature over the raw sighash followed by its sighash type, pubkey an x-only key
+fn check_schnorr_signature(sig: &[u8], pubkey: &[u8], tx: &Transaction, input_index: usize, utxo: &TxOutput) -> bool{
+    let (sighash_type, sig) = match sig.split_last(){
+        Some((sighash_type, sig)) => (*sighash_type, sig),
+        None => return false
+    };
+    //from_bytes panics on anything but 32 bytes
+    if pubkey.len() != 32{
+        return false
+    }
+    let (public_key, signature) = match (schnorr::VerifyingKey::from_bytes(pubkey), schnorr::Signature::try_from(sig)){
+        (Ok(public_key), Ok(signature)) => (public_key, signature),
+        _ => return false
+    };
+    match compute_sig_hash(tx.clone(), input_index, utxo, sighash_type){
+        Some(sighash) => public_key.verify_raw(&sighash, &signature).is_ok(),
+        None => false
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Script (Vec<OpCode>);
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
@@ -388,64 +1191,474 @@ pub enum OpCode{
     SHA256,
     CHECKSIG,
     EQUALVERIFY,
+    CHECKMULTISIG,
+    //CHECKMULTISIG that fails the script instead of pushing a result
+    CHECKMULTISIGVERIFY,
+    //runs the following ops up to ELSE or ENDIF only if the popped item is true
+    IF,
+    ELSE,
+    ENDIF,
+    NOT,
+    VERIFY,
+    DROP,
+    //pushes whether the top two items are equal, EQUALVERIFY fails instead
+    EQUAL,
+    //the top item is a locktime the transaction has to be locked to at least, it is left on the stack
+    CHECKLOCKTIMEVERIFY,
+    //the top item is a relative lock the input sequence has to be at least, it is left on the stack
+    CHECKSEQUENCEVERIFY,
+    //fails the script, outputs starting with it only carry data
+    RETURN,
+    //CHECKSIG with a BIP340 schnorr signature and a 32 byte x-only key
+    CHECKSIGSCHNORR,
+}
+
+//consensus limits on every script that is run
+//encoded size of a script
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+//largest item a script may push
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+//ops other than pushes in a single script
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+//items on the stack at any point
+pub const MAX_STACK_SIZE: usize = 1000;
+
+//why a script failed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScriptError{
+    ScriptSize(usize),
+    PushSize(usize),
+    OpCount(usize),
+    StackSize,
+    StackUnderflow,
+    UnbalancedConditional,
+    EqualVerify,
+    Verify,
+    SignatureCheck,
+    //ECDSA signature that is not in canonical low S form
+    HighS,
+    MultisigCount,
+    InvalidNumber,
+    UnsatisfiedLocktime,
+    UnsatisfiedSequence,
+    Return,
+    //finished with an empty stack or a false item on top
+    FalseResult,
+    //the spending script does not end with the output script
+    NotOutputSpend,
+    NotPushOnly,
+    InvalidRedeemScript,
+}
+
+impl std::fmt::Display for ScriptError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        match self{
+            ScriptError::ScriptSize(size) => write!(f, "script of {} bytes is over the {} byte limit", size, MAX_SCRIPT_SIZE),
+            ScriptError::PushSize(size) => write!(f, "push of {} bytes is over the {} byte limit", size, MAX_SCRIPT_ELEMENT_SIZE),
+            ScriptError::OpCount(count) => write!(f, "{} ops is over the limit of {}", count, MAX_OPS_PER_SCRIPT),
+            ScriptError::StackSize => write!(f, "stack grew past {} items", MAX_STACK_SIZE),
+            ScriptError::StackUnderflow => write!(f, "not enough items on the stack"),
+            ScriptError::UnbalancedConditional => write!(f, "unbalanced IF, ELSE or ENDIF"),
+            ScriptError::EqualVerify => write!(f, "EQUALVERIFY failed"),
+            ScriptError::Verify => write!(f, "VERIFY failed"),
+            ScriptError::SignatureCheck => write!(f, "signature check failed"),
+            ScriptError::HighS => write!(f, "signature s value is not low"),
+            ScriptError::MultisigCount => write!(f, "invalid multisig key or signature count"),
+            ScriptError::InvalidNumber => write!(f, "item is not a valid number"),
+            ScriptError::UnsatisfiedLocktime => write!(f, "locktime requirement not satisfied"),
+            ScriptError::UnsatisfiedSequence => write!(f, "sequence requirement not satisfied"),
+            ScriptError::Return => write!(f, "RETURN executed"),
+            ScriptError::FalseResult => write!(f, "script finished without a true item on top"),
+            ScriptError::NotOutputSpend => write!(f, "spending script does not end with the output script"),
+            ScriptError::NotPushOnly => write!(f, "unlocking script is not push only"),
+            ScriptError::InvalidRedeemScript => write!(f, "missing or undecodable redeem script"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError{}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError>{
+    stack.pop().ok_or(ScriptError::StackUnderflow)
+}
+
+//data of ops that are all pushes
+fn push_data(ops: &[OpCode]) -> Option<Vec<Vec<u8>>>{
+    ops.iter()
+        .map(|op| match op{
+            OpCode::PUSHBYTES(data) => Some(data.clone()),
+            _ => None
+        })
+        .collect()
+}
+
+//anything but an empty or all zero item
+fn is_true(item: &[u8]) -> bool{
+    item.iter().any(|&b| b != 0)
+}
+
+//numbers are little endian without trailing zero bytes, zero is the empty item
+pub fn script_number(number: usize) -> Vec<u8>{
+    let mut bytes = number.to_le_bytes().to_vec();
+    while bytes.last() == Some(&0){
+        bytes.pop();
+    }
+    bytes
+}
+
+fn stack_number(item: &[u8]) -> Option<usize>{
+    if item.len() > 8{
+        return None
+    }
+    let mut bytes = [0u8; 8];
+    bytes[..item.len()].copy_from_slice(item);
+    usize::try_from(u64::from_le_bytes(bytes)).ok()
+}
+
+//the hash an HTLC locks to, SHA256 in scripts hashes the hex encoding of the item
+pub fn hash_lock(preimage: &[u8]) -> Vec<u8>{
+    sha256(hex::encode(preimage)).to_vec()
+}
+
+//most keys a multisig script may list, counts are pushed as a single byte
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+fn stack_count(item: &[u8]) -> Option<usize>{
+    match item{
+        [count] => Some(*count as usize),
+        _ => None
+    }
+}
+
+fn verify_sig_bytes(pubkey: &[u8], message_hash: [u8; 32], sig: &[u8]) -> bool{
+    match (VerifyingKey::from_sec1_bytes(pubkey), Signature::from_slice(sig)){
+        (Ok(public_key), Ok(signature)) => verify_sig(public_key, message_hash, signature),
+        _ => false
+    }
+}
+
+//pops n, the n keys, m and then m signatures
+//signatures have to be in the same order as their keys so each key is tried at most once
+fn check_multisig(stack: &mut Vec<Vec<u8>>, tx: &Transaction, input_index: usize, utxo: &TxOutput) -> Result<(), ScriptError>{
+    let key_count = match stack_count(&pop(stack)?){
+        Some(n) if (1..=MAX_MULTISIG_KEYS).contains(&n) => n,
+        _ => return Err(ScriptError::MultisigCount)
+    };
+    let keys = stack.split_off(stack.len().checked_sub(key_count).ok_or(ScriptError::StackUnderflow)?);
+    let required = match stack_count(&pop(stack)?){
+        Some(m) if (1..=key_count).contains(&m) => m,
+        _ => return Err(ScriptError::MultisigCount)
+    };
+    let sigs = stack.split_off(stack.len().checked_sub(required).ok_or(ScriptError::StackUnderflow)?);
+    if !sigs.iter().all(|sig| is_low_s(sig)){
+        return Err(ScriptError::HighS)
+    }
+    let mut keys = keys.iter();
+    match sigs.iter().all(|sig| keys.any(|key| check_signature(sig, key, tx, input_index, utxo))){
+        true => Ok(()),
+        false => Err(ScriptError::SignatureCheck)
+    }
+}
+
+//field order is version, timestamp, height, inputs, outputs, locktime
+impl Encode for Transaction{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        self.version.encode_to(buf);
+        self.timestamp.encode_to(buf);
+        self.height.encode_to(buf);
+        self.inputs.encode_to(buf);
+        self.outputs.encode_to(buf);
+        self.locktime.encode_to(buf);
+    }
+}
+
+impl Decode for Transaction{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        let version = usize::decode_from(reader)?;
+        let timestamp = usize::decode_from(reader)?;
+        let height = usize::decode_from(reader)?;
+        let inputs: Vec<TxInput> = Vec::decode_from(reader)?;
+        let outputs: Vec<TxOutput> = Vec::decode_from(reader)?;
+        let locktime = usize::decode_from(reader)?;
+        Ok(Self { 
+            timestamp, 
+            version, 
+            input_count: inputs.len(), 
+            inputs, 
+            output_count: outputs.len(), 
+            outputs, 
+            height,
+            locktime
+        })
+    }
+}
+
+impl Encode for TxInput{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        self.prev.encode_to(buf);
+        self.output_index.encode_to(buf);
+        self.script.encode_to(buf);
+        self.sequence.encode_to(buf);
+    }
+}
+
+impl Decode for TxInput{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        Ok(Self { 
+            prev: Decode::decode_from(reader)?, 
+            output_index: Decode::decode_from(reader)?, 
+            script: Decode::decode_from(reader)?,
+            sequence: Decode::decode_from(reader)?
+        })
+    }
+}
+
+impl Encode for TxOutput{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        self.value.encode_to(buf);
+        self.script.encode_to(buf);
+    }
+}
+
+impl Decode for TxOutput{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        Ok(Self { 
+            value: Decode::decode_from(reader)?, 
+            script: Decode::decode_from(reader)? 
+        })
+    }
+}
+
+impl Encode for Script{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        self.0.encode_to(buf);
+    }
+}
+
+impl Decode for Script{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        Ok(Self(Vec::decode_from(reader)?))
+    }
+}
+
+//each opcode is a single byte, pushes are followed by their length prefixed data
+impl Encode for OpCode{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        match self{
+            OpCode::PUSHBYTES(data) => {
+                buf.push(0x4c);
+                data.encode_to(buf);
+            }
+            OpCode::DUP => buf.push(0x76),
+            OpCode::SHA256 => buf.push(0xa8),
+            OpCode::CHECKSIG => buf.push(0xac),
+            OpCode::EQUALVERIFY => buf.push(0x88),
+            OpCode::CHECKMULTISIG => buf.push(0xae),
+            OpCode::CHECKMULTISIGVERIFY => buf.push(0xaf),
+            OpCode::IF => buf.push(0x63),
+            OpCode::ELSE => buf.push(0x67),
+            OpCode::ENDIF => buf.push(0x68),
+            OpCode::NOT => buf.push(0x91),
+            OpCode::VERIFY => buf.push(0x69),
+            OpCode::DROP => buf.push(0x75),
+            OpCode::EQUAL => buf.push(0x87),
+            OpCode::CHECKLOCKTIMEVERIFY => buf.push(0xb1),
+            OpCode::CHECKSEQUENCEVERIFY => buf.push(0xb2),
+            OpCode::RETURN => buf.push(0x6a),
+            OpCode::CHECKSIGSCHNORR => buf.push(0xba),
+        }
+    }
+}
+
+impl Decode for OpCode{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        Ok(match u8::decode_from(reader)?{
+            0x4c => OpCode::PUSHBYTES(Vec::decode_from(reader)?),
+            0x76 => OpCode::DUP,
+            0xa8 => OpCode::SHA256,
+            0xac => OpCode::CHECKSIG,
+            0x88 => OpCode::EQUALVERIFY,
+            0xae => OpCode::CHECKMULTISIG,
+            0xaf => OpCode::CHECKMULTISIGVERIFY,
+            0x63 => OpCode::IF,
+            0x67 => OpCode::ELSE,
+            0x68 => OpCode::ENDIF,
+            0x91 => OpCode::NOT,
+            0x69 => OpCode::VERIFY,
+            0x75 => OpCode::DROP,
+            0x87 => OpCode::EQUAL,
+            0xb1 => OpCode::CHECKLOCKTIMEVERIFY,
+            0xb2 => OpCode::CHECKSEQUENCEVERIFY,
+            0x6a => OpCode::RETURN,
+            0xba => OpCode::CHECKSIGSCHNORR,
+            code => bail!("unknown opcode {:02x}", code)
+        })
+    }
 }
 
 impl Script{
     pub fn empty() -> Self{
         Self(vec![])
     }
-    pub fn validate_script(&self, tx: &Transaction, input_index: usize, utxo: &TxOutput) -> bool{
-        let mut stack: Vec<Vec<u8>> = Vec::new();
+    //self is the unlocking script followed by the locking script of utxo, the unlocking script may only
+    //push data and the locking script runs on those pushes, so no op of the spender can change its flow
+    pub fn validate_script(&self, tx: &Transaction, input_index: usize, utxo: &TxOutput) -> Result<(), ScriptError>{
+        self.check_limits()?;
+        let unlocking = match self.0.len().checked_sub(utxo.script.0.len()){
+            Some(split) if self.0[split..] == utxo.script.0[..] => &self.0[..split],
+            _ => return Err(ScriptError::NotOutputSpend)
+        };
+        let mut pushes = push_data(unlocking).ok_or(ScriptError::NotPushOnly)?;
+        if pushes.len() > MAX_STACK_SIZE{
+            return Err(ScriptError::StackSize)
+        }
+        let mut stack = pushes.clone();
+        utxo.script.execute(&mut stack, tx, input_index, utxo)?;
+        if utxo.script.p2sh_script_hash().is_none(){
+            return Ok(())
+        }
+        //P2SH, the last push is the redeem script which then has to succeed on the pushes before it
+        let redeem_script = pushes.pop()
+            .and_then(|bytes| Script::decode(&bytes).ok())
+            .ok_or(ScriptError::InvalidRedeemScript)?;
+        redeem_script.execute(&mut pushes, tx, input_index, utxo)
+    }
+
+    //size, op count and push sizes are checked up front, including ops in branches that are skipped
+    fn check_limits(&self) -> Result<(), ScriptError>{
+        let size = self.encode().len();
+        if size > MAX_SCRIPT_SIZE{
+            return Err(ScriptError::ScriptSize(size))
+        }
+        let mut op_count = 0;
+        for op in self.0.iter(){
+            match op{
+                OpCode::PUSHBYTES(data) if data.len() > MAX_SCRIPT_ELEMENT_SIZE => return Err(ScriptError::PushSize(data.len())),
+                OpCode::PUSHBYTES(_) => {}
+                _ => op_count += 1
+            }
+        }
+        if op_count > MAX_OPS_PER_SCRIPT{
+            return Err(ScriptError::OpCount(op_count))
+        }
+        Ok(())
+    }
+
+    //runs the ops on stack, succeeds if they all do and leave a true item on top
+    fn execute(&self, stack: &mut Vec<Vec<u8>>, tx: &Transaction, input_index: usize, utxo: &TxOutput) -> Result<(), ScriptError>{
+        self.check_limits()?;
+        //one entry per open IF, ops only run while every entry is true
+        let mut branches: Vec<bool> = Vec::new();
         for op in self.0.iter(){
+            let executing = branches.iter().all(|&branch| branch);
             match op{
+                OpCode::IF => {
+                    let condition = match executing{
+                        false => false,
+                        true => is_true(&pop(stack)?)
+                    };
+                    //a skipped IF is still pushed so it gets matched by its ENDIF
+                    branches.push(condition);
+                }
+                OpCode::ELSE => {
+                    match branches.last_mut(){
+                        Some(branch) => *branch = !*branch,
+                        None => return Err(ScriptError::UnbalancedConditional)
+                    }
+                }
+                OpCode::ENDIF => {
+                    if branches.pop().is_none(){
+                        return Err(ScriptError::UnbalancedConditional)
+                    }
+                }
+                _ if !executing => {}
                 OpCode::PUSHBYTES(data) => {
                     stack.push(data.clone());
                 }
                 OpCode::DUP => {
-                    if let Some(top) = stack.last(){
-                        stack.push(top.clone());
-                    }
-                    else{
-                        return false
-                    }
+                    let top = stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                    stack.push(top);
                 }
                 OpCode::SHA256 => {
-                    if let Some(top) = stack.pop(){
-                        let x = sha256(String::from_utf8_lossy(&top.clone()).to_string()).to_vec();
-                        stack.push(x.clone());
-                    }
+                    //hashes the hex encoding to match how pubkey hashes are derived
+                    let top = pop(stack)?;
+                    stack.push(sha256(hex::encode(&top)).to_vec());
                 }
                 OpCode::EQUALVERIFY => {
-                    if let Some(x1) = stack.pop() && let Some(x2) = stack.pop(){
-                        if x1 != x2{
-                            return false
-                        }
+                    if pop(stack)? != pop(stack)?{
+                        return Err(ScriptError::EqualVerify)
                     }
-                    else{return false}
                 }
                 OpCode::CHECKSIG => {
-                    let sighash = compute_sig_hash(tx.clone(), input_index, utxo);
-                    let pk = match stack.pop(){
-                        Some(pk) => pk,
-                        None => return false
-                    };
-                    let sig = match stack.pop(){
-                        Some(sig) => sig,
-                        None => return false
-                    };
-
-                    let public_key = VerifyingKey::from_sec1_bytes(&pk).unwrap();
-                    let signature= Signature::from_slice(&sig).unwrap();
-                    match verify_sig(public_key, sighash, signature){
-                        true => stack.push(vec![1]),
-                        false => return false
+                    let pk = pop(stack)?;
+                    let sig = pop(stack)?;
+                    if !is_low_s(&sig){
+                        return Err(ScriptError::HighS)
+                    }
+                    if !check_signature(&sig, &pk, tx, input_index, utxo){
+                        return Err(ScriptError::SignatureCheck)
+                    }
+                    stack.push(vec![1]);
+                }
+                OpCode::CHECKSIGSCHNORR => {
+                    let pk = pop(stack)?;
+                    let sig = pop(stack)?;
+                    if !check_schnorr_signature(&sig, &pk, tx, input_index, utxo){
+                        return Err(ScriptError::SignatureCheck)
+                    }
+                    stack.push(vec![1]);
+                }
+                OpCode::CHECKMULTISIG => {
+                    check_multisig(stack, tx, input_index, utxo)?;
+                    stack.push(vec![1]);
+                }
+                OpCode::CHECKMULTISIGVERIFY => {
+                    check_multisig(stack, tx, input_index, utxo)?;
+                }
+                OpCode::NOT => {
+                    let top = pop(stack)?;
+                    stack.push(if is_true(&top) { vec![] } else { vec![1] });
+                }
+                OpCode::VERIFY => {
+                    if !is_true(&pop(stack)?){
+                        return Err(ScriptError::Verify)
+                    }
+                }
+                OpCode::DROP => {
+                    pop(stack)?;
+                }
+                OpCode::EQUAL => {
+                    let equal = pop(stack)? == pop(stack)?;
+                    stack.push(if equal { vec![1] } else { vec![] });
+                }
+                OpCode::CHECKLOCKTIMEVERIFY => {
+                    let top = stack.last().ok_or(ScriptError::StackUnderflow)?;
+                    let lock = stack_number(top).ok_or(ScriptError::InvalidNumber)?;
+                    if !tx.check_locktime(lock, input_index){
+                        return Err(ScriptError::UnsatisfiedLocktime)
+                    }
+                }
+                OpCode::CHECKSEQUENCEVERIFY => {
+                    let top = stack.last().ok_or(ScriptError::StackUnderflow)?;
+                    let lock = stack_number(top)
+                        .and_then(|lock| u32::try_from(lock).ok())
+                        .ok_or(ScriptError::InvalidNumber)?;
+                    if !tx.check_sequence(lock, input_index){
+                        return Err(ScriptError::UnsatisfiedSequence)
                     }
                 }
+                OpCode::RETURN => return Err(ScriptError::Return),
             }
+            if stack.len() > MAX_STACK_SIZE{
+                return Err(ScriptError::StackSize)
+            }
+        }
+        if !branches.is_empty(){
+            return Err(ScriptError::UnbalancedConditional)
         }
-        match stack.last() {
-            Some(top) => top.iter().any(|&b| b != 0),
-            None => false
+        match stack.last(){
+            Some(top) if is_true(top) => Ok(()),
+            _ => Err(ScriptError::FalseResult)
         }
     }
 
@@ -475,8 +1688,8 @@ impl Script{
     }
 
     pub fn P2PKHOutput_pubkey_hash(&self) -> Option<Vec<u8>>{
-        match self.0.get(2){
-            Some(OpCode::PUSHBYTES(hash)) => {
+        match self.0.as_slice(){
+            [OpCode::DUP, OpCode::SHA256, OpCode::PUSHBYTES(hash), OpCode::EQUALVERIFY, OpCode::CHECKSIG] => {
                 Some(hash.clone())
             }
             _ => {
@@ -484,12 +1697,170 @@ impl Script{
             }
         }
     }
+
+    //signatures ordered like the keys they belong to
+    #[allow(non_snake_case)]
+    pub fn MultisigInput(sigs: Vec<Vec<u8>>) -> Self{
+        Self(sigs.into_iter().map(OpCode::PUSHBYTES).collect())
+    }
+
+    //spendable with signatures from required of the pubkeys
+    #[allow(non_snake_case)]
+    pub fn MultisigOutput(required: usize, pubkeys: Vec<Vec<u8>>) -> Self{
+        let key_count = pubkeys.len();
+        let mut ops = vec![OpCode::PUSHBYTES(vec![required as u8])];
+        ops.extend(pubkeys.into_iter().map(OpCode::PUSHBYTES));
+        ops.push(OpCode::PUSHBYTES(vec![key_count as u8]));
+        ops.push(OpCode::CHECKMULTISIG);
+        Self(ops)
+    }
+
+    #[allow(non_snake_case)]
+    pub fn DataOutput(payload: Vec<u8>) -> Self{
+        Self(vec![
+            OpCode::RETURN,
+            OpCode::PUSHBYTES(payload),
+        ])
+    }
+
+    pub fn is_data_carrier(&self) -> bool{
+        self.0.first() == Some(&OpCode::RETURN)
+    }
+
+    //payload of a script built by DataOutput
+    pub fn data_payload(&self) -> Option<Vec<u8>>{
+        match self.0.as_slice(){
+            [OpCode::RETURN, OpCode::PUSHBYTES(payload)] => Some(payload.clone()),
+            _ => None
+        }
+    }
+
+    //hash a P2SH output commits to, SHA256 in scripts hashes the hex encoding of the pushed script
+    pub fn script_hash(&self) -> Vec<u8>{
+        hash_lock(&self.encode())
+    }
+
+    #[allow(non_snake_case)]
+    pub fn P2SHOutput(script_hash: Vec<u8>) -> Self{
+        Self(vec![
+            OpCode::SHA256,
+            OpCode::PUSHBYTES(script_hash),
+            OpCode::EQUAL,
+        ])
+    }
+
+    //pushes satisfying the redeem script followed by the redeem script itself
+    #[allow(non_snake_case)]
+    pub fn P2SHInput(pushes: Vec<Vec<u8>>, redeem_script: &Script) -> Self{
+        let mut ops: Vec<OpCode> = pushes.into_iter().map(OpCode::PUSHBYTES).collect();
+        ops.push(OpCode::PUSHBYTES(redeem_script.encode()));
+        Self(ops)
+    }
+
+    pub fn p2sh_script_hash(&self) -> Option<Vec<u8>>{
+        match self.0.as_slice(){
+            [OpCode::SHA256, OpCode::PUSHBYTES(hash), OpCode::EQUAL] => Some(hash.clone()),
+            _ => None
+        }
+    }
+
+    //pays straight to an x-only key, spent with a schnorr signature alone
+    #[allow(non_snake_case)]
+    pub fn SchnorrOutput(xonly_key: Vec<u8>) -> Self{
+        Self(vec![
+            OpCode::PUSHBYTES(xonly_key),
+            OpCode::CHECKSIGSCHNORR,
+        ])
+    }
+
+    #[allow(non_snake_case)]
+    pub fn SchnorrInput(sig: Vec<u8>) -> Self{
+        Self(vec![OpCode::PUSHBYTES(sig)])
+    }
+
+    pub fn schnorr_key(&self) -> Option<Vec<u8>>{
+        match self.0.as_slice(){
+            [OpCode::PUSHBYTES(key), OpCode::CHECKSIGSCHNORR] => Some(key.clone()),
+            _ => None
+        }
+    }
+
+    //pays to recipient_hash with the preimage of payment_hash, or back to sender_hash from locktime on
+    #[allow(non_snake_case)]
+    pub fn HTLCOutput(payment_hash: Vec<u8>, recipient_hash: Vec<u8>, sender_hash: Vec<u8>, locktime: usize) -> Self{
+        Self(vec![
+            OpCode::IF,
+            OpCode::SHA256,
+            OpCode::PUSHBYTES(payment_hash),
+            OpCode::EQUALVERIFY,
+            OpCode::DUP,
+            OpCode::SHA256,
+            OpCode::PUSHBYTES(recipient_hash),
+            OpCode::ELSE,
+            OpCode::PUSHBYTES(script_number(locktime)),
+            OpCode::CHECKLOCKTIMEVERIFY,
+            OpCode::DROP,
+            OpCode::DUP,
+            OpCode::SHA256,
+            OpCode::PUSHBYTES(sender_hash),
+            OpCode::ENDIF,
+            OpCode::EQUALVERIFY,
+            OpCode::CHECKSIG,
+        ])
+    }
+
+    #[allow(non_snake_case)]
+    pub fn HTLCClaimInput(sig: Vec<u8>, pubkey: Vec<u8>, preimage: Vec<u8>) -> Self{
+        Self(vec![
+            OpCode::PUSHBYTES(sig),
+            OpCode::PUSHBYTES(pubkey),
+            OpCode::PUSHBYTES(preimage),
+            OpCode::PUSHBYTES(vec![1]),
+        ])
+    }
+
+    #[allow(non_snake_case)]
+    pub fn HTLCRefundInput(sig: Vec<u8>, pubkey: Vec<u8>) -> Self{
+        Self(vec![
+            OpCode::PUSHBYTES(sig),
+            OpCode::PUSHBYTES(pubkey),
+            OpCode::PUSHBYTES(vec![]),
+        ])
+    }
+
+    //refund locktime of a script built by HTLCOutput
+    pub fn htlc_locktime(&self) -> Option<usize>{
+        match self.0.as_slice(){
+            [
+                OpCode::IF, OpCode::SHA256, OpCode::PUSHBYTES(_), OpCode::EQUALVERIFY, OpCode::DUP, OpCode::SHA256, OpCode::PUSHBYTES(_),
+                OpCode::ELSE, OpCode::PUSHBYTES(locktime), OpCode::CHECKLOCKTIMEVERIFY, OpCode::DROP, OpCode::DUP, OpCode::SHA256, OpCode::PUSHBYTES(_),
+                OpCode::ENDIF, OpCode::EQUALVERIFY, OpCode::CHECKSIG
+            ] => stack_number(locktime),
+            _ => None
+        }
+    }
+
+    //required signatures and keys of a script built by MultisigOutput
+    pub fn multisig_keys(&self) -> Option<(usize, Vec<Vec<u8>>)>{
+        let (last, ops) = self.0.split_last()?;
+        if *last != OpCode::CHECKMULTISIG || ops.len() < 2{
+            return None
+        }
+        let pushes = push_data(ops)?;
+        let required = stack_count(&pushes[0])?;
+        let key_count = stack_count(&pushes[pushes.len() - 1])?;
+        let keys = pushes[1..pushes.len() - 1].to_vec();
+        if keys.len() != key_count || key_count > MAX_MULTISIG_KEYS || !(1..=key_count).contains(&required){
+            return None
+        }
+        Some((required, keys))
+    }
 }
 
 #[cfg(test)]
 #[allow(non_snake_case)]
 mod tests{
-    use crate::messages::{Mempool, TransactionWithFee};
+    use crate::{chain::MAX_BLOCK_SIZE, messages::Mempool};
 
     use super::*;
 
@@ -518,11 +1889,13 @@ mod tests{
             version: 10,
             input_count: 1,
             inputs: vec![TxInput{
-                prev: sha256("Hello World".to_string()),
+                prev: sha256("Hello World"),
                 output_index: 1,
                 script: utxo.script.clone(),
+                sequence: SEQUENCE_FINAL,
             }],
             output_count: 1,
+            height: 0,
             outputs: vec![TxOutput{
                 value: 10,
                 script: Script(vec![
@@ -532,9 +1905,10 @@ mod tests{
                     OpCode::EQUALVERIFY,
                     OpCode::CHECKSIG,
                 ])
-            }]
+            }],
+            locktime: 0,
         };
-        let sig = B.sign(tx.serialize()).to_vec();
+        let sig = B.sign_input(&tx, 0, &utxo, SIGHASH_ALL).unwrap();
         let unlocking_script = Script(vec![
             OpCode::PUSHBYTES(sig),
             OpCode::PUSHBYTES(B.get_pub_key()),
@@ -542,21 +1916,641 @@ mod tests{
         tx.inputs[0].script = unlocking_script.clone();
 
         let script = Script::concat(unlocking_script.clone(), utxo.script.clone());
-        assert_eq!(script.validate_script(&tx, 0, &utxo), true)
+        assert_eq!(script.validate_script(&tx, 0, &utxo), Ok(()))
+
+    }
+
+    #[test]
+    fn spend_validates(){
+        let A = User::new();
+        let B = User::new();
+        let mut utxos = UTXOS::new();
+        let reward = Transaction::reward(10, A.get_pub_key(), 1, 1);
+        let hash = reward.txid();
+        utxos.add_transaction(reward.clone(), 1, 0).unwrap();
 
+        let inputs = vec![((hash, 0), reward.outputs[0].clone())];
+        let tx = Transaction::new(1, A.clone(), inputs.clone(), vec![(hex::encode(B.get_pub_key()), 9)]);
+        assert!(utxos.validate_transaction(tx.clone(), 2, 0, 1).is_ok());
+
+        //the coinbase is not mature yet
+        assert!(utxos.validate_transaction(tx, 2, 0, 2).is_err());
+
+        //B can not spend A's output
+        let tx = Transaction::new(1, B.clone(), inputs, vec![(hex::encode(B.get_pub_key()), 9)]);
+        assert!(utxos.validate_transaction(tx, 2, 0, 1).is_err());
+    }
+
+    #[test]
+    fn transaction_encoding_vector(){
+        let tx = Transaction{
+            timestamp: 1767225600,
+            version: 1,
+            input_count: 1,
+            inputs: vec![TxInput{
+                prev: [0x11; 32],
+                output_index: 2,
+                script: Script::P2PKHInput(vec![0xaa, 0xbb], vec![0x02, 0x03]),
+                sequence: 0xffff_fffe,
+            }],
+            output_count: 1,
+            outputs: vec![TxOutput{
+                value: 50,
+                script: Script::P2PKHOutput(vec![0xcc])
+            }],
+            height: 0,
+            locktime: 600,
+        };
+        let expected = concat!(
+            "0100000000000000",
+            "00b9556900000000",
+            "0000000000000000",
+            "01", "1111111111111111111111111111111111111111111111111111111111111111", "0200000000000000",
+            "02", "4c02aabb", "4c020203", "feffffff",
+            "01", "3200000000000000",
+            "05", "76a84c01cc88ac",
+            "5802000000000000",
+        );
+        assert_eq!(hex::encode(tx.encode()), expected);
+        assert_eq!(tx.size(), expected.len() / 2);
+        assert_eq!(hex::encode(tx.full_hash()), "1ea17853f68c29cca0518109c2b19104bb4aa5198d5f0e027115e36b8d93a36d");
+        //the txid is the same encoding with an empty unlocking script
+        assert_eq!(hex::encode(tx.txid()), "243039a4450cc5ea00acd64daebe3901c358ea5c80ca3291c2314fe32844a67d");
+        assert_eq!(Transaction::decode(&tx.encode()).unwrap(), tx);
+
+        //unknown opcodes and trailing bytes are rejected, the last opcode sits before the locktime
+        let mut bad = tx.encode();
+        let last = bad.len() - 9;
+        bad[last] = 0xff;
+        assert!(Transaction::decode(&bad).is_err());
+        let mut long = tx.encode();
+        long.push(0);
+        assert!(Transaction::decode(&long).is_err());
+    }
+
+    #[test]
+    fn wallet_separates_immature_funds(){
+        let user = User::new();
+        let mut wallet = Wallet::new(user.get_pub_key());
+        for height in 1..=3{
+            let coinbase = Transaction::reward(10, user.get_pub_key(), 1, height);
+            wallet.update(Block::new(vec![coinbase], [0u8; 32], 3, 1, height));
+        }
+        assert_eq!(wallet.value, 30);
+        assert_eq!(wallet.balance(4, 2), (20, 10));
+        assert_eq!(wallet.balance(4, 4), (0, 30));
+        assert!(wallet.get_inputs(15, 4, 2).is_some());
+        assert!(wallet.get_inputs(25, 4, 2).is_none());
+    }
+
+    fn multisig_spend(users: &[User], required: usize) -> (UTXOS, PartialTransaction){
+        let pubkeys = users.iter().map(|user| user.get_pub_key()).collect();
+        let funding = Transaction::unsigned(1, &[], vec![TxOutput::new(10, Script::MultisigOutput(required, pubkeys))]);
+        let mut utxos = UTXOS::new();
+        utxos.add_transaction(funding.clone(), 1, 0).unwrap();
+        let inputs = vec![((funding.txid(), 0), funding.outputs[0].clone())];
+        let outputs = vec![TxOutput::new(9, Script::P2PKHOutput(users[0].get_pub_key_hash()))];
+        (utxos, PartialTransaction::new(1, inputs, outputs).unwrap())
+    }
+
+    #[test]
+    fn multisig_needs_enough_ordered_signatures(){
+        let users: Vec<User> = (0..3).map(|_| User::new()).collect();
+        let (utxos, mut partial) = multisig_spend(&users, 2);
+        let utxo = partial.spent[0].clone();
+        assert_eq!(utxo.script.multisig_keys().unwrap().0, 2);
+        assert_eq!(utxo.script.encode()[0..3], [0x06, 0x4c, 0x01]);
+
+        assert_eq!(partial.sign(&User::new()), 0);
+        assert_eq!(partial.sign(&users[2]), 1);
+        assert_eq!(partial.missing(), 1);
+        assert!(partial.finalize().is_none());
+        partial.sign(&users[0]);
+        let tx = partial.finalize().unwrap();
+        assert!(utxos.validate_transaction(tx.clone(), 2, 0, 1).is_ok());
+
+        //signatures out of key order do not match
+        let mut swapped = tx.clone();
+        swapped.inputs[0].script.0.reverse();
+        assert!(utxos.validate_transaction(swapped, 2, 0, 1).is_err());
+
+        //one signature is not enough, not even twice
+        let mut short = tx.clone();
+        short.inputs[0].script.0.pop();
+        assert!(utxos.validate_transaction(short.clone(), 2, 0, 1).is_err());
+        let sig = short.inputs[0].script.0[0].clone();
+        short.inputs[0].script.0.push(sig);
+        assert!(utxos.validate_transaction(short, 2, 0, 1).is_err());
+
+        //the verify variant leaves nothing on the stack so the script needs a result after it
+        let mut locking = utxo.script.clone();
+        *locking.0.last_mut().unwrap() = OpCode::CHECKMULTISIGVERIFY;
+        let verify = TxOutput::new(10, locking.clone());
+        let script = Script::concat(tx.inputs[0].script.clone(), locking.clone());
+        assert!(script.validate_script(&tx, 0, &verify).is_err());
+        locking.0.push(OpCode::PUSHBYTES(vec![1]));
+        let verify = TxOutput::new(10, locking.clone());
+        let error = PartialTransaction::new(1, vec![(([1; 32], 0), verify.clone())], tx.outputs.clone()).unwrap_err();
+        assert!(error.to_string().contains("not a multisig"));
+        let sigs = users[..2].iter().map(|user| user.sign_input(&tx, 0, &verify, SIGHASH_ALL).unwrap()).collect();
+        let script = Script::concat(Script::MultisigInput(sigs), locking);
+        assert!(script.validate_script(&tx, 0, &verify).is_ok());
+    }
+
+    #[test]
+    fn cosigners_combine_partial_signatures(){
+        let users: Vec<User> = (0..3).map(|_| User::new()).collect();
+        let (utxos, mut first) = multisig_spend(&users, 2);
+        let json = serde_json::to_string(&first).unwrap();
+        let mut second: PartialTransaction = serde_json::from_str(&json).unwrap();
+        first.sign(&users[1]);
+        second.sign(&users[2]);
+        first.combine(&second).unwrap();
+        assert!(first.is_complete());
+        assert!(utxos.validate_transaction(first.finalize().unwrap(), 2, 0, 1).is_ok());
+
+        let (_, other) = multisig_spend(&users, 3);
+        assert!(first.combine(&other).is_err());
+    }
+
+    #[test]
+    fn wallet_tracks_multisig_outputs(){
+        let users: Vec<User> = (0..2).map(|_| User::new()).collect();
+        let script = Script::MultisigOutput(2, users.iter().map(|user| user.get_pub_key()).collect());
+        let funding = Transaction::unsigned(1, &[], vec![TxOutput::new(10, script.clone())]);
+        let block = Block::new(vec![Transaction::reward(10, users[0].get_pub_key(), 1, 1), funding.clone()], [0u8; 32], 3, 1, 1);
+        let mut wallet = Wallet::new(users[1].get_pub_key());
+        wallet.update(block.clone());
+
+        //multisig funds are not part of the balance
+        assert_eq!(wallet.balance(2, 1), (0, 0));
+        assert_eq!(wallet.multisig_outputs().len(), 1);
+        let (inputs, value) = wallet.get_multisig_inputs(&script, 5, 2, 1).unwrap();
+        assert_eq!((inputs[0].0, value), ((funding.txid(), 0), 10));
+        assert!(wallet.get_multisig_inputs(&Script::MultisigOutput(1, vec![users[1].get_pub_key()]), 5, 2, 1).is_none());
+
+        wallet.revert(&block, &BlockUndo::new());
+        assert!(wallet.multisig_outputs().is_empty());
+    }
+
+    //runs ops as the whole script of the only input of a transaction with the given locks
+    fn run_script(ops: Vec<OpCode>, locktime: usize, sequence: u32) -> bool{
+        let utxo = TxOutput::new(10, Script(ops));
+        let mut tx = Transaction::unsigned(1, &[(([1; 32], 0), utxo.clone())], Vec::new());
+        tx.locktime = locktime;
+        tx.inputs[0].sequence = sequence;
+        utxo.script.validate_script(&tx, 0, &utxo).is_ok()
+    }
+
+    #[test]
+    fn conditional_opcodes(){
+        use OpCode::*;
+        let t = || PUSHBYTES(vec![1]);
+        let f = || PUSHBYTES(vec![]);
+        assert!(run_script(vec![t(), IF, t(), ELSE, f(), ENDIF], 0, SEQUENCE_FINAL));
+        assert!(!run_script(vec![f(), IF, t(), ELSE, f(), ENDIF], 0, SEQUENCE_FINAL));
+        assert!(run_script(vec![f(), IF, f(), ELSE, t(), ENDIF], 0, SEQUENCE_FINAL));
+        //nested branches inside a skipped one are skipped as well
+        assert!(run_script(vec![f(), IF, t(), IF, f(), ENDIF, ELSE, t(), ENDIF], 0, SEQUENCE_FINAL));
+        //and they do not consume the stack
+        assert!(run_script(vec![t(), f(), IF, IF, ENDIF, ENDIF], 0, SEQUENCE_FINAL));
+        //unbalanced branches fail
+        assert!(!run_script(vec![t(), IF, t()], 0, SEQUENCE_FINAL));
+        assert!(!run_script(vec![t(), ENDIF], 0, SEQUENCE_FINAL));
+        assert!(!run_script(vec![ELSE, t()], 0, SEQUENCE_FINAL));
+
+        assert!(run_script(vec![f(), NOT], 0, SEQUENCE_FINAL));
+        assert!(!run_script(vec![PUSHBYTES(vec![0, 0]), NOT, NOT], 0, SEQUENCE_FINAL));
+        assert!(run_script(vec![t(), t(), VERIFY], 0, SEQUENCE_FINAL));
+        assert!(!run_script(vec![t(), f(), VERIFY], 0, SEQUENCE_FINAL));
+        assert!(run_script(vec![t(), f(), DROP], 0, SEQUENCE_FINAL));
+        assert!(run_script(vec![PUSHBYTES(hash_lock(b"secret")), PUSHBYTES(b"secret".to_vec()), SHA256, EQUAL], 0, SEQUENCE_FINAL));
+        assert!(!run_script(vec![PUSHBYTES(hash_lock(b"secret")), PUSHBYTES(b"guess".to_vec()), SHA256, EQUAL], 0, SEQUENCE_FINAL));
+    }
+
+    #[test]
+    fn timelock_opcodes(){
+        use OpCode::*;
+        let cltv = |lock: usize| vec![PUSHBYTES(script_number(lock)), CHECKLOCKTIMEVERIFY];
+        assert!(run_script(cltv(100), 100, 0));
+        assert!(!run_script(cltv(101), 100, 0));
+        //heights and times do not compare
+        assert!(!run_script(cltv(100), LOCKTIME_THRESHOLD + 100, 0));
+        //a final input switches the transaction locktime off
+        assert!(!run_script(cltv(100), 100, SEQUENCE_FINAL));
+
+        let csv = |lock: u32| vec![PUSHBYTES(script_number(lock as usize)), CHECKSEQUENCEVERIFY];
+        assert!(run_script(csv(5), 0, 5));
+        assert!(!run_script(csv(6), 0, 5));
+        assert!(!run_script(csv(5), 0, 5 | SEQUENCE_TYPE_FLAG));
+        assert!(!run_script(csv(5), 0, 5 | SEQUENCE_DISABLE_FLAG));
+        assert!(run_script(csv(SEQUENCE_DISABLE_FLAG), 0, SEQUENCE_FINAL));
+    }
+
+    #[test]
+    fn transaction_locks_are_enforced(){
+        let user = User::new();
+        let mut utxos = UTXOS::new();
+        let funding = Transaction::unsigned(1, &[], vec![TxOutput::new(10, Script::P2PKHOutput(user.get_pub_key_hash()))]);
+        utxos.add_transaction(funding.clone(), 10, 5000).unwrap();
+        let inputs = vec![((funding.txid(), 0), funding.outputs[0].clone())];
+        let signed = |locktime: usize, sequence: u32|{
+            let mut tx = Transaction::unsigned(1, &inputs, vec![TxOutput::new(9, Script::P2PKHOutput(user.get_pub_key_hash()))]);
+            tx.locktime = locktime;
+            tx.inputs[0].sequence = sequence;
+            let sig = user.sign_input(&tx, 0, &inputs[0].1, SIGHASH_ALL).unwrap();
+            tx.inputs[0].script = Script::P2PKHInput(sig, user.get_pub_key());
+            tx
+        };
+
+        //absolute locks by height and by median time past
+        assert!(utxos.validate_transaction(signed(20, 0), 20, 6000, 1).is_err());
+        assert!(utxos.validate_transaction(signed(20, 0), 21, 6000, 1).is_ok());
+        assert!(utxos.validate_transaction(signed(20, SEQUENCE_FINAL), 20, 6000, 1).is_ok());
+        assert!(utxos.validate_transaction(signed(LOCKTIME_THRESHOLD + 10, 0), 30, LOCKTIME_THRESHOLD + 10, 1).is_err());
+        assert!(utxos.validate_transaction(signed(LOCKTIME_THRESHOLD + 10, 0), 30, LOCKTIME_THRESHOLD + 11, 1).is_ok());
+
+        //relative locks count from the height and time the output was created at
+        assert!(utxos.validate_transaction(signed(0, 3), 12, 6000, 1).is_err());
+        assert!(utxos.validate_transaction(signed(0, 3), 13, 6000, 1).is_ok());
+        let two_units = SEQUENCE_TYPE_FLAG | 2;
+        assert!(utxos.validate_transaction(signed(0, two_units), 30, 5000 + 1023, 1).is_err());
+        assert!(utxos.validate_transaction(signed(0, two_units), 30, 5000 + 1024, 1).is_ok());
+    }
+
+    #[test]
+    fn htlc_claim_and_refund(){
+        let sender = User::new();
+        let recipient = User::new();
+        let preimage = b"payment preimage".to_vec();
+        let script = Script::HTLCOutput(hash_lock(&preimage), recipient.get_pub_key_hash(), sender.get_pub_key_hash(), 50);
+        assert_eq!(script.htlc_locktime(), Some(50));
+        let funding = Transaction::unsigned(1, &[], vec![TxOutput::new(10, script)]);
+        let mut utxos = UTXOS::new();
+        utxos.add_transaction(funding.clone(), 1, 0).unwrap();
+        let input = ((funding.txid(), 0), funding.outputs[0].clone());
+        let outputs = |user: &User| vec![TxOutput::new(9, Script::P2PKHOutput(user.get_pub_key_hash()))];
+
+        let claim = Transaction::htlc_claim(1, recipient.clone(), input.clone(), preimage.clone(), outputs(&recipient));
+        assert!(utxos.validate_transaction(claim, 2, 0, 1).is_ok());
+        let wrong_preimage = Transaction::htlc_claim(1, recipient.clone(), input.clone(), b"guess".to_vec(), outputs(&recipient));
+        assert!(utxos.validate_transaction(wrong_preimage, 2, 0, 1).is_err());
+        let sender_claim = Transaction::htlc_claim(1, sender.clone(), input.clone(), preimage, outputs(&sender));
+        assert!(utxos.validate_transaction(sender_claim, 2, 0, 1).is_err());
+
+        let refund = Transaction::htlc_refund(1, sender.clone(), input.clone(), outputs(&sender)).unwrap();
+        assert_eq!(refund.locktime, 50);
+        assert!(utxos.validate_transaction(refund.clone(), 50, 0, 1).is_err());
+        assert!(utxos.validate_transaction(refund, 51, 0, 1).is_ok());
+        let recipient_refund = Transaction::htlc_refund(1, recipient.clone(), input.clone(), outputs(&recipient)).unwrap();
+        assert!(utxos.validate_transaction(recipient_refund, 51, 0, 1).is_err());
+
+        //dropping the locktime does not get around CHECKLOCKTIMEVERIFY
+        let mut early = Transaction::htlc_refund(1, sender.clone(), input.clone(), outputs(&sender)).unwrap();
+        early.locktime = 0;
+        let sig = sender.sign_input(&early, 0, &input.1, SIGHASH_ALL).unwrap();
+        early.inputs[0].script = Script::HTLCRefundInput(sig, sender.get_pub_key());
+        assert!(utxos.validate_transaction(early, 51, 0, 1).is_err());
+
+        assert!(Transaction::htlc_refund(1, sender.clone(), ((funding.txid(), 0), outputs(&sender).remove(0)), outputs(&sender)).is_err());
+    }
+
+    #[test]
+    fn data_carriers_stay_out_of_utxos(){
+        let user = User::new();
+        let mut utxos = UTXOS::new();
+        let reward = Transaction::reward(10, user.get_pub_key(), 1, 1);
+        utxos.add_transaction(reward.clone(), 1, 0).unwrap();
+        let inputs = vec![((reward.txid(), 0), reward.outputs[0].clone())];
+        let outputs = vec![(hex::encode(user.get_pub_key()), 9)];
+        let document = hex::encode(sha256("document")).into_bytes();
+
+        let tx = Transaction::to_addresses(1, user.clone(), inputs.clone(), outputs.clone(), Some(document.clone())).unwrap();
+        assert_eq!(tx.data_payloads(), vec![document]);
+        assert!(utxos.validate_transaction(tx.clone(), 2, 0, 1).is_ok());
+        utxos.add_transaction(tx.clone(), 2, 0).unwrap();
+        assert_eq!(utxos.size(), 1);
+        assert!(utxos.get_entry(tx.txid(), 1).unwrap().is_none());
+
+        //data outputs can not be spent
+        assert!(!run_script(vec![OpCode::RETURN, OpCode::PUSHBYTES(vec![1])], 0, SEQUENCE_FINAL));
+
+        let too_big = vec![7u8; MAX_DATA_CARRIER_SIZE + 1];
+        assert!(TxOutput::data(too_big.clone()).is_err());
+        assert!(Transaction::to_addresses(1, user.clone(), inputs.clone(), outputs.clone(), Some(too_big.clone())).is_err());
+        let mut utxos = UTXOS::new();
+        utxos.add_transaction(reward, 1, 0).unwrap();
+        let mut oversized = Transaction::new(1, user.clone(), inputs.clone(), outputs.clone());
+        oversized.outputs.push(TxOutput::new(0, Script::DataOutput(too_big)));
+        oversized.output_count += 1;
+        assert!(!oversized.has_valid_data_outputs());
+        assert!(utxos.validate_transaction(oversized, 2, 0, 1).is_err());
+    }
+
+    #[test]
+    fn p2sh_reveals_redeem_script(){
+        let users: Vec<User> = (0..3).map(|_| User::new()).collect();
+        let redeem_script = Script::MultisigOutput(2, users.iter().map(|user| user.get_pub_key()).collect());
+        let mut wallet = Wallet::new(users[0].get_pub_key());
+        let address = wallet.p2sh_address(redeem_script.clone());
+        assert!(address.starts_with(P2SH_ADDRESS_PREFIX));
+        assert!(address_script("p2sh:abcd").is_err());
+
+        let funding = Transaction::unsigned(1, &[], vec![TxOutput::new(10, address_script(&address).unwrap())]);
+        let block = Block::new(vec![Transaction::reward(10, users[1].get_pub_key(), 1, 1), funding.clone()], [0u8; 32], 3, 1, 1);
+        wallet.update(block);
+        let outputs = wallet.p2sh_outputs();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].2, redeem_script);
+        assert_eq!(wallet.balance(2, 1), (0, 0));
+
+        let mut utxos = UTXOS::new();
+        utxos.add_transaction(funding.clone(), 1, 0).unwrap();
+        let utxo = funding.outputs[0].clone();
+        let mut tx = Transaction::unsigned(1, &[((funding.txid(), 0), utxo.clone())], vec![TxOutput::new(9, Script::P2PKHOutput(users[0].get_pub_key_hash()))]);
+        let sigs: Vec<Vec<u8>> = users[1..].iter().map(|user| user.sign_input(&tx, 0, &utxo, SIGHASH_ALL).unwrap()).collect();
+        let with_script = |tx: &Transaction, script: Script|{
+            let mut tx = tx.clone();
+            tx.inputs[0].script = script;
+            tx
+        };
+
+        tx = with_script(&tx, Script::P2SHInput(sigs.clone(), &redeem_script));
+        assert!(utxos.validate_transaction(tx.clone(), 2, 0, 1).is_ok());
+
+        //the revealed script has to match the hash and be satisfied
+        let other_script = Script::MultisigOutput(1, users.iter().map(|user| user.get_pub_key()).collect());
+        assert!(utxos.validate_transaction(with_script(&tx, Script::P2SHInput(sigs[..1].to_vec(), &other_script)), 2, 0, 1).is_err());
+        assert!(utxos.validate_transaction(with_script(&tx, Script::P2SHInput(sigs[..1].to_vec(), &redeem_script)), 2, 0, 1).is_err());
+        //only pushes may come before the redeem script
+        let mut unlocking = Script::P2SHInput(sigs, &redeem_script);
+        unlocking.0.insert(0, OpCode::DUP);
+        assert!(utxos.validate_transaction(with_script(&tx, unlocking), 2, 0, 1).is_err());
+    }
+
+    #[test]
+    fn schnorr_outputs_spend_with_key_path(){
+        let user = User::new();
+        let other = User::new();
+        let mut wallet = Wallet::new(user.get_pub_key());
+        assert_eq!(xonly_key(&user.get_pub_key()), Some(user.get_schnorr_pub_key()));
+        let address = wallet.schnorr_address().unwrap();
+        assert_eq!(address_script(&address).unwrap(), Script::SchnorrOutput(user.get_schnorr_pub_key()));
+        assert!(address_script("schnorr:abcd").is_err());
+
+        let funding = Transaction::unsigned(1, &[], vec![TxOutput::new(10, address_script(&address).unwrap())]);
+        let block = Block::new(vec![Transaction::reward(10, other.get_pub_key(), 1, 1), funding.clone()], [0u8; 32], 3, 1, 1);
+        wallet.update(block);
+        assert_eq!(wallet.balance(2, 1), (10, 0));
+
+        let mut utxos = UTXOS::new();
+        utxos.add_transaction(funding.clone(), 1, 0).unwrap();
+        let (inputs, _) = wallet.get_inputs(9, 2, 1).unwrap();
+        let tx = Transaction::to_addresses(1, user.clone(), inputs.clone(), vec![(hex::encode(other.get_pub_key()), 9)], None).unwrap();
+        assert_eq!(tx.inputs[0].script.0.len(), 1);
+        assert!(utxos.validate_transaction(tx.clone(), 2, 0, 1).is_ok());
+
+        //only a schnorr signature from the same key over the same sighash works
+        let utxo = &inputs[0].1;
+        let with_sig = |sig: Vec<u8>|{
+            let mut tx = tx.clone();
+            tx.inputs[0].script = Script::SchnorrInput(sig);
+            tx
+        };
+        let foreign = other.sign_input_schnorr(&tx, 0, utxo, SIGHASH_ALL).unwrap();
+        assert!(utxos.validate_transaction(with_sig(foreign), 2, 0, 1).is_err());
+        let ecdsa = user.sign_input(&tx, 0, utxo, SIGHASH_ALL).unwrap();
+        assert!(utxos.validate_transaction(with_sig(ecdsa), 2, 0, 1).is_err());
+        let mut tampered = user.sign_input_schnorr(&tx, 0, utxo, SIGHASH_ALL).unwrap();
+        tampered[10] ^= 1;
+        assert!(utxos.validate_transaction(with_sig(tampered), 2, 0, 1).is_err());
+        let mut changed = tx.clone();
+        changed.outputs[0].value = 8;
+        assert!(utxos.validate_transaction(changed, 2, 0, 1).is_err());
+        let short_key = Script::SchnorrOutput(vec![2, 3]);
+        assert!(Script::concat(Script::SchnorrInput(vec![0; 65]), short_key.clone()).validate_script(&tx, 0, &TxOutput::new(10, short_key)).is_err());
+
+        //the opcode survives encoding
+        let script = Script::SchnorrOutput(user.get_schnorr_pub_key());
+        assert_eq!(Script::decode(&script.encode()).unwrap(), script);
+    }
+
+    #[test]
+    fn script_errors_and_limits(){
+        let run = |ops: Vec<OpCode>|{
+            let utxo = TxOutput::new(10, Script(ops));
+            let tx = Transaction::unsigned(1, &[(([1; 32], 0), utxo.clone())], Vec::new());
+            utxo.script.validate_script(&tx, 0, &utxo)
+        };
+        let push = |data: Vec<u8>| OpCode::PUSHBYTES(data);
+        assert_eq!(run(vec![push(vec![1])]), Ok(()));
+        assert_eq!(run(vec![push(vec![])]), Err(ScriptError::FalseResult));
+        assert_eq!(run(vec![OpCode::DUP]), Err(ScriptError::StackUnderflow));
+        assert_eq!(run(vec![push(vec![1]), OpCode::ENDIF]), Err(ScriptError::UnbalancedConditional));
+        assert_eq!(run(vec![push(vec![1]), OpCode::RETURN]), Err(ScriptError::Return));
+        assert_eq!(run(vec![push(vec![1]), push(vec![2]), OpCode::EQUALVERIFY]), Err(ScriptError::EqualVerify));
+        assert_eq!(run(vec![push(vec![1; 9]), OpCode::CHECKLOCKTIMEVERIFY]), Err(ScriptError::InvalidNumber));
+
+        assert_eq!(run(vec![push(vec![1; MAX_SCRIPT_ELEMENT_SIZE])]), Ok(()));
+        assert_eq!(run(vec![push(vec![1; MAX_SCRIPT_ELEMENT_SIZE + 1])]), Err(ScriptError::PushSize(MAX_SCRIPT_ELEMENT_SIZE + 1)));
+        //push sizes and op counts also hold in branches that are not run
+        let skipped = |op: OpCode| vec![push(vec![]), OpCode::IF, op, OpCode::ENDIF, push(vec![1])];
+        assert!(matches!(run(skipped(push(vec![1; MAX_SCRIPT_ELEMENT_SIZE + 1]))), Err(ScriptError::PushSize(_))));
+        let mut ops = vec![push(vec![1])];
+        ops.extend(std::iter::repeat_n(OpCode::DUP, MAX_OPS_PER_SCRIPT + 1));
+        assert_eq!(run(ops), Err(ScriptError::OpCount(MAX_OPS_PER_SCRIPT + 1)));
+        assert_eq!(run(vec![push(vec![1]); MAX_STACK_SIZE]), Ok(()));
+        assert_eq!(run(vec![push(vec![1]); MAX_STACK_SIZE + 1]), Err(ScriptError::StackSize));
+        assert!(matches!(run(vec![push(vec![1; MAX_SCRIPT_ELEMENT_SIZE]); 20]), Err(ScriptError::ScriptSize(_))));
+
+        //the unlocking script only pushes, whatever the output script is
+        let utxo = TxOutput::new(10, Script(vec![push(vec![7]), OpCode::EQUAL]));
+        let tx = Transaction::unsigned(1, &[(([1; 32], 0), utxo.clone())], Vec::new());
+        let spend = |unlocking: Vec<OpCode>| Script::concat(Script(unlocking), utxo.script.clone()).validate_script(&tx, 0, &utxo);
+        assert_eq!(spend(vec![push(vec![7])]), Ok(()));
+        assert_eq!(spend(vec![push(vec![7]), OpCode::DUP, OpCode::DROP]), Err(ScriptError::NotPushOnly));
+        assert_eq!(spend(vec![push(vec![1]), OpCode::IF]), Err(ScriptError::NotPushOnly));
+        assert_eq!(Script(vec![push(vec![7])]).validate_script(&tx, 0, &utxo), Err(ScriptError::NotOutputSpend));
+
+        //a malformed key whose hash matches fails the signature check instead of panicking
+        let bad_key = vec![0x05; 7];
+        let utxo = TxOutput::new(10, Script::P2PKHOutput(sha256(hex::encode(&bad_key)).to_vec()));
+        let funding = Transaction::unsigned(1, &[], vec![utxo.clone()]);
+        let mut utxos = UTXOS::new();
+        utxos.add_transaction(funding.clone(), 1, 0).unwrap();
+        let mut tx = Transaction::unsigned(1, &[((funding.txid(), 0), utxo)], vec![TxOutput::new(9, Script::P2PKHOutput(vec![1]))]);
+        tx.inputs[0].script = Script::P2PKHInput(vec![0xff; 3], bad_key);
+        let error = utxos.validate_transaction(tx, 2, 0, 1).unwrap_err();
+        assert_eq!(error.downcast_ref::<ScriptError>(), Some(&ScriptError::SignatureCheck));
+    }
+
+    #[test]
+    fn only_low_s_signatures(){
+        let user = User::new();
+        let funding = Transaction::reward(10, user.get_pub_key(), 1, 1);
+        let utxo = funding.outputs[0].clone();
+        let tx = Transaction::new(1, user.clone(), vec![((funding.txid(), 0), utxo.clone())], vec![(hex::encode(user.get_pub_key()), 9)]);
+        let sig = user.sign_input(&tx, 0, &utxo, SIGHASH_ALL).unwrap();
+        let (sighash_type, bytes) = sig.split_last().unwrap();
+        let signature = Signature::from_slice(bytes).unwrap();
+        assert!(signature.normalize_s().is_none());
+
+        //negating s gives the other signature that is valid for the same key and message
+        let (r, s) = signature.split_scalars();
+        let high = Signature::from_scalars(r.to_bytes(), (-s).to_bytes()).unwrap();
+        let mut high_sig = high.to_vec();
+        high_sig.push(*sighash_type);
+        let mut malleated = tx.clone();
+        malleated.inputs[0].script = Script::P2PKHInput(high_sig.clone(), user.get_pub_key());
+        let script = Script::concat(malleated.inputs[0].script.clone(), utxo.script.clone());
+        assert_eq!(script.validate_script(&malleated, 0, &utxo), Err(ScriptError::HighS));
+
+        let multisig = Script::MultisigOutput(1, vec![user.get_pub_key()]);
+        let multisig_utxo = TxOutput::new(10, multisig.clone());
+        let script = Script::concat(Script::MultisigInput(vec![high_sig]), multisig);
+        assert_eq!(script.validate_script(&malleated, 0, &multisig_utxo), Err(ScriptError::HighS));
+
+        //the txid does not depend on unlocking scripts, the full hash does
+        assert_eq!(malleated.txid(), tx.txid());
+        assert_ne!(malleated.full_hash(), tx.full_hash());
+        let block = Block::new(vec![funding, tx.clone()], [0u8; 32], 3, 1, 1);
+        let mut changed = block.clone();
+        changed.transactions[1] = malleated;
+        assert_ne!(Block::get_merkle_root(changed.transactions.clone()), block.block_header.merkle_root);
+    }
+
+    fn sighash_transaction() -> Transaction{
+        let input = |prev: u8, output_index: usize, sequence: u32| TxInput{
+            prev: [prev; 32],
+            output_index,
+            script: Script::empty(),
+            sequence,
+        };
+        Transaction{
+            timestamp: 1767225600,
+            version: 1,
+            input_count: 2,
+            inputs: vec![input(0x11, 0, 0xffff_fffe), input(0x22, 1, 5)],
+            output_count: 2,
+            outputs: vec![TxOutput::new(50, Script::P2PKHOutput(vec![0xcc])), TxOutput::new(20, Script::P2PKHOutput(vec![0xdd]))],
+            height: 0,
+            locktime: 0,
+        }
+    }
+
+    #[test]
+    fn sighash_vectors(){
+        let tx = sighash_transaction();
+        let utxo = TxOutput::new(10, Script::P2PKHOutput(vec![0xaa]));
+        let vectors: [(usize, u8, &str); 12] = [
+            (0, SIGHASH_ALL, "2901b5635bff3b30417d64f4c77ab7b168b476db97f520c8a7d81eb0d92a650a"),
+            (0, SIGHASH_NONE, "a6ee670042eacebb81281dfe0e19b1d612adea5b544bc31b920825b4ebea5b5f"),
+            (0, SIGHASH_SINGLE, "d23d7dbcd6d4e5cb8e1bdb91e5c490dbcc1db6460ce4ca08b7583816b5d0c637"),
+            (0, SIGHASH_ALL | SIGHASH_ANYONECANPAY, "bdaa50773adf4bcc60844a273c98b602435f0cf4acfa02bdc0729bb06e43907a"),
+            (0, SIGHASH_NONE | SIGHASH_ANYONECANPAY, "050cb3d292207c640fd0f319ea04191973067cbdb6332a7b3aba64b52c79ba52"),
+            (0, SIGHASH_SINGLE | SIGHASH_ANYONECANPAY, "46d18be3696dc3399bea5544ea9c171f75a7479d74afc2179814cddfe5c99750"),
+            (1, SIGHASH_ALL, "9e86208c3c7c09e83def3fe1b78b5de1d87f6d3787922c9eb98c1c29471dc8c9"),
+            (1, SIGHASH_NONE, "762ef4dba51cec9caed49ae1d95a368f0ab3219ad7cdddc2f93fd23cf7621b4c"),
+            (1, SIGHASH_SINGLE, "3f8fe65e6451cf2227fb7b00c664a61b753c1bc7c18595acc6e057c54419e94a"),
+            (1, SIGHASH_ALL | SIGHASH_ANYONECANPAY, "e09ca3fae494d828c47374362f90c9c155da0d37cd2911431afb8e5717e12b49"),
+            (1, SIGHASH_NONE | SIGHASH_ANYONECANPAY, "4f6c6668a5d2ad1bfbaa0153a7a1f91d16bb3d4e2aca84bb65c6d672848a5749"),
+            (1, SIGHASH_SINGLE | SIGHASH_ANYONECANPAY, "d0113e20d202b8572363b432c6cddf1c87d00aa94cbc543e8beaa4593a9b548e"),
+        ];
+        for (input_index, sighash_type, expected) in vectors{
+            let sighash = compute_sig_hash(tx.clone(), input_index, &utxo, sighash_type).unwrap();
+            assert_eq!(hex::encode(sighash), expected, "input {} type {:02x}", input_index, sighash_type);
+        }
+
+        for sighash_type in [0x00, 0x04, 0x41, 0x84]{
+            assert!(compute_sig_hash(tx.clone(), 0, &utxo, sighash_type).is_none());
+        }
+        //SINGLE needs an output at the input's index
+        let mut short = tx.clone();
+        short.outputs.pop();
+        assert!(compute_sig_hash(short, 1, &utxo, SIGHASH_SINGLE).is_none());
+    }
+
+    #[test]
+    fn sighash_types_allow_their_changes(){
+        let user = User::new();
+        let utxo = TxOutput::new(10, Script::P2PKHOutput(user.get_pub_key_hash()));
+        let valid = |tx: &Transaction, input_index: usize| {
+            let script = Script::concat(tx.inputs[input_index].script.clone(), utxo.script.clone());
+            script.validate_script(tx, input_index, &utxo).is_ok()
+        };
+        let signed = |sighash_type: u8| {
+            let mut tx = sighash_transaction();
+            tx.sign_p2pkh_input(&user, 1, &utxo, sighash_type).unwrap();
+            tx
+        };
+        let add_input = |tx: &mut Transaction| {
+            tx.inputs.push(TxInput { prev: [0x33; 32], output_index: 0, script: Script::empty(), sequence: SEQUENCE_FINAL });
+            tx.input_count += 1;
+        };
+        let change_outputs = |tx: &mut Transaction, index: usize| tx.outputs[index].value += 1;
+
+        let tx = signed(SIGHASH_ALL);
+        assert!(valid(&tx, 1));
+        let mut changed = tx.clone();
+        change_outputs(&mut changed, 0);
+        assert!(!valid(&changed, 1));
+        let mut changed = tx.clone();
+        changed.inputs[0].sequence = 0;
+        assert!(!valid(&changed, 1));
+
+        //NONE lets every output and the other sequences change
+        let mut changed = signed(SIGHASH_NONE);
+        change_outputs(&mut changed, 0);
+        change_outputs(&mut changed, 1);
+        changed.inputs[0].sequence = 7;
+        assert!(valid(&changed, 1));
+        add_input(&mut changed);
+        assert!(!valid(&changed, 1));
+
+        //SINGLE only holds the output at the same index
+        let tx = signed(SIGHASH_SINGLE);
+        let mut changed = tx.clone();
+        change_outputs(&mut changed, 0);
+        changed.outputs.push(TxOutput::new(1, Script::empty()));
+        assert!(valid(&changed, 1));
+        let mut changed = tx.clone();
+        change_outputs(&mut changed, 1);
+        assert!(!valid(&changed, 1));
+        assert!(tx.clone().sign_p2pkh_input(&user, 1, &utxo, SIGHASH_SINGLE).is_ok());
+        let mut short = tx.clone();
+        short.outputs.pop();
+        assert!(short.sign_p2pkh_input(&user, 1, &utxo, SIGHASH_SINGLE).is_err());
+
+        //ANYONECANPAY lets others add inputs, crowdfunding a fixed set of outputs
+        let mut changed = signed(SIGHASH_ALL | SIGHASH_ANYONECANPAY);
+        add_input(&mut changed);
+        assert!(valid(&changed, 1));
+        change_outputs(&mut changed, 0);
+        assert!(!valid(&changed, 1));
+
+        //the type byte is part of what is signed
+        let mut forged = signed(SIGHASH_ALL);
+        let mut unlocking = forged.inputs[1].script.clone();
+        if let OpCode::PUSHBYTES(sig) = &mut unlocking.0[0]{
+            *sig.last_mut().unwrap() = SIGHASH_NONE;
+        }
+        forged.inputs[1].script = unlocking;
+        assert!(!valid(&forged, 1));
     }
 
     fn display_wallet(wallet: &Wallet){
         println!("Wallet");
         println!("value: {}", wallet.value);
-        for (key, utxo) in wallet.utxos.0.iter(){
+        for (key, entry) in wallet.utxos.0.iter(){
+            let utxo = &entry.output;
             println!("{},{} : {} for: {}", hex::encode(key.0), key.1, &utxo.value, hex::encode(utxo.script.P2PKHOutput_pubkey_hash().unwrap()));
         }
     }
 
     fn display_utxos(utxos: &UTXOS){
         println!("UTXOS");
-        for (key, utxo) in utxos.0.iter(){
+        for (key, entry) in utxos.0.iter(){
+            let utxo = &entry.output;
             println!("{},{} : {} for: {}", hex::encode(key.0), key.1, &utxo.value, hex::encode(utxo.script.P2PKHOutput_pubkey_hash().unwrap()));
         }
     }
@@ -566,7 +2560,7 @@ mod tests{
         let mut m =  mempool.0.clone();
         while let Some(txwf) = m.pop(){
             println!("Fee: {}", txwf.fee);
-            println!("Tx: {}", hex::encode(txwf.transaction.serialize()));
+            println!("Tx: {}", hex::encode(txwf.transaction.encode()));
         }
     }
 
@@ -578,11 +2572,11 @@ mod tests{
         let user2 = User::new();
         println!("User pubkey hash: {}", hex::encode(user.get_pub_key_hash()));
         println!("User2 pubkey hash: {}", hex::encode(user2.get_pub_key_hash()));
-        let new_tx = Transaction::reward(10, user.get_pub_key(), 1);
+        let new_tx = Transaction::reward(10, user.get_pub_key(), 1, 1);
         let mut wallet = Wallet::new(user.get_pub_key());
         let block1 = Block::new(
         vec![new_tx.clone()],
-        sha256("0000".to_string()),
+        sha256("0000"),
         3,
         1,
         1
@@ -590,9 +2584,9 @@ mod tests{
         println!("\nAdding first block\n");
         wallet.update(block1.clone());
         display_wallet(&wallet);
-        utxos.add_block(block1.clone());
+        utxos.add_block(block1.clone(), 0).unwrap();
         display_utxos(&utxos);
-        println!("tx: {}", hex::encode(sha256(new_tx.clone().serialize())));
+        println!("tx: {}", hex::encode(new_tx.txid()));
         for tx in block1.clone().transactions{
             if !is_coinbase(&tx){
                 mempool.remove(tx.clone());
@@ -604,7 +2598,7 @@ mod tests{
 
         let total: usize = 5;
         let fee: usize = 1;
-        let (inputs, value) = wallet.get_inputs(total).unwrap();
+        let (inputs, value) = wallet.get_inputs(total, 3, 1).unwrap();
         let mut outputs = vec![(hex::encode(user2.get_pub_key()), total - fee)];
         outputs.push((hex::encode(wallet.pub_key.clone()), value - total));
         let new_tx = Transaction::new(
@@ -615,8 +2609,8 @@ mod tests{
             );
         mempool.add(new_tx.clone(), utxos.get_fee(new_tx.clone()).unwrap());
         
-        let mut new_txs = mempool.get_next_transactions();
-        new_txs.push(Transaction::reward(10, user2.get_pub_key(), 1));
+        let mut new_txs = mempool.get_next_transactions(MAX_BLOCK_SIZE);
+        new_txs.push(Transaction::reward(10, user2.get_pub_key(), 1, 2));
         let block2 = Block::new(
             new_txs.clone(),
             sha256(block1.to_string()),
@@ -627,7 +2621,7 @@ mod tests{
         println!("\nAdding second block\n");
         for tx in block2.clone().transactions{
             if !is_coinbase(&tx){
-                if let Some(fee) = utxos.get_fee(tx.clone()){
+                if utxos.get_fee(tx.clone()).is_ok(){
                     mempool.remove(tx.clone());
                 }
                 else{
@@ -638,7 +2632,7 @@ mod tests{
         }
         wallet.update(block2.clone());
         display_wallet(&wallet);
-        utxos.add_block(block2.clone());
+        utxos.add_block(block2.clone(), 0).unwrap();
         display_utxos(&utxos);
         for tx in new_txs.clone(){
             for input in tx.inputs{
@@ -650,7 +2644,7 @@ mod tests{
 
         let total: usize = 4;
         let fee: usize = 1;
-        let (inputs, value) = wallet.get_inputs(total).unwrap();
+        let (inputs, value) = wallet.get_inputs(total, 3, 1).unwrap();
     
         let mut outputs = vec![(hex::encode(user2.get_pub_key()), total - fee)];
         if value > total{
@@ -664,8 +2658,8 @@ mod tests{
                 outputs,
             );
         mempool.add(new_tx.clone(), utxos.get_fee(new_tx.clone()).unwrap());
-        let mut new_txs = mempool.get_next_transactions();
-        new_txs.push(Transaction::reward(10, user.get_pub_key(), 1));
+        let mut new_txs = mempool.get_next_transactions(MAX_BLOCK_SIZE);
+        new_txs.push(Transaction::reward(10, user.get_pub_key(), 1, 3));
         let block3 = Block::new(
             new_txs,
             sha256(block2.to_string()),
@@ -676,7 +2670,7 @@ mod tests{
 
         for tx in block3.clone().transactions{
             if !is_coinbase(&tx){
-                if let Some(fee) = utxos.get_fee(tx.clone()){
+                if utxos.get_fee(tx.clone()).is_ok(){
                     mempool.remove(tx.clone());
                 }
                 else{
@@ -688,7 +2682,7 @@ mod tests{
         println!("\nAdding third block\n");
         wallet.update(block3.clone());
         display_wallet(&wallet);
-        utxos.add_block(block3.clone());
+        utxos.add_block(block3.clone(), 0).unwrap();
         display_utxos(&utxos);
         display_mempool(&mempool);
         
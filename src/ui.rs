@@ -3,7 +3,7 @@ use axum::{
     Json,
     response::Html,
     routing::{get, post},
-    extract::State,
+    extract::{Path, State},
 };
 use serde::{Deserialize, Serialize};
 #[allow(unused)]
@@ -19,9 +19,14 @@ use std::{
     collections::HashMap
 };
 
+use k256::elliptic_curve::bigint::Encoding;
+
 use crate::{
+    chain::compact_to_target,
+    merkle::MerkleProof,
     network::{Node, NetworkCommand},
-    transactions::Transaction,
+    encoding::Encode,
+    transactions::{Script, Transaction},
 };
 
 use anyhow::Result;
@@ -33,6 +38,9 @@ struct TransactionRequest{
     to: Vec<String>,
     to_amount: Vec<usize>,
     fee: usize,
+    //text stored in a data carrier output, e.g. a document hash
+    #[serde(default)]
+    data: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -90,13 +98,68 @@ async fn save_address_book(
 struct NodeStatus{
     height: usize,
     mempool_size: usize,
-    difficulty: usize,
+    difficulty: f64,
+    bits: String,
+    chain_work: String,
+    next_retarget: usize,
+}
+
+//explorer view of a block on the active chain
+#[derive(Serialize)]
+struct BlockSummary{
+    height: usize,
+    hash: String,
+    prev_hash: String,
+    timestamp: usize,
+    bits: String,
+    target: String,
+    chain_work: String,
+    transaction_count: usize,
+    data: Vec<DataSummary>,
+}
+
+//data carrier payload, shown as text when it is valid utf-8
+#[derive(Serialize)]
+struct DataSummary{
+    txid: String,
+    hex: String,
+    text: Option<String>,
+}
+
+const EXPLORER_BLOCKS: usize = 20;
+
+//everything a light client needs to check a payment against a header it already has
+#[derive(Serialize)]
+struct MerkleProofResponse{
+    txid: String,
+    //leaf the proof has to be verified against, the hash including unlocking scripts rather than the txid
+    full_hash: String,
+    height: usize,
+    block_hash: String,
+    merkle_root: String,
+    proof: MerkleProof,
+}
+
+//m-of-n multisig to receive on through a P2SH address
+#[derive(Debug, Deserialize)]
+struct P2shAddressRequest{
+    required: usize,
+    pubkeys: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct P2shAddressResponse{
+    address: String,
+    redeem_script: String,
 }
 
 #[derive(Serialize)]
 struct UserStatus{
     amount: usize,
+    immature: usize,
     pk: String,
+    //receive address for schnorr outputs to the same key
+    schnorr_address: Option<String>,
 }
 
 async fn submit_transaction(State(state): State<AppState>, Json(req): Json<TransactionRequest>) -> Json<TransactionResponse>{
@@ -106,15 +169,22 @@ async fn submit_transaction(State(state): State<AppState>, Json(req): Json<Trans
         info!("\t\t{}:{}", to, amount)
     }
     info!("\tFee: {}", req.fee);
+    let payload = req.data.filter(|data| !data.is_empty()).map(String::into_bytes);
 
     let mut total_spend: usize = req.to_amount.iter().sum();
     total_spend += req.fee;
-    if let Some((inputs, excess)) = state.node.read().await.wallet.get_inputs(total_spend){
+    if let Some((inputs, excess)) = state.node.read().await.get_inputs(total_spend){
         let mut outputs: Vec<(String, usize)> = req.to.iter().cloned().zip(req.to_amount).collect();
         outputs.push((hex::encode(state.node.read().await.user.get_pub_key().clone()), excess - total_spend));
         let tx = {
             let node_read = state.node.read().await;
-            Transaction::new(node_read.version, node_read.user.clone(), inputs, outputs)
+            match Transaction::to_addresses(node_read.version, node_read.user.clone(), inputs, outputs, payload){
+                Ok(tx) => tx,
+                Err(e) => return Json(TransactionResponse { 
+                    success: false, 
+                    message: e.to_string()
+                })
+            }
         };
         state.network_tx.send(NetworkCommand::Transaction(tx)).await.unwrap();
         Json(TransactionResponse { 
@@ -125,7 +195,7 @@ async fn submit_transaction(State(state): State<AppState>, Json(req): Json<Trans
         
         Json(TransactionResponse { 
             success: false, 
-            message: format!("Amount larger: {} than currently available {}", total_spend, state.node.read().await.wallet.value)
+            message: format!("Amount larger: {} than currently available {}", total_spend, state.node.read().await.balance().0)
         })
     }
 
@@ -140,15 +210,86 @@ async fn get_node_status(State(state): State<AppState>) -> Json<NodeStatus>{
     Json(NodeStatus { 
         height: node_read.height, 
         mempool_size: node_read.get_mempool_size(), 
-        difficulty: node_read.difficulty
+        difficulty: node_read.difficulty(),
+        bits: format!("{:08x}", node_read.bits),
+        chain_work: format!("{:x}", node_read.tip_work()),
+        next_retarget: node_read.next_retarget(),
     })
 }
 
+async fn get_blocks(State(state): State<AppState>) -> Json<Vec<BlockSummary>>{
+    let node_read = state.node.read().await;
+    Json((0..=node_read.height).rev()
+        .take(EXPLORER_BLOCKS)
+        .filter_map(|height| node_read.active_block(height))
+        .map(|block| {
+            let hash = block.calculate_hash();
+            let header = &block.block_header;
+            BlockSummary { 
+                height: header.height, 
+                hash: hex::encode(hash), 
+                prev_hash: hex::encode(header.prev_hash), 
+                timestamp: header.timestamp, 
+                bits: format!("{:08x}", header.bits), 
+                target: hex::encode(compact_to_target(header.bits).to_be_bytes()), 
+                chain_work: format!("{:x}", node_read.chain_work(&hash).unwrap_or_default()), 
+                transaction_count: block.transactions.len(),
+                data: block.transactions.iter()
+                    .flat_map(|tx| {
+                        let txid = hex::encode(tx.txid());
+                        tx.data_payloads().into_iter().map(move |payload| DataSummary { 
+                            txid: txid.clone(), 
+                            hex: hex::encode(&payload), 
+                            text: String::from_utf8(payload).ok() 
+                        })
+                    })
+                    .collect()
+            }
+        })
+        .collect())
+}
+
+async fn get_merkle_proof(State(state): State<AppState>, Path(txid): Path<String>) -> Json<Option<MerkleProofResponse>>{
+    let txid: [u8; 32] = match hex::decode(&txid).ok().and_then(|bytes| bytes.try_into().ok()){
+        Some(txid) => txid,
+        None => return Json(None)
+    };
+    let node_read = state.node.read().await;
+    Json(node_read.get_merkle_proof(&txid).map(|(header, full_hash, proof)| MerkleProofResponse { 
+        txid: hex::encode(txid), 
+        full_hash: hex::encode(full_hash),
+        height: header.height, 
+        block_hash: hex::encode(header.hash()), 
+        merkle_root: hex::encode(header.merkle_root), 
+        proof 
+    }))
+}
+
+async fn create_p2sh_address(State(state): State<AppState>, Json(req): Json<P2shAddressRequest>) -> Json<Option<P2shAddressResponse>>{
+    let pubkeys = match req.pubkeys.iter().map(hex::decode).collect::<Result<Vec<Vec<u8>>, _>>(){
+        Ok(pubkeys) => pubkeys,
+        Err(_) => return Json(None)
+    };
+    let redeem_script = Script::MultisigOutput(req.required, pubkeys);
+    if redeem_script.multisig_keys().is_none(){
+        return Json(None)
+    }
+    let address = state.node.write().await.wallet.p2sh_address(redeem_script.clone());
+    info!("New P2SH address {}", address);
+    Json(Some(P2shAddressResponse { 
+        address, 
+        redeem_script: hex::encode(redeem_script.encode()) 
+    }))
+}
+
 async fn get_user_status(State(state): State<AppState>) -> Json<UserStatus>{
-    let wallet_read = state.node.read().await.wallet.clone();
+    let node_read = state.node.read().await;
+    let (amount, immature) = node_read.balance();
     Json(UserStatus { 
-        amount: wallet_read.value, 
-        pk: hex::encode(wallet_read.pub_key) 
+        amount, 
+        immature,
+        pk: hex::encode(node_read.wallet.pub_key.clone()),
+        schnorr_address: node_read.wallet.schnorr_address(),
     })
 }
 
@@ -164,6 +305,7 @@ pub async fn start_server(node: Arc<RwLock<Node>>, network_tx: mpsc::Sender<Netw
     let static_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
         .join("src/static");
 
+    let ui_port = node.read().await.params().ui_port;
     let state = AppState{
         node,
         network_tx,
@@ -174,18 +316,21 @@ pub async fn start_server(node: Arc<RwLock<Node>>, network_tx: mpsc::Sender<Netw
         .route("/", get(index))
         .route("/api/transaction", post(submit_transaction))
         .route("/api/node_status", get(get_node_status))
+        .route("/api/blocks", get(get_blocks))
+        .route("/api/merkle_proof/{txid}", get(get_merkle_proof))
         .route("/api/user_status", get(get_user_status))
+        .route("/api/p2sh_address", post(create_p2sh_address))
         .route("/api/address_book", get(get_address_book))
         .route("/api/address_book", post(save_address_book))
         .route("/api/save_check", get(check_save_request))
         .nest_service("/static", ServeDir::new(static_dir))
         .with_state(state);
 
-    let addr = "0.0.0.0:3000";
+    let addr = format!("0.0.0.0:{}", ui_port);
 
     let listener = TcpListener::bind(addr).await?;
 
-    let url = format!("http://127.0.0.1:3000");
+    let url = format!("http://127.0.0.1:{}", ui_port);
     info!("Web ui running");
 
     if let Err(e) = webbrowser::open(&url) {
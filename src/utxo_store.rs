@@ -0,0 +1,539 @@
+use std::{
+    collections::HashMap, fs::{self, File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf},
+};
+
+use anyhow::{Result, bail};
+
+use crate::{
+    encoding::{Decode, Encode, Reader},
+    miner::HashDigest,
+    store::write_atomic,
+    transactions::{UtxoEntry, UtxoSet},
+};
+
+//changes held in memory before they are written out, a flush happens after the block that passes it
+pub const MAX_CACHE_ENTRIES: usize = 100_000;
+const INITIAL_CAPACITY: usize = 1024;
+const TABLE_VERSION: u32 = 1;
+const TABLE_FILE: &str = "table.dat";
+const COINS_FILE: &str = "coins.dat";
+
+//the table file is an open addressing hash table with linear probing, kept at most this full in tenths
+const MAX_LOAD: usize = 7;
+
+//  version     4 bytes
+//  best block  32 bytes    tip the stored outputs belong to, zero before the first flush and while one runs
+//  capacity    8 bytes     number of slots, a power of two
+//  count       8 bytes     live slots
+//  used        8 bytes     live and removed slots
+//  live bytes  8 bytes     size of the coins records live slots point at
+const HEADER_SIZE: usize = 68;
+
+//  state       1 byte
+//  hash        32 bytes
+//  index       8 bytes
+//  offset      8 bytes     of the encoded entry in the coins file
+//  len         4 bytes
+const SLOT_SIZE: usize = 53;
+
+const EMPTY: u8 = 0;
+const LIVE: u8 = 1;
+//left in place of a removed output so probing continues past it
+const REMOVED: u8 = 2;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct TableHeader{
+    best_block: HashDigest,
+    capacity: usize,
+    count: usize,
+    used: usize,
+    live_bytes: usize,
+}
+
+impl Encode for TableHeader{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        TABLE_VERSION.encode_to(buf);
+        self.best_block.encode_to(buf);
+        self.capacity.encode_to(buf);
+        self.count.encode_to(buf);
+        self.used.encode_to(buf);
+        self.live_bytes.encode_to(buf);
+    }
+}
+
+impl Decode for TableHeader{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        let version = u32::decode_from(reader)?;
+        if version != TABLE_VERSION{
+            bail!("unknown utxo table version {}", version)
+        }
+        let header = Self {
+            best_block: Decode::decode_from(reader)?,
+            capacity: Decode::decode_from(reader)?,
+            count: Decode::decode_from(reader)?,
+            used: Decode::decode_from(reader)?,
+            live_bytes: Decode::decode_from(reader)?,
+        };
+        if !header.capacity.is_power_of_two(){
+            bail!("utxo table capacity {} is not a power of two", header.capacity)
+        }
+        Ok(header)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Slot{
+    state: u8,
+    hash: [u8; 32],
+    index: usize,
+    offset: usize,
+    len: u32,
+}
+
+impl Slot{
+    fn holds(&self, hash: &[u8; 32], index: usize) -> bool{
+        self.state == LIVE && self.hash == *hash && self.index == index
+    }
+}
+
+impl Encode for Slot{
+    fn encode_to(&self, buf: &mut Vec<u8>){
+        self.state.encode_to(buf);
+        self.hash.encode_to(buf);
+        self.index.encode_to(buf);
+        self.offset.encode_to(buf);
+        self.len.encode_to(buf);
+    }
+}
+
+impl Decode for Slot{
+    fn decode_from(reader: &mut Reader) -> Result<Self>{
+        Ok(Self {
+            state: Decode::decode_from(reader)?,
+            hash: Decode::decode_from(reader)?,
+            index: Decode::decode_from(reader)?,
+            offset: Decode::decode_from(reader)?,
+            len: Decode::decode_from(reader)?,
+        })
+    }
+}
+
+//txids are hashes already so their first bytes are spread evenly, the index separates outputs of one transaction
+fn first_slot(hash: &[u8; 32], index: usize, capacity: usize) -> usize{
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    let mixed = u64::from_le_bytes(bytes) ^ (index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    mixed as usize & (capacity - 1)
+}
+
+fn slot_offset(slot: usize) -> u64{
+    (HEADER_SIZE + slot * SLOT_SIZE) as u64
+}
+
+fn read_slot(table: &mut File, slot: usize) -> Result<Slot>{
+    let mut data = [0u8; SLOT_SIZE];
+    table.seek(SeekFrom::Start(slot_offset(slot)))?;
+    table.read_exact(&mut data)?;
+    Slot::decode(&data)
+}
+
+//slot holding the output if it is stored, otherwise the first free slot on its probe sequence
+fn find_slot(table: &mut File, capacity: usize, hash: &[u8; 32], index: usize) -> Result<(usize, Slot)>{
+    let mut position = first_slot(hash, index, capacity);
+    let mut free = None;
+    for _ in 0..capacity{
+        let slot = read_slot(table, position)?;
+        if slot.holds(hash, index){
+            return Ok((position, slot))
+        }
+        if slot.state != LIVE && free.is_none(){
+            free = Some((position, slot));
+        }
+        if slot.state == EMPTY{
+            break
+        }
+        position = (position + 1) & (capacity - 1);
+    }
+    match free{
+        Some(free) => Ok(free),
+        None => bail!("utxo table is full")
+    }
+}
+
+//the utxo set of the active chain kept on disk, changes collect in a bounded cache and are written out
+//together, without a directory everything stays in the cache
+#[derive(Debug, Default)]
+pub struct UtxoStore{
+    dir: Option<PathBuf>,
+    header: TableHeader,
+    //changes not written yet, None marks a removed output
+    cache: HashMap<([u8; 32], usize), Option<UtxoEntry>>,
+    max_cache_entries: usize,
+    //live outputs including the cached changes
+    count: usize,
+}
+
+impl UtxoStore{
+    pub fn memory() -> Self{
+        Self {
+            max_cache_entries: MAX_CACHE_ENTRIES,
+            ..Default::default()
+        }
+    }
+
+    //opens the store in dir, creating an empty one if there is none
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self>{
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let table_path = dir.join(TABLE_FILE);
+        if !table_path.exists(){
+            write_empty(&dir, INITIAL_CAPACITY)?;
+        }
+        let mut file = File::open(&table_path)?;
+        let mut data = [0u8; HEADER_SIZE];
+        file.read_exact(&mut data)?;
+        let header = TableHeader::decode(&data)?;
+        if file.metadata()?.len() as usize != HEADER_SIZE + header.capacity * SLOT_SIZE{
+            bail!("utxo table does not hold {} slots", header.capacity)
+        }
+        Ok(Self {
+            dir: Some(dir),
+            header,
+            cache: HashMap::new(),
+            max_cache_entries: MAX_CACHE_ENTRIES,
+            count: header.count,
+        })
+    }
+
+    //opens an empty store in dir, removing any outputs stored there before
+    pub fn create<P: AsRef<Path>>(dir: P) -> Result<Self>{
+        if dir.as_ref().exists(){
+            fs::remove_dir_all(&dir)?;
+        }
+        Self::open(dir)
+    }
+
+    //empties the store, keeping it on disk if it was
+    pub fn clear(&mut self) -> Result<()>{
+        *self = match &self.dir{
+            Some(dir) => Self::create(dir.clone())?,
+            None => Self::memory(),
+        };
+        Ok(())
+    }
+
+    //tip the outputs on disk belong to as of the last flush, None if that flush did not finish
+    pub fn best_block(&self) -> Option<HashDigest>{
+        Some(self.header.best_block).filter(|hash| *hash != [0u8; 32])
+    }
+
+    pub fn cache_len(&self) -> usize{
+        self.cache.len()
+    }
+
+    //whether the cache has grown past its bound, a memory store never needs flushing
+    pub fn needs_flush(&self) -> bool{
+        self.dir.is_some() && self.cache.len() > self.max_cache_entries
+    }
+
+    //called once tip is connected or disconnected, writes the cache out when it needs flushing
+    pub fn sync(&mut self, tip: &HashDigest) -> Result<()>{
+        if self.needs_flush(){
+            self.flush(tip)?;
+        }
+        Ok(())
+    }
+
+    //writes every cached change to disk and records tip as the block they lead up to
+    pub fn flush(&mut self, tip: &HashDigest) -> Result<()>{
+        let dir = match &self.dir{
+            Some(dir) => dir.clone(),
+            None => return Ok(())
+        };
+        if self.cache.is_empty() && self.best_block() == Some(*tip){
+            return Ok(())
+        }
+        //the header names no block while slots change so an interrupted flush is never taken for a finished one
+        self.header.best_block = [0u8; 32];
+        write_header(&dir, &self.header)?;
+
+        let needed = (self.header.used + self.cache.len()) * 10;
+        let garbage = (fs::metadata(dir.join(COINS_FILE))?.len() as usize).saturating_sub(self.header.live_bytes);
+        if needed > self.header.capacity * MAX_LOAD || garbage > self.header.live_bytes.max(1 << 20){
+            let mut capacity = self.header.capacity;
+            while (self.header.count + self.cache.len()) * 10 > capacity * MAX_LOAD / 2{
+                capacity *= 2;
+            }
+            self.header = rebuild(&dir, &self.header, capacity)?;
+        }
+
+        let mut coins = OpenOptions::new().append(true).open(dir.join(COINS_FILE))?;
+        let coins_len = coins.metadata()?.len() as usize;
+        let mut table = OpenOptions::new().read(true).write(true).open(dir.join(TABLE_FILE))?;
+        let mut header = self.header;
+        let mut records = Vec::new();
+        for ((hash, index), change) in self.cache.iter(){
+            let (position, mut slot) = find_slot(&mut table, header.capacity, hash, *index)?;
+            if slot.state == LIVE{
+                header.count -= 1;
+                header.live_bytes -= slot.len as usize;
+            }
+            match change{
+                Some(entry) => {
+                    let data = entry.encode();
+                    if slot.state == EMPTY{
+                        header.used += 1;
+                    }
+                    slot = Slot { state: LIVE, hash: *hash, index: *index, offset: coins_len + records.len(), len: data.len() as u32 };
+                    header.count += 1;
+                    header.live_bytes += data.len();
+                    records.extend(data);
+                }
+                None if slot.state == LIVE => slot.state = REMOVED,
+                None => continue
+            }
+            table.seek(SeekFrom::Start(slot_offset(position)))?;
+            table.write_all(&slot.encode())?;
+        }
+        //the coins are on disk before the header counting them is
+        coins.write_all(&records)?;
+        coins.sync_data()?;
+        table.sync_data()?;
+        header.best_block = *tip;
+        write_header(&dir, &header)?;
+        self.header = header;
+        self.count = header.count;
+        self.cache.clear();
+        Ok(())
+    }
+
+    fn read_entry(&self, dir: &Path, hash: &[u8; 32], index: usize) -> Result<Option<UtxoEntry>>{
+        let mut table = File::open(dir.join(TABLE_FILE))?;
+        let (_, slot) = find_slot(&mut table, self.header.capacity, hash, index)?;
+        if slot.state != LIVE{
+            return Ok(None)
+        }
+        let mut coins = File::open(dir.join(COINS_FILE))?;
+        coins.seek(SeekFrom::Start(slot.offset as u64))?;
+        let mut data = vec![0u8; slot.len as usize];
+        coins.read_exact(&mut data)?;
+        Ok(Some(UtxoEntry::decode(&data)?))
+    }
+}
+
+impl UtxoSet for UtxoStore{
+    fn get_entry(&self, output_hash: [u8; 32], index: usize) -> io::Result<Option<UtxoEntry>>{
+        if let Some(change) = self.cache.get(&(output_hash, index)){
+            return Ok(change.clone())
+        }
+        let dir = match &self.dir{
+            Some(dir) => dir,
+            None => return Ok(None)
+        };
+        //a slot or coin that does not decode is as unreadable as one that fails to read
+        self.read_entry(dir, &output_hash, index).map_err(|e| match e.downcast::<io::Error>(){
+            Ok(e) => e,
+            Err(e) => io::Error::new(io::ErrorKind::InvalidData, format!("utxo {}:{}: {:#}", hex::encode(output_hash), index, e))
+        })
+    }
+
+    fn insert(&mut self, hash: [u8; 32], index: usize, entry: UtxoEntry) -> io::Result<()>{
+        if self.get_entry(hash, index)?.is_none(){
+            self.count += 1;
+        }
+        self.cache.insert((hash, index), Some(entry));
+        Ok(())
+    }
+
+    fn remove(&mut self, hash: [u8; 32], index: usize) -> io::Result<Option<UtxoEntry>>{
+        let entry = match self.get_entry(hash, index)?{
+            Some(entry) => entry,
+            None => return Ok(None)
+        };
+        self.count -= 1;
+        match self.dir{
+            Some(_) => self.cache.insert((hash, index), None),
+            None => self.cache.remove(&(hash, index))
+        };
+        Ok(Some(entry))
+    }
+
+    fn size(&self) -> usize{
+        self.count
+    }
+}
+
+fn write_empty(dir: &Path, capacity: usize) -> Result<()>{
+    write_atomic(&dir.join(COINS_FILE), &[])?;
+    let header = TableHeader { capacity, ..Default::default() };
+    let mut table = header.encode();
+    table.resize(HEADER_SIZE + capacity * SLOT_SIZE, EMPTY);
+    write_atomic(&dir.join(TABLE_FILE), &table)
+}
+
+fn write_header(dir: &Path, header: &TableHeader) -> Result<()>{
+    let mut table = OpenOptions::new().write(true).open(dir.join(TABLE_FILE))?;
+    table.write_all(&header.encode())?;
+    table.sync_data()?;
+    Ok(())
+}
+
+//rewrites both files with only the live outputs, dropping removed slots and coins nothing points at
+fn rebuild(dir: &Path, header: &TableHeader, capacity: usize) -> Result<TableHeader>{
+    let old_table = fs::read(dir.join(TABLE_FILE))?;
+    let old_coins = fs::read(dir.join(COINS_FILE))?;
+    let mut new_header = TableHeader { best_block: header.best_block, capacity, ..Default::default() };
+    let mut table = vec![EMPTY; HEADER_SIZE + capacity * SLOT_SIZE];
+    let mut coins = Vec::with_capacity(header.live_bytes);
+    for data in old_table[HEADER_SIZE..].chunks(SLOT_SIZE){
+        let mut slot = Slot::decode(data)?;
+        if slot.state != LIVE{
+            continue
+        }
+        let record = match old_coins.get(slot.offset..slot.offset + slot.len as usize){
+            Some(record) => record,
+            None => bail!("utxo {}:{} points past the coins file", hex::encode(slot.hash), slot.index)
+        };
+        slot.offset = coins.len();
+        coins.extend_from_slice(record);
+        let mut position = first_slot(&slot.hash, slot.index, capacity);
+        while table[slot_offset(position) as usize] != EMPTY{
+            position = (position + 1) & (capacity - 1);
+        }
+        let start = slot_offset(position) as usize;
+        table[start..start + SLOT_SIZE].copy_from_slice(&slot.encode());
+        new_header.count += 1;
+        new_header.used += 1;
+    }
+    new_header.live_bytes = coins.len();
+    table[..HEADER_SIZE].copy_from_slice(&new_header.encode());
+    //the two renames are not atomic together, the header left unnamed by flush covers a crash between them
+    write_atomic(&dir.join(COINS_FILE), &coins)?;
+    write_atomic(&dir.join(TABLE_FILE), &table)?;
+    Ok(new_header)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::transactions::{Script, TxOutput};
+
+    fn test_dir(name: &str) -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("coin_net_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(value: usize) -> UtxoEntry{
+        UtxoEntry::new(TxOutput::new(value, Script::P2PKHOutput(vec![value as u8; 20])), value, 0, value.is_multiple_of(2))
+    }
+
+    fn txid(n: usize) -> [u8; 32]{
+        crate::miner::sha256(n.to_string())
+    }
+
+    #[test]
+    fn flushed_outputs_survive_reopening(){
+        let dir = test_dir("utxos_reopen");
+        let mut store = UtxoStore::open(&dir).unwrap();
+        for n in 0..50{
+            store.insert(txid(n), n % 3, entry(n)).unwrap();
+        }
+        assert_eq!(store.remove(txid(7), 1).unwrap().unwrap().output.value, 7);
+        assert!(store.remove(txid(7), 1).unwrap().is_none());
+        store.flush(&[1u8; 32]).unwrap();
+        assert_eq!(store.cache_len(), 0);
+
+        //changes after the flush are only in the cache
+        store.remove(txid(8), 2).unwrap();
+        store.insert(txid(100), 0, entry(100)).unwrap();
+        assert_eq!(store.size(), 49);
+        assert!(store.get_entry(txid(8), 2).unwrap().is_none());
+
+        let reopened = UtxoStore::open(&dir).unwrap();
+        assert_eq!(reopened.best_block(), Some([1u8; 32]));
+        assert_eq!(reopened.size(), 49);
+        assert!(reopened.get_entry(txid(7), 1).unwrap().is_none());
+        assert_eq!(reopened.get_entry(txid(8), 2).unwrap().unwrap().output.value, 8);
+        assert!(reopened.get_entry(txid(100), 0).unwrap().is_none());
+        let stored = reopened.get_entry(txid(10), 1).unwrap().unwrap();
+        assert_eq!((stored.height, stored.coinbase), (10, true));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_outputs_are_errors(){
+        let dir = test_dir("utxos_unreadable");
+        let mut store = UtxoStore::open(&dir).unwrap();
+        store.insert(txid(1), 0, entry(1)).unwrap();
+        store.flush(&[1u8; 32]).unwrap();
+        fs::write(dir.join(COINS_FILE), []).unwrap();
+        assert!(store.get_entry(txid(1), 0).is_err());
+        assert!(store.remove(txid(1), 0).is_err());
+        assert!(store.insert(txid(1), 0, entry(2)).is_err());
+        assert_eq!(store.size(), 1);
+        //outputs that were never stored are still missing rather than unreadable
+        assert!(store.get_entry(txid(2), 0).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn table_grows_and_compacts(){
+        let dir = test_dir("utxos_grow");
+        let mut store = UtxoStore::open(&dir).unwrap();
+        store.max_cache_entries = 100;
+        for round in 0..4{
+            for n in 0..1000{
+                store.insert(txid(round * 1000 + n), 0, entry(n)).unwrap();
+            }
+            store.sync(&txid(round)).unwrap();
+            //spend most of what the round created so removed slots pile up
+            for n in 0..900{
+                store.remove(txid(round * 1000 + n), 0).unwrap();
+            }
+            store.sync(&txid(round)).unwrap();
+        }
+        assert_eq!(store.cache_len(), 0);
+        assert_eq!(store.size(), 400);
+        assert!(store.header.capacity > INITIAL_CAPACITY);
+
+        let reopened = UtxoStore::open(&dir).unwrap();
+        assert_eq!(reopened.size(), 400);
+        for round in 0..4{
+            assert!(reopened.get_entry(txid(round * 1000 + 899), 0).unwrap().is_none());
+            assert_eq!(reopened.get_entry(txid(round * 1000 + 950), 0).unwrap().unwrap().output.value, 950);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unfinished_flushes_are_detected(){
+        let dir = test_dir("utxos_unfinished");
+        let mut store = UtxoStore::open(&dir).unwrap();
+        store.insert(txid(1), 0, entry(1)).unwrap();
+        store.flush(&[1u8; 32]).unwrap();
+        assert_eq!(UtxoStore::open(&dir).unwrap().best_block(), Some([1u8; 32]));
+
+        //a crash after the header was cleared but before the new tip was written
+        write_header(&dir, &TableHeader { best_block: [0u8; 32], ..store.header }).unwrap();
+        assert_eq!(UtxoStore::open(&dir).unwrap().best_block(), None);
+
+        //a table cut short is refused rather than read
+        OpenOptions::new().write(true).open(dir.join(TABLE_FILE)).unwrap().set_len(HEADER_SIZE as u64 + 10).unwrap();
+        assert!(UtxoStore::open(&dir).is_err());
+        assert!(UtxoStore::create(&dir).unwrap().get_entry(txid(1), 0).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn memory_store_forgets_removed_outputs(){
+        let mut store = UtxoStore::memory();
+        store.insert(txid(1), 0, entry(1)).unwrap();
+        store.insert(txid(1), 0, entry(1)).unwrap();
+        assert_eq!(store.size(), 1);
+        store.remove(txid(1), 0).unwrap();
+        assert_eq!((store.size(), store.cache_len()), (0, 0));
+        store.flush(&txid(1)).unwrap();
+        assert_eq!(store.best_block(), None);
+    }
+}